@@ -0,0 +1,117 @@
+//! `--sync-advisory-db`: sharing the RustSec advisory databases of cargo-audit and cargo-deny
+//! between this machine and the build server, so a `cargo remote audit` or `cargo remote deny
+//! check` fetches them on the build server and local runs reuse them. The databases are only a
+//! cache, so a failed transfer is a warning and never fails the run.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{info, warn};
+
+use crate::audit;
+use crate::local_shell;
+use crate::transport::Transport;
+
+/// The directories below the cargo home the databases are kept in: cargo-audit uses
+/// `advisory-db`, cargo-deny `advisory-dbs`.
+const DIRS: [&str; 2] = ["advisory-db", "advisory-dbs"];
+
+/// The transfers of the advisory databases to the build server before the build and back after
+/// it. Each side only overwrites files that are older than the ones of the other side.
+pub struct Transfers {
+    /// Unset if there is no local database yet.
+    pub upload: Option<Command>,
+    pub download: Command,
+    cargo_home: PathBuf,
+}
+
+impl Transfers {
+    /// Assembles the transfers between the local cargo home and `~/.cargo` on the build server.
+    /// Returns [`None`] when building on this machine, where both are the same.
+    pub fn new(rsync_bin: &str, transport: &Transport) -> Option<Transfers> {
+        if transport.is_local() {
+            info!("Not syncing the advisory databases, the build server is this machine");
+            return None;
+        }
+        let cargo_home = std::env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))?;
+
+        let local_dbs: Vec<PathBuf> = DIRS
+            .iter()
+            .map(|dir| cargo_home.join(dir))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        let upload = if local_dbs.is_empty() {
+            None
+        } else {
+            let mut upload = rsync(rsync_bin, transport);
+            upload
+                .args(transport.rsync_setup_args("mkdir -p .cargo"))
+                .args(local_dbs.iter().map(|dir| local_shell::path(dir)))
+                .arg(transport.location(".cargo/"));
+            Some(upload)
+        };
+
+        let mut download = rsync(rsync_bin, transport);
+        download
+            .arg("--ignore-missing-args")
+            .args(
+                DIRS.iter()
+                    .map(|dir| transport.location(&format!(".cargo/{}", dir))),
+            )
+            .arg(local_shell::dir(&cargo_home));
+        Some(Transfers {
+            upload,
+            download,
+            cargo_home,
+        })
+    }
+
+    /// Transfers the local databases to the build server.
+    pub fn upload(&mut self) {
+        if let Some(upload) = &mut self.upload {
+            info!("Transferring the advisory databases to build server.");
+            run(upload, "to");
+        }
+    }
+
+    /// Transfers the databases of the build server back.
+    pub fn download(&mut self) {
+        info!("Transferring the advisory databases back from build server.");
+        if let Err(e) = std::fs::create_dir_all(&self.cargo_home) {
+            warn!(
+                "Failed to create '{}' for the advisory databases (error: {})",
+                self.cargo_home.to_string_lossy(),
+                e
+            );
+            return;
+        }
+        run(&mut self.download, "from");
+    }
+}
+
+/// An rsync transfer that keeps the newer of two versions of a file.
+fn rsync(rsync_bin: &str, transport: &Transport) -> Command {
+    let mut rsync = local_shell::command(rsync_bin);
+    rsync
+        .args(["-a", "--update", "--compress"])
+        .args(transport.rsync_args())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit());
+    rsync
+}
+
+fn run(rsync: &mut Command, direction: &str) {
+    match audit::status(rsync.stdout(Stdio::null())) {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!(
+            "Failed to transfer the advisory databases {} the build server (rsync {})",
+            direction, status
+        ),
+        Err(e) => warn!(
+            "Failed to transfer the advisory databases {} the build server (error: {})",
+            direction, e
+        ),
+    }
+}
@@ -0,0 +1,41 @@
+//! Where cargo-remote keeps its global config and its data: below the XDG base directories, or
+//! `%APPDATA%` and `%LOCALAPPDATA%` on Windows, which has no XDG directories.
+
+use std::path::PathBuf;
+
+const PREFIX: &str = "cargo-remote";
+
+/// The global config file [`name`], if it exists.
+#[cfg(unix)]
+pub fn find_config_file(name: &str) -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix(PREFIX)
+        .ok()?
+        .find_config_file(name)
+}
+
+#[cfg(not(unix))]
+pub fn find_config_file(name: &str) -> Option<PathBuf> {
+    Some(windows_dir("APPDATA")?.join(name)).filter(|path| path.is_file())
+}
+
+/// The data file [`name`], which may be in a subdirectory. Missing directories are created.
+#[cfg(unix)]
+pub fn place_data_file(name: &str) -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix(PREFIX)
+        .ok()?
+        .place_data_file(name)
+        .ok()
+}
+
+#[cfg(not(unix))]
+pub fn place_data_file(name: &str) -> Option<PathBuf> {
+    let path = windows_dir("LOCALAPPDATA")?.join(name);
+    std::fs::create_dir_all(path.parent()?).ok()?;
+    Some(path)
+}
+
+/// The directory of cargo-remote in the directory the environment variable [`var`] names.
+#[cfg(not(unix))]
+fn windows_dir(var: &str) -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os(var)?).join(PREFIX))
+}
@@ -0,0 +1,209 @@
+//! `--audit`: an append-only log of everything cargo-remote executes, to review what it runs on
+//! shared infrastructure. Every command spawned on this machine is recorded with its argv, and
+//! those running a command on the build server with that command's shell string as well, one JSON
+//! object per line with the time and the exit code. Values of forwarded environment variables are
+//! redacted.
+//!
+//! Each entry is written with its own open in append mode, so runs sharing the log don't mix
+//! their lines and a log rotated away between two entries is started anew.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::app_dirs;
+use crate::process_group;
+use crate::shell_quote;
+use crate::transport::Transport;
+
+/// What a secret is replaced with in the log.
+const REDACTED: &str = "<redacted>";
+
+/// The audit of this run, [`None`] without `--audit`.
+static AUDIT: Mutex<Option<Audit>> = Mutex::new(None);
+
+struct Audit {
+    path: PathBuf,
+    /// Tells the entries of this run from those of other runs in the same log.
+    run: String,
+    project: String,
+    /// How the build server is reached, to tell which commands run something there.
+    transport: Option<Transport>,
+    secrets: Vec<String>,
+    /// Whether failing to write the log was reported already.
+    failed: bool,
+}
+
+impl Audit {
+    fn redact(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_owned(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+}
+
+/// The audit log if `audit_log` isn't set, in the data directory.
+pub fn default_path() -> Option<PathBuf> {
+    app_dirs::place_data_file("audit.jsonl")
+}
+
+/// Records the commands of this run of the project in [`project_dir`] in [`path`] from now on.
+pub fn start(path: PathBuf, project_dir: &Path) {
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    *AUDIT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Audit {
+        path,
+        run: format!("{}-{}", started_at, std::process::id()),
+        project: project_dir.to_string_lossy().into_owned(),
+        transport: None,
+        secrets: Vec::new(),
+        failed: false,
+    });
+}
+
+/// Makes the commands that run something through [`transport`] record what they run there.
+pub fn set_transport(transport: &Transport) {
+    if let Some(audit) = AUDIT.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        audit.transport = Some(transport.clone());
+    }
+}
+
+/// Keeps the [`secrets`] out of the log from now on.
+pub fn redact<'a>(secrets: impl IntoIterator<Item = &'a str>) {
+    if let Some(audit) = AUDIT.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        audit.secrets.extend(
+            secrets
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned),
+        );
+    }
+}
+
+/// Like [`Command::status`], recording [`cmd`].
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+    let status = cmd.status();
+    record(cmd, status.as_ref().ok());
+    status
+}
+
+/// Like [`Command::output`], recording [`cmd`].
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    let output = cmd.output();
+    record(cmd, output.as_ref().ok().map(|output| &output.status));
+    output
+}
+
+/// Records that [`cmd`] ran and exited with [`status`], which is [`None`] if it couldn't be run
+/// or was killed at a timeout.
+pub fn record(cmd: &Command, status: Option<&ExitStatus>) {
+    let mut audit = AUDIT.lock().unwrap_or_else(|e| e.into_inner());
+    let audit = match audit.as_mut() {
+        Some(audit) => audit,
+        None => return,
+    };
+    let argv = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| audit.redact(&arg.to_string_lossy()))
+        .collect::<Vec<_>>();
+    let remote = audit
+        .transport
+        .as_ref()
+        .and_then(|transport| transport.remote_command(cmd))
+        .map(|command| audit.redact(&command));
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let entry = json!({
+        "time_ms": time,
+        "run": audit.run,
+        "project": audit.project,
+        "argv": argv,
+        "remote": remote,
+        "exit": status.and_then(ExitStatus::code),
+        "signal": status.and_then(process_group::exit_signal),
+    });
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&audit.path)
+        .and_then(|mut log| log.write_all(format!("{}\n", entry).as_bytes()));
+    if let (Err(e), false) = (written, audit.failed) {
+        audit.failed = true;
+        warn!(
+            "Failed to write the audit log '{}' (error: {})",
+            audit.path.to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Prints the entries of the last run recorded in the audit log at [`path`].
+pub fn show(path: &Path) -> Result<(), String> {
+    let log = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read '{}': {}", path.to_string_lossy(), e))?;
+    let entries = log
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .collect::<Vec<_>>();
+    let run = match entries.last().and_then(|entry| entry.get("run")) {
+        Some(run) => run,
+        None => {
+            println!("No commands recorded in {}", path.to_string_lossy());
+            return Ok(());
+        }
+    };
+    let entries = entries
+        .iter()
+        .filter(|entry| entry.get("run") == Some(run))
+        .collect::<Vec<_>>();
+    let field = |entry: &Value, key: &str| entry.get(key).and_then(Value::as_u64);
+    let started = entries
+        .first()
+        .and_then(|entry| field(entry, "time_ms"))
+        .unwrap_or_default();
+    println!(
+        "Run {} of {}:",
+        run.as_str().unwrap_or_default(),
+        entries[0]
+            .get("project")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+    );
+    for entry in entries {
+        let at = field(entry, "time_ms")
+            .unwrap_or(started)
+            .saturating_sub(started);
+        let exit = entry.get("exit").and_then(Value::as_i64);
+        let outcome = match (exit, field(entry, "signal")) {
+            (Some(code), _) => format!("exit {}", code),
+            (None, Some(signal)) => format!("signal {}", signal),
+            (None, None) => "no exit".to_owned(),
+        };
+        let argv = entry
+            .get("argv")
+            .and_then(Value::as_array)
+            .map(|argv| {
+                argv.iter()
+                    .filter_map(Value::as_str)
+                    .map(shell_quote)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        println!("+{:>7.1}s  {:<9}  {}", at as f64 / 1000.0, outcome, argv);
+        if let Some(remote) = entry.get("remote").and_then(Value::as_str) {
+            println!("{:>21}{}", "remote: ", remote);
+        }
+    }
+    Ok(())
+}
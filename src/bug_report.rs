@@ -0,0 +1,161 @@
+//! `--bug-report`: what's needed to look into a failing run, bundled into one zip file that can be
+//! attached to an issue. Secrets are redacted like in the logs and `--dry-run`, only the names of
+//! forwarded environment variables ever end up in the report.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::audit;
+use crate::transport::Transport;
+
+/// How many lines of the build output are kept.
+const TAIL_LINES: usize = 200;
+
+/// The last lines the build wrote to stdout and stderr.
+#[derive(Default)]
+pub struct OutputTail {
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl OutputTail {
+    /// Keeps [`line`] without its colors, dropping the oldest line once [`TAIL_LINES`] are kept.
+    pub fn record(&self, line: &[u8]) {
+        let line = strip_ansi(&String::from_utf8_lossy(line));
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line.trim_end().to_owned());
+    }
+
+    fn text(&self) -> String {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+/// The files of the report, filled in while the run goes on.
+#[derive(Default)]
+pub struct BugReport {
+    files: Vec<(&'static str, String)>,
+    tail: Arc<OutputTail>,
+}
+
+impl BugReport {
+    /// Sets the content of the file [`name`], replacing what was there.
+    pub fn set(&mut self, name: &'static str, text: String) {
+        match self.files.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, content)) => *content = text,
+            None => self.files.push((name, text)),
+        }
+    }
+
+    /// The tail of the build output, shared with the threads forwarding it.
+    pub fn output_tail(&self) -> Arc<OutputTail> {
+        self.tail.clone()
+    }
+
+    /// Writes the report as `cargo-remote-report-<timestamp>.zip` into [`dir`] and returns its
+    /// path.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf, String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let path = dir.join(format!("cargo-remote-report-{}.zip", timestamp));
+        let file = std::fs::File::create(&path)
+            .map_err(|e| format!("can't create '{}': {}", path.to_string_lossy(), e))?;
+        let mut zip = zip::ZipWriter::new(file);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let tail = self.tail.text();
+        for (name, text) in self
+            .files
+            .iter()
+            .map(|(name, text)| (*name, text))
+            .chain(Some(("build-output.txt", &tail)))
+        {
+            zip.start_file(name, options)
+                .map_err(|e| e.to_string())
+                .and_then(|()| zip.write_all(text.as_bytes()).map_err(|e| e.to_string()))
+                .map_err(|e| format!("can't add {}: {}", name, e))?;
+        }
+        zip.finish()
+            .map_err(|e| format!("can't write '{}': {}", path.to_string_lossy(), e))?;
+        Ok(path)
+    }
+}
+
+/// The versions of cargo-remote and the local tools it runs, [`ssh`] and [`rsync`] being the
+/// configured binaries.
+pub fn local_versions(ssh: &str, rsync: &str) -> String {
+    let mut versions = format!("cargo-remote {}\n", env!("CARGO_PKG_VERSION"));
+    let tools: Vec<(&str, &[&str])> = vec![
+        ("uname", &["-srm"]),
+        ("cargo", &["--version"]),
+        (ssh, &["-V"]),
+        (rsync, &["--version"]),
+        ("git", &["--version"]),
+    ];
+    for (program, args) in tools {
+        versions.push_str(&format!("$ {} {}\n", program, args.join(" ")));
+        versions.push_str(&first_line(Command::new(program).args(args)));
+    }
+    versions
+}
+
+/// What the build server tells about itself, with the environment profiles sourced by
+/// [`sources`] like for the build.
+pub fn remote_host_info(transport: &Transport, sources: &str) -> String {
+    let script = format!(
+        "{}uname -a; cargo --version; rustc --version; rustup --version 2>/dev/null; \
+         rsync --version | head -n 1; df -h .",
+        sources
+    );
+    let mut info = transport.shell(&script);
+    match audit::output(info.stdin(Stdio::null())) {
+        Ok(output) => format!(
+            "{}{}{}\n",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+            output.status
+        ),
+        Err(e) => format!("can't ask the build server: {}\n", e),
+    }
+}
+
+/// The first line [`cmd`] prints, on stdout or, like `ssh -V`, on stderr.
+fn first_line(cmd: &mut Command) -> String {
+    match audit::output(cmd.stdin(Stdio::null())) {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let line = stdout.lines().chain(stderr.lines()).next().unwrap_or("");
+            format!("{}\n", line)
+        }
+        Err(e) => format!("can't run it: {}\n", e),
+    }
+}
+
+/// Removes the escape sequences that color terminal output, like `\x1b[1;32m`.
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+        } else if chars.next() == Some('[') {
+            // the parameters are digits and semicolons, a letter ends the sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
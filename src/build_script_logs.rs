@@ -0,0 +1,97 @@
+//! The output of failed build scripts. Cargo points to the `output` and `stderr` files in the
+//! remote `target/<profile>/build/<package>-<hash>/` directory, which don't exist locally, so the
+//! directories named in the output of a failed build are picked up and their files fetched.
+
+use std::sync::Mutex;
+
+/// The directory below the local target directory the files are fetched into.
+pub const LOCAL_DIR: &str = "remote-build-logs";
+
+/// How many build script directories are fetched at most.
+pub const MAX_DIRS: usize = 5;
+
+/// The largest file that is fetched, in rsync's `--max-size` format.
+pub const MAX_FILE_SIZE: &str = "1M";
+
+/// The files cargo keeps the output of a build script in.
+pub const FILES: [&str; 2] = ["output", "stderr"];
+
+/// The characters besides whitespace a path in cargo's output ends at.
+const DELIMITERS: &[char] = &['`', '"', '\'', '(', ')', '[', ']', '<', '>'];
+
+/// A build script directory named in the output.
+#[derive(Clone, PartialEq)]
+pub struct BuildDir {
+    /// The absolute remote path of the directory containing [`name`], e.g.
+    /// `/home/me/remote-builds/1234-5678/target/debug/build`.
+    pub parent: String,
+    /// The name of the directory, `<package>-<hash>`.
+    pub name: String,
+}
+
+/// Collects the build script directories named in the output of the remote command.
+#[derive(Default)]
+pub struct Hints {
+    dirs: Mutex<Vec<BuildDir>>,
+}
+
+impl Hints {
+    /// Picks up the build script directories named in [`line`], up to [`MAX_DIRS`].
+    pub fn observe(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        if !line.contains("/build/") {
+            return;
+        }
+        let mut dirs = self.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        for dir in build_dirs(&line) {
+            if dirs.len() < MAX_DIRS && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    /// The directories picked up so far.
+    pub fn dirs(&self) -> Vec<BuildDir> {
+        self.dirs.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Finds the absolute paths like `/…/target/debug/build/foo-0123456789abcdef/build-script-build`
+/// in [`line`] and returns the build script directories they point into.
+fn build_dirs(line: &str) -> Vec<BuildDir> {
+    let mut dirs = Vec::new();
+    let mut search = 0;
+    while let Some(found) = line[search..].find("/build/") {
+        let at = search + found;
+        search = at + "/build/".len();
+        let start = line[..at].rfind(is_delimiter).map_or(0, |i| {
+            i + line[i..].chars().next().map_or(0, char::len_utf8)
+        });
+        let parent = &line[start..at + "/build".len()];
+        let rest = &line[search..];
+        let name = &rest[..rest
+            .find(|c: char| c == '/' || is_delimiter(c))
+            .unwrap_or(rest.len())];
+        if parent.starts_with('/') && is_build_dir_name(name) {
+            dirs.push(BuildDir {
+                parent: parent.to_owned(),
+                name: name.to_owned(),
+            });
+        }
+    }
+    dirs
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || DELIMITERS.contains(&c)
+}
+
+/// Whether [`name`] looks like `<package>-<hash>`, with cargo's 16 hex digit hash.
+fn is_build_dir_name(name: &str) -> bool {
+    match name.rsplit_once('-') {
+        Some((package, hash)) => {
+            !package.is_empty() && hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,282 @@
+//! `--coalesce`: one run for a burst of invocations, e.g. from an editor that runs
+//! `cargo remote check` on every save. Runs of a project take turns through a local lock. If a
+//! run of the same command is already waiting for its turn, it will sync everything the newcomer
+//! would, so the newcomer only leaves a note and exits.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+/// The lock runs of the project take turns through, in the local target directory.
+pub const LOCK_FILE: &str = ".cargo-remote-run.lock";
+
+/// The runs waiting for their turn, next to [`LOCK_FILE`]. It's only read and written while
+/// holding a lock on it.
+pub const STATE_FILE: &str = ".cargo-remote-coalesce.json";
+
+/// How often a waiting run checks whether it's its turn.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A run waiting for its turn, as recorded in the [`STATE_FILE`].
+struct Waiter {
+    pid: u32,
+    command: String,
+    /// How many invocations left it to this run.
+    coalesced: u64,
+}
+
+/// What [`enter`] decided.
+pub enum Turn {
+    /// A waiting run of the same command takes over, this one has nothing to do.
+    Coalesced,
+    /// It's this run's turn, the lock is held until the value is dropped.
+    Run(File),
+    /// The user interrupted the wait.
+    Interrupted,
+}
+
+/// Waits for the turn of this run of [`command`] in the project with the local target directory
+/// [`local_target`], unless a run of the same command is already waiting. Once it's this run's
+/// turn, it keeps waiting for [`window`], so the rest of a burst that only starts now coalesces
+/// into it as well. The wait ends early once [`interrupted`] is set.
+pub fn enter(
+    local_target: &Path,
+    command: &str,
+    window: Duration,
+    interrupted: &AtomicBool,
+) -> std::io::Result<Turn> {
+    std::fs::create_dir_all(local_target)?;
+    let state_path = local_target.join(STATE_FILE);
+    let pid = std::process::id();
+
+    let registered = update(&state_path, |waiters| {
+        match waiters.iter_mut().find(|w| w.command == command) {
+            Some(waiter) => {
+                waiter.coalesced += 1;
+                false
+            }
+            None => {
+                waiters.push(Waiter {
+                    pid,
+                    command: command.to_owned(),
+                    coalesced: 0,
+                });
+                true
+            }
+        }
+    })?;
+    if !registered {
+        return Ok(Turn::Coalesced);
+    }
+
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(local_target.join(LOCK_FILE))?;
+    if !try_lock(&lock)? {
+        info!("Waiting for the previous run of this project to finish");
+        // polled, a blocking lock would outlast Ctrl-C
+        while !try_lock(&lock)? {
+            if interrupted.load(Ordering::SeqCst) {
+                return Ok(Turn::Interrupted);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    std::thread::sleep(window);
+
+    let mut coalesced = 0;
+    update(&state_path, |waiters| {
+        if let Some(at) = waiters.iter().position(|w| w.pid == pid) {
+            coalesced = waiters.remove(at).coalesced;
+        }
+    })?;
+    if coalesced > 0 {
+        info!(
+            "Running once for {} more invocations of '{}'",
+            coalesced, command
+        );
+    }
+    Ok(Turn::Run(lock))
+}
+
+/// Runs [`change`] on the waiters recorded in the state file at [`path`], while holding a lock
+/// on it. Waiters whose process is gone are dropped first.
+fn update<T>(path: &Path, change: impl FnOnce(&mut Vec<Waiter>) -> T) -> std::io::Result<T> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let mut waiters: Vec<Waiter> = match serde_json::from_str::<Value>(&content) {
+        Ok(Value::Array(waiters)) => waiters.iter().filter_map(parse_waiter).collect(),
+        _ => {
+            if !content.trim().is_empty() {
+                warn!(
+                    "Ignoring the unreadable coalescing state in {}",
+                    path.to_string_lossy()
+                );
+            }
+            Vec::new()
+        }
+    };
+    waiters.retain(|w| is_alive(w.pid));
+    let result = change(&mut waiters);
+    let content = Value::Array(
+        waiters
+            .iter()
+            .map(|w| json!({"pid": w.pid, "command": w.command, "coalesced": w.coalesced}))
+            .collect(),
+    );
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(content.to_string().as_bytes())?;
+    // closing the file releases the lock
+    Ok(result)
+}
+
+fn parse_waiter(value: &Value) -> Option<Waiter> {
+    Some(Waiter {
+        pid: value
+            .get("pid")?
+            .as_u64()
+            .filter(|pid| *pid <= u64::from(u32::MAX))? as u32,
+        command: value.get("command")?.as_str()?.to_owned(),
+        coalesced: value.get("coalesced")?.as_u64()?,
+    })
+}
+
+/// Whether the process [`pid`] still exists.
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Without a way to tell on Windows, no run waits for another one to rerun for it there, they only
+/// take turns.
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Takes the exclusive lock on [`file`] if nobody else holds it.
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+// the threads of a test share a pid, which Windows can't tell is alive
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// A fresh local target directory for one test, removed when it ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "cargo-remote-coalesce-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn waiters(dir: &Path) -> Vec<(String, u64)> {
+        let mut waiters = Vec::new();
+        update(&dir.join(STATE_FILE), |w| {
+            waiters = w.iter().map(|w| (w.command.clone(), w.coalesced)).collect()
+        })
+        .unwrap();
+        waiters
+    }
+
+    #[test]
+    fn a_burst_coalesces_into_the_waiting_run() {
+        let dir = Arc::new(TempDir::new("burst"));
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let enter_in_thread = |command: &'static str| {
+            let (dir, interrupted) = (dir.clone(), interrupted.clone());
+            std::thread::spawn(move || {
+                match enter(&dir.0, command, Duration::ZERO, &interrupted).unwrap() {
+                    Turn::Run(_) => "run",
+                    Turn::Coalesced => "coalesced",
+                    Turn::Interrupted => "interrupted",
+                }
+            })
+        };
+
+        let running = match enter(&dir.0, "check", Duration::ZERO, &interrupted).unwrap() {
+            Turn::Run(lock) => lock,
+            _ => panic!("the first run didn't get its turn"),
+        };
+        let waiting = enter_in_thread("check");
+        let other = enter_in_thread("test");
+        while waiters(&dir.0).len() < 2 {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        let burst: Vec<_> = (0..5).map(|_| enter_in_thread("check")).collect();
+        for run in burst {
+            assert_eq!(run.join().unwrap(), "coalesced");
+        }
+        let expected = vec![("check".to_owned(), 5), ("test".to_owned(), 0)];
+        let mut recorded = waiters(&dir.0);
+        recorded.sort();
+        assert_eq!(recorded, expected);
+        assert!(!waiting.is_finished());
+
+        drop(running);
+        assert_eq!(waiting.join().unwrap(), "run");
+        assert_eq!(other.join().unwrap(), "run");
+        assert!(waiters(&dir.0).is_empty());
+    }
+
+    #[test]
+    fn an_interrupt_ends_the_wait() {
+        let dir = Arc::new(TempDir::new("interrupt"));
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let running = match enter(&dir.0, "check", Duration::ZERO, &interrupted).unwrap() {
+            Turn::Run(lock) => lock,
+            _ => panic!("the first run didn't get its turn"),
+        };
+        let waiting = {
+            let (dir, interrupted) = (dir.clone(), interrupted.clone());
+            std::thread::spawn(move || enter(&dir.0, "test", Duration::ZERO, &interrupted))
+        };
+        while waiters(&dir.0).is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        interrupted.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            waiting.join().unwrap().unwrap(),
+            Turn::Interrupted
+        ));
+        drop(running);
+    }
+}
@@ -0,0 +1,1441 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+use toml::Value;
+
+use log::{info, warn};
+
+use crate::local_shell::LocalShell;
+use crate::units::{parse_bandwidth, parse_duration, parse_size};
+use crate::webhook::WebhookOn;
+
+/// The environment variable that names the build server if `--remote` doesn't.
+pub const REMOTE_ENV: &str = "CARGO_REMOTE_HOST";
+
+/// Options of a remote build that can be given on the command line or in one of the config files.
+/// Options given on the command line take precedence over the project config, which takes
+/// precedence over the global config.
+#[derive(StructOpt, Debug)]
+pub struct RemoteConfig {
+    /// `CARGO_REMOTE_HOST` comes after the flag but before the config files, so CI jobs can pick
+    /// the build server without writing a config, see [`RemoteConfig::complete_from_env`].
+    #[structopt(
+        short = "r",
+        long = "remote",
+        help = "Remote ssh build server or an alias from the [remotes] config table, repeat it to build on several at once [env: CARGO_REMOTE_HOST]",
+        raw(number_of_values = "1")
+    )]
+    pub remote: Vec<String>,
+
+    #[structopt(
+        short = "b",
+        long = "build-env",
+        help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. (repeatable) [default: RUST_BACKTRACE=1]",
+        raw(number_of_values = "1")
+    )]
+    pub build_env: Vec<String>,
+
+    #[structopt(
+        long = "forward-env",
+        help = "Pass the local environment variable (or all matching a pattern like 'CARGO_*') to the remote command (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub forward_env: Vec<String>,
+
+    #[structopt(
+        long = "pre-hook",
+        help = "Shell command run on the build server before the cargo command, aborts it if failing (repeatable)",
+        raw(visible_alias = r#""pre-cmd""#),
+        raw(number_of_values = "1")
+    )]
+    pub pre_hooks: Vec<String>,
+
+    #[structopt(
+        long = "post-hook",
+        help = "Shell command run on the build server after the cargo command, whose exit code is in $CARGO_REMOTE_STATUS (repeatable)",
+        raw(visible_alias = r#""post-cmd""#),
+        raw(number_of_values = "1")
+    )]
+    pub post_hooks: Vec<String>,
+
+    #[structopt(
+        short = "d",
+        long = "rustup-default",
+        help = "Toolchain to build with, run as cargo +<toolchain> (e.g. stable, nightly, 1.74.0) [default: the one of the project's rust-toolchain file, else stable]",
+        raw(visible_alias = r#""toolchain""#)
+    )]
+    pub rustup_default: Option<String>,
+
+    #[structopt(
+        long = "install-toolchain",
+        help = "Install the --rustup-default or rust-toolchain file toolchain on the build server if it's missing"
+    )]
+    pub install_toolchain: bool,
+
+    #[structopt(
+        long = "docker-image",
+        help = "Build inside a container of this image on the build server, with the synced sources mounted at /work"
+    )]
+    pub docker_image: Option<String>,
+
+    #[structopt(
+        long = "target",
+        help = "Build for this target triple, may select the build server from the [targets] config table"
+    )]
+    pub target: Option<String>,
+
+    #[structopt(
+        long = "no-rustup-target-add",
+        help = "Don't install the standard library of --target with rustup before building"
+    )]
+    pub no_rustup_target_add: bool,
+
+    #[structopt(
+        long = "package",
+        help = "Sync only this workspace package and the members it depends on, and build it with -p"
+    )]
+    pub package: Option<String>,
+
+    #[structopt(
+        long = "sync-parent",
+        help = "Sync from this ancestor of the workspace root (relative to it), so path dependencies outside of the workspace are transferred along with it"
+    )]
+    pub sync_parent: Option<String>,
+
+    #[structopt(
+        short = "e",
+        long = "env",
+        help = "Environment profile to source (repeatable) [default: ~/.cargo/env]",
+        raw(number_of_values = "1")
+    )]
+    pub env: Vec<String>,
+
+    #[structopt(
+        long = "build-path",
+        help = "Remote directory the project directories are created in [default: ~/remote-builds]"
+    )]
+    pub build_path: Option<String>,
+
+    #[structopt(
+        long = "build-path-no-hash",
+        help = "Use --build-path itself as project directory instead of a hashed subdirectory"
+    )]
+    pub build_path_no_hash: bool,
+
+    #[structopt(
+        long = "remote-target-dir",
+        help = "Set CARGO_TARGET_DIR on the build server and copy artifacts back from there, relative to the project directory [default: target]"
+    )]
+    pub remote_target_dir: Option<String>,
+
+    #[structopt(
+        long = "isolate",
+        help = "Which local checkouts share a remote build directory: none (all with the same path), host or branch [default: host]"
+    )]
+    pub isolate: Option<Isolation>,
+
+    #[structopt(
+        long = "isolate-toolchain",
+        help = "Give every toolchain its own remote build directory, so switching toolchains doesn't rebuild everything"
+    )]
+    pub isolate_toolchain: bool,
+
+    #[structopt(
+        long = "migrate-remote-dir",
+        help = "Move a build directory created by an older cargo-remote version without asking (not moved if non-interactive)"
+    )]
+    pub migrate_remote_dir: bool,
+
+    #[structopt(
+        short = "c",
+        long = "copy-back",
+        help = "Transfer the target folder or a file or glob pattern below it back to the local machine (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub copy_back: Vec<String>,
+
+    #[structopt(
+        long = "no-copy-lock",
+        help = "don't transfer the Cargo.lock file back to the local machine"
+    )]
+    pub no_copy_lock: bool,
+
+    #[structopt(
+        long = "sync-back-file",
+        help = "Transfer this file, relative to the workspace root, back after every run if it changed, e.g. workspace-hack/Cargo.toml regenerated by cargo hakari generate. Replaces the default of just Cargo.lock, so list it as well (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub sync_back_files: Vec<String>,
+
+    #[structopt(
+        long = "copy-back-on-failure",
+        help = "Transfer artifacts and Cargo.lock back even if the remote command failed"
+    )]
+    pub copy_back_on_failure: bool,
+
+    #[structopt(
+        long = "copy-back-bins",
+        help = "Transfer the binaries of the workspace for the selected profile back to the local machine"
+    )]
+    pub copy_back_bins: bool,
+
+    #[structopt(
+        long = "copy-back-isolate",
+        help = "Transfer artifacts back into target/remote/ instead of target/, so they don't mix with local builds for another host"
+    )]
+    pub copy_back_isolate: bool,
+
+    #[structopt(
+        long = "doc-crate",
+        help = "With doc --open, transfer only the documentation of this crate and the shared files back and open it"
+    )]
+    pub doc_crate: Option<String>,
+
+    #[structopt(
+        long = "collect-on-failure",
+        help = "Transfer remote files matching the pattern into target/remote-failure-<timestamp>/ if the remote command failed (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub collect_on_failure: Vec<String>,
+
+    #[structopt(
+        long = "no-build-script-logs",
+        help = "Don't transfer the output of the build scripts named in the output of a failed build into target/remote-build-logs/"
+    )]
+    pub no_build_script_logs: bool,
+
+    #[structopt(
+        long = "fetch",
+        help = "Transfer a remote path or glob pattern relative to the build directory back to the same place in the project, also outside target/ (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub fetch: Vec<String>,
+
+    #[structopt(
+        long = "no-hygiene-check",
+        help = "Don't warn about copied-back artifacts that git doesn't ignore"
+    )]
+    pub no_hygiene_check: bool,
+
+    #[structopt(
+        long = "transfer-hidden",
+        help = "Transfer hidden files and directories to the build server"
+    )]
+    pub hidden: bool,
+
+    #[structopt(
+        long = "transfer-hidden-except",
+        help = "Transfer hidden files and directories except those matching the rsync pattern and .git (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub hidden_except: Vec<String>,
+
+    #[structopt(
+        long = "transfer-hidden-only",
+        help = "Transfer the hidden files and directories matching the rsync pattern, but no other hidden ones (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub hidden_only: Vec<String>,
+
+    #[structopt(
+        long = "transfer-cargo-config",
+        help = "Transfer .cargo/config.toml and .cargo/config even if hidden files aren't transferred"
+    )]
+    pub transfer_cargo_config: bool,
+
+    #[structopt(
+        long = "transfer-credentials",
+        help = "Copy the local cargo registry credentials to the build server (asks first, not copied if non-interactive)"
+    )]
+    pub transfer_credentials: bool,
+
+    #[structopt(
+        long = "sync-advisory-db",
+        help = "Share the advisory databases of cargo-audit and cargo-deny in ~/.cargo with the build server, both ways"
+    )]
+    pub sync_advisory_db: bool,
+
+    #[structopt(
+        long = "use-direnv",
+        help = "Run cargo through 'direnv exec', so the .envrc of the project applies remotely (asks first, not used if non-interactive)"
+    )]
+    pub use_direnv: bool,
+
+    #[structopt(
+        long = "include",
+        help = "Transfer files matching the rsync pattern even if they are hidden or excluded (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub include: Vec<String>,
+
+    #[structopt(
+        long = "exclude",
+        help = "Exclude files matching the rsync pattern from the transfer (repeatable)",
+        raw(number_of_values = "1")
+    )]
+    pub exclude: Vec<String>,
+
+    #[structopt(
+        long = "respect-gitignore",
+        help = "Don't transfer files ignored by (nested) .gitignore files or .git/info/exclude",
+        raw(alias = r#""use-gitignore""#)
+    )]
+    pub respect_gitignore: bool,
+
+    #[structopt(
+        long = "no-delete",
+        help = "Don't delete remote files that don't exist locally when syncing, which may leave stale files behind"
+    )]
+    pub no_delete: bool,
+
+    #[structopt(
+        long = "skip-unchanged",
+        help = "Don't start rsync if no source file changed since the last successful sync to the build server, as recorded in .cargo-remote/state"
+    )]
+    pub skip_unchanged: bool,
+
+    #[structopt(
+        long = "remote-clean-shell",
+        help = "Run the remote command in a shell that skips the user's profile and rc files"
+    )]
+    pub remote_clean_shell: bool,
+
+    #[structopt(
+        long = "remote-shell",
+        help = "Run the remote command in a login shell of this program (e.g. zsh or sh) instead of the account's default shell [default: bash]"
+    )]
+    pub remote_shell: Option<String>,
+
+    #[structopt(
+        long = "ssh-opt",
+        help = "Extra argument passed to ssh, also used for the rsync transfers (repeatable)",
+        raw(allow_hyphen_values = "true", number_of_values = "1")
+    )]
+    pub ssh_opts: Vec<String>,
+
+    #[structopt(
+        long = "jump-host",
+        help = "Connect through this bastion host (ssh -J), several hops are separated by commas"
+    )]
+    pub jump_host: Option<String>,
+
+    #[structopt(long = "ssh-bin", help = "The ssh program to use [default: ssh]")]
+    pub ssh_bin: Option<String>,
+
+    #[structopt(
+        long = "rsync-bin",
+        help = "The local rsync program to use [default: rsync]"
+    )]
+    pub rsync_bin: Option<String>,
+
+    #[structopt(
+        long = "local-shell",
+        help = "How rsync and ssh are run on Windows: native (Cygwin, cwRsync), msys (MSYS2, Git for Windows) or wsl (inside WSL through wsl.exe) [default: native]"
+    )]
+    pub local_shell: Option<LocalShell>,
+
+    #[structopt(
+        long = "connect-timeout",
+        help = "Give up connecting to the build server after this long (e.g. 10s), for every ssh connection",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub connect_timeout: Option<Duration>,
+
+    #[structopt(
+        long = "skip-probe",
+        help = "Don't check that the build server is reachable and has rsync and cargo before syncing"
+    )]
+    pub skip_probe: bool,
+
+    #[structopt(
+        long = "no-control-master",
+        help = "Open a new ssh connection for every transfer instead of sharing one via a ControlMaster socket"
+    )]
+    pub no_control_master: bool,
+
+    #[structopt(
+        long = "ssh-control-path",
+        help = "Share one ssh connection through the ControlMaster socket at this path, which stays open for a while after the run, so the next runs don't authenticate again"
+    )]
+    pub ssh_control_path: Option<String>,
+
+    #[structopt(
+        long = "retries",
+        help = "Retry the upload and the remote command this many times if the connection to the build server failed [default: 0]"
+    )]
+    pub retries: Option<u32>,
+
+    #[structopt(
+        long = "bwlimit",
+        help = "Limit the bandwidth of all transfers, in KiB/s or as a size per second like 2MB",
+        parse(try_from_str = "parse_bandwidth")
+    )]
+    pub bwlimit: Option<u64>,
+
+    #[structopt(
+        long = "no-compress",
+        help = "Don't compress the transfers, e.g. for already compressed artifacts"
+    )]
+    pub no_compress: bool,
+
+    #[structopt(
+        long = "no-partial",
+        help = "Start interrupted transfers over instead of resuming them from a partial directory"
+    )]
+    pub no_partial: bool,
+
+    #[structopt(
+        long = "force-tty",
+        help = "Always allocate a TTY on the build server, which merges the remote stdout and stderr"
+    )]
+    pub force_tty: bool,
+
+    #[structopt(
+        long = "non-interactive",
+        help = "Behave as if stdin and stdout weren't terminals: ask nothing (every question is answered no), allocate no TTY and show no progress"
+    )]
+    pub non_interactive: bool,
+
+    #[structopt(
+        long = "no-local-cargo",
+        help = "Don't run cargo on this machine, find the workspace root from the manifests (the default if there's no cargo)"
+    )]
+    pub no_local_cargo: bool,
+
+    #[structopt(
+        long = "no-pty-bridge",
+        help = "Read rewritten remote output through a pipe instead of a local pseudo terminal"
+    )]
+    pub no_pty_bridge: bool,
+
+    #[structopt(
+        long = "no-stale-lock-recovery",
+        help = "Don't remove cargo's file locks left behind by a crashed run when the build waits for them"
+    )]
+    pub no_stale_lock_recovery: bool,
+
+    #[structopt(
+        long = "stale-lock-grace",
+        help = "How long the build may wait for a file lock before it is checked for being stale [default: 30s]",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub stale_lock_grace: Option<Duration>,
+
+    #[structopt(
+        long = "coalesce",
+        help = "Take turns with the other runs of the project, waiting this many milliseconds once it's this run's turn, and exit right away if a run of the same command is already waiting, e.g. for a burst of saves in an editor"
+    )]
+    pub coalesce: Option<u64>,
+
+    #[structopt(
+        long = "no-space-monitor",
+        help = "Don't poll the free space of the remote filesystem during the build, e.g. on slow links"
+    )]
+    pub no_space_monitor: bool,
+
+    #[structopt(
+        long = "space-floor",
+        help = "Warn when the free space of the remote filesystem drops below this size during the build [default: 1GiB]",
+        parse(try_from_str = "parse_size")
+    )]
+    pub space_floor: Option<u64>,
+
+    #[structopt(
+        long = "space-monitor-interval",
+        help = "How often the free space of the remote filesystem is checked during the build [default: 10s]",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub space_monitor_interval: Option<Duration>,
+
+    #[structopt(
+        long = "max-remote-target-size",
+        help = "After a successful build, remove the oldest incremental compilation sessions until the remote target directory is smaller than this size (e.g. 20G)",
+        parse(try_from_str = "parse_size")
+    )]
+    pub max_remote_target_size: Option<u64>,
+
+    #[structopt(
+        long = "regression-threshold",
+        help = "Warn if the build takes this many percent longer than the last run of the same command [default: 25]"
+    )]
+    pub regression_threshold: Option<u64>,
+
+    #[structopt(
+        long = "stats",
+        help = "Print the duration and transfer size of every phase at the end, even if a phase failed"
+    )]
+    pub stats: bool,
+
+    #[structopt(
+        long = "strict-flags",
+        help = "Fail instead of warning if cargo options conflict with cargo-remote options"
+    )]
+    pub strict_flags: bool,
+
+    #[structopt(
+        long = "no-config-warnings",
+        help = "Don't warn about config keys that are unknown or overridden"
+    )]
+    pub no_config_warnings: bool,
+
+    #[structopt(
+        long = "audit",
+        help = "Append every command run locally and on the build server to the audit log, with its exit code"
+    )]
+    pub audit: bool,
+
+    #[structopt(
+        long = "audit-log",
+        env = "CARGO_REMOTE_AUDIT_LOG",
+        help = "The audit log of --audit [default: audit.jsonl in the cargo-remote data directory]"
+    )]
+    pub audit_log: Option<String>,
+
+    #[structopt(
+        long = "webhook",
+        env = "CARGO_REMOTE_WEBHOOK",
+        help = "POST a JSON summary of the run (project, host, command, exit code, durations, artifacts) to this URL when it ends"
+    )]
+    pub webhook: Option<String>,
+
+    #[structopt(
+        long = "webhook-on",
+        help = "Which runs --webhook reports: success, failure or always [default: always]"
+    )]
+    pub webhook_on: Option<WebhookOn>,
+
+    #[structopt(
+        long = "sync-timeout",
+        help = "Abort if transferring the sources takes longer than this (e.g. 90s, 30m, 1h30m)",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub sync_timeout: Option<Duration>,
+
+    #[structopt(
+        long = "build-timeout",
+        help = "Abort and kill the remote command if it runs longer than this (e.g. 90s, 30m, 1h30m)",
+        raw(visible_alias = r#""timeout""#),
+        parse(try_from_str = "parse_duration")
+    )]
+    pub build_timeout: Option<Duration>,
+
+    #[structopt(
+        long = "copy-back-timeout",
+        help = "Abort if transferring artifacts back takes longer than this (e.g. 90s, 30m, 1h30m)",
+        parse(try_from_str = "parse_duration")
+    )]
+    pub copy_back_timeout: Option<Duration>,
+}
+
+impl RemoteConfig {
+    /// Takes the build server from [`REMOTE_ENV`] unless `--remote` named one and returns whether
+    /// it did. clap's `env` can't be used, it adds the variable to the values of a repeatable
+    /// option even if the option was given.
+    pub fn complete_from_env(&mut self) -> bool {
+        if !self.remote.is_empty() {
+            return false;
+        }
+        match std::env::var(REMOTE_ENV) {
+            Ok(remote) if !remote.is_empty() => {
+                self.remote = vec![remote];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fills every option that wasn't set yet from the [`configs`], highest precedence first, so
+    /// the first one that sets an option wins. Includes and excludes are merged instead. Returns
+    /// the keys of each config that had no effect.
+    pub fn complete_from_configs<'a>(
+        &mut self,
+        configs: impl IntoIterator<Item = &'a Value>,
+    ) -> Vec<UnusedKeys> {
+        let decided = RefCell::default();
+        configs
+            .into_iter()
+            .map(|config| self.complete_from_config(&Source::new(config, &decided)))
+            .collect()
+    }
+
+    fn complete_from_config(&mut self, config: &Source) -> UnusedKeys {
+        complete_list(&mut self.remote, config, "remote");
+        complete_list(&mut self.build_env, config, "build_env");
+        complete_list(&mut self.forward_env, config, "forward_env");
+        complete_list(&mut self.pre_hooks, config, "pre_hooks");
+        complete_list(&mut self.post_hooks, config, "post_hooks");
+        complete_list(&mut self.pre_hooks, config, "pre_commands");
+        complete_list(&mut self.post_hooks, config, "post_commands");
+        complete_option(&mut self.rustup_default, config, "rustup_default");
+        complete_flag(&mut self.install_toolchain, config, "install_toolchain");
+        complete_option(&mut self.docker_image, config, "docker_image");
+        complete_option(&mut self.target, config, "target");
+        complete_option(&mut self.package, config, "package");
+        complete_option(&mut self.sync_parent, config, "sync_parent");
+        complete_flag(
+            &mut self.no_rustup_target_add,
+            config,
+            "no_rustup_target_add",
+        );
+        complete_negated_flag(
+            &mut self.no_rustup_target_add,
+            config,
+            "no_rustup_target_add",
+            "rustup_target_add",
+        );
+        complete_list(&mut self.env, config, "env");
+        complete_option(&mut self.build_path, config, "build_path");
+        complete_option(&mut self.remote_target_dir, config, "remote_target_dir");
+        complete_flag(&mut self.build_path_no_hash, config, "build_path_no_hash");
+        complete_option(&mut self.isolate, config, "isolate");
+        complete_flag(&mut self.isolate_toolchain, config, "isolate_toolchain");
+        complete_flag(&mut self.migrate_remote_dir, config, "migrate_remote_dir");
+        if !self.copy_back.is_empty() {
+            config.shadowed("copy_back");
+        } else {
+            // an empty path stands for the whole target folder
+            self.copy_back = match config.get("copy_back") {
+                None | Some(Value::Boolean(false)) => Vec::new(),
+                Some(Value::Boolean(true)) => vec![String::new()],
+                Some(paths) => Vec::<String>::from_config(paths).unwrap_or_else(|| {
+                    warn!(
+                        "Ignoring config key 'copy_back': {} is neither a boolean nor paths",
+                        paths
+                    );
+                    Vec::new()
+                }),
+            };
+        }
+        complete_flag(&mut self.no_copy_lock, config, "no_copy_lock");
+        complete_negated_flag(&mut self.no_copy_lock, config, "no_copy_lock", "copy_lock");
+        complete_list(&mut self.sync_back_files, config, "sync_back_files");
+        complete_flag(
+            &mut self.copy_back_on_failure,
+            config,
+            "copy_back_on_failure",
+        );
+        complete_flag(&mut self.copy_back_bins, config, "copy_back_bins");
+        complete_flag(&mut self.copy_back_isolate, config, "copy_back_isolate");
+        complete_option(&mut self.doc_crate, config, "doc_crate");
+        complete_list(&mut self.collect_on_failure, config, "collect_on_failure");
+        complete_flag(
+            &mut self.no_build_script_logs,
+            config,
+            "no_build_script_logs",
+        );
+        complete_negated_flag(
+            &mut self.no_build_script_logs,
+            config,
+            "no_build_script_logs",
+            "build_script_logs",
+        );
+        complete_list(&mut self.fetch, config, "fetch");
+        complete_flag(&mut self.no_hygiene_check, config, "no_hygiene_check");
+        complete_negated_flag(
+            &mut self.no_hygiene_check,
+            config,
+            "no_hygiene_check",
+            "hygiene_check",
+        );
+        complete_flag(&mut self.hidden, config, "hidden");
+        complete_list(&mut self.hidden_except, config, "hidden_except");
+        complete_list(&mut self.hidden_only, config, "hidden_only");
+        complete_flag(
+            &mut self.transfer_cargo_config,
+            config,
+            "transfer_cargo_config",
+        );
+        complete_flag(&mut self.use_direnv, config, "use_direnv");
+        complete_flag(&mut self.sync_advisory_db, config, "sync_advisory_db");
+        complete_flag(
+            &mut self.transfer_credentials,
+            config,
+            "transfer_credentials",
+        );
+        for key in &["includes", "include"] {
+            self.include
+                .extend(config_field::<Vec<String>>(config, key).unwrap_or_default());
+        }
+        for key in &["excludes", "exclude"] {
+            self.exclude
+                .extend(config_field::<Vec<String>>(config, key).unwrap_or_default());
+        }
+        complete_flag(&mut self.respect_gitignore, config, "respect_gitignore");
+        complete_flag_alias(
+            &mut self.respect_gitignore,
+            config,
+            "respect_gitignore",
+            "use_gitignore",
+        );
+        complete_flag(&mut self.no_delete, config, "no_delete");
+        complete_negated_flag(&mut self.no_delete, config, "no_delete", "delete");
+        complete_flag(&mut self.skip_unchanged, config, "skip_unchanged");
+        complete_flag(&mut self.remote_clean_shell, config, "remote_clean_shell");
+        complete_option(&mut self.remote_shell, config, "remote_shell");
+        complete_list(&mut self.ssh_opts, config, "ssh_options");
+        complete_option(&mut self.jump_host, config, "jump_host");
+        complete_option(&mut self.ssh_bin, config, "ssh_bin");
+        complete_option(&mut self.rsync_bin, config, "rsync_bin");
+        complete_option(&mut self.local_shell, config, "local_shell");
+        complete_option(&mut self.connect_timeout, config, "connect_timeout");
+        complete_flag(&mut self.skip_probe, config, "skip_probe");
+        complete_negated_flag(&mut self.skip_probe, config, "skip_probe", "probe");
+        complete_flag(&mut self.no_control_master, config, "no_control_master");
+        complete_negated_flag(
+            &mut self.no_control_master,
+            config,
+            "no_control_master",
+            "control_master",
+        );
+        complete_option(&mut self.ssh_control_path, config, "ssh_control_path");
+        complete_option(&mut self.retries, config, "retries");
+        if self.bwlimit.is_some() {
+            config.shadowed("bwlimit");
+        } else {
+            self.bwlimit = match config.get("bwlimit") {
+                Some(Value::String(limit)) => parse_bandwidth(limit)
+                    .map_err(|e| warn!("Ignoring config key 'bwlimit': {}", e))
+                    .ok(),
+                _ => config_field(config, "bwlimit"),
+            };
+        }
+        complete_flag(&mut self.no_compress, config, "no_compress");
+        complete_negated_flag(&mut self.no_compress, config, "no_compress", "compress");
+        complete_flag(&mut self.no_partial, config, "no_partial");
+        complete_negated_flag(&mut self.no_partial, config, "no_partial", "partial");
+        complete_flag(&mut self.force_tty, config, "force_tty");
+        complete_flag(&mut self.non_interactive, config, "non_interactive");
+        complete_flag(&mut self.no_pty_bridge, config, "no_pty_bridge");
+        complete_negated_flag(
+            &mut self.no_pty_bridge,
+            config,
+            "no_pty_bridge",
+            "pty_bridge",
+        );
+        complete_flag(
+            &mut self.no_stale_lock_recovery,
+            config,
+            "no_stale_lock_recovery",
+        );
+        complete_negated_flag(
+            &mut self.no_stale_lock_recovery,
+            config,
+            "no_stale_lock_recovery",
+            "stale_lock_recovery",
+        );
+        complete_option(&mut self.stale_lock_grace, config, "stale_lock_grace");
+        complete_option(&mut self.coalesce, config, "coalesce");
+        complete_flag(&mut self.no_space_monitor, config, "no_space_monitor");
+        complete_negated_flag(
+            &mut self.no_space_monitor,
+            config,
+            "no_space_monitor",
+            "space_monitor",
+        );
+        if self.space_floor.is_some() {
+            config.shadowed("space_floor");
+        } else {
+            self.space_floor = match config.get("space_floor") {
+                Some(Value::String(floor)) => parse_size(floor)
+                    .map_err(|e| warn!("Ignoring config key 'space_floor': {}", e))
+                    .ok(),
+                _ => config_field(config, "space_floor"),
+            };
+        }
+        if self.max_remote_target_size.is_some() {
+            config.shadowed("max_remote_target_size");
+        } else {
+            self.max_remote_target_size = match config.get("max_remote_target_size") {
+                Some(Value::String(size)) => parse_size(size)
+                    .map_err(|e| warn!("Ignoring config key 'max_remote_target_size': {}", e))
+                    .ok(),
+                _ => config_field(config, "max_remote_target_size"),
+            };
+        }
+        complete_option(
+            &mut self.space_monitor_interval,
+            config,
+            "space_monitor_interval",
+        );
+        complete_option(
+            &mut self.regression_threshold,
+            config,
+            "regression_threshold",
+        );
+        complete_flag(&mut self.stats, config, "stats");
+        complete_flag(&mut self.strict_flags, config, "strict_flags");
+        complete_option(&mut self.sync_timeout, config, "sync_timeout");
+        complete_option(&mut self.build_timeout, config, "build_timeout");
+        complete_option(&mut self.build_timeout, config, "timeout");
+        complete_option(&mut self.copy_back_timeout, config, "copy_back_timeout");
+        complete_flag(&mut self.no_config_warnings, config, "no_config_warnings");
+        complete_negated_flag(
+            &mut self.no_config_warnings,
+            config,
+            "no_config_warnings",
+            "config_warnings",
+        );
+        complete_flag(&mut self.audit, config, "audit");
+        complete_option(&mut self.audit_log, config, "audit_log");
+        complete_option(&mut self.webhook, config, "webhook");
+        complete_option(&mut self.webhook_on, config, "webhook_on");
+        config.unused()
+    }
+
+    /// Picks the build server for [`target`] from the `[targets]` table of the [`named_configs`]
+    /// with the highest precedence, returning the name of that config. Only called if neither
+    /// `--remote` nor `CARGO_REMOTE_HOST` named a build server, as the table takes precedence over
+    /// the `remote` keys of the configs.
+    pub fn pick_target_remote<'a>(
+        &mut self,
+        target: &str,
+        named_configs: &'a [(String, Value)],
+    ) -> Option<&'a str> {
+        let (name, remote) = named_configs
+            .iter()
+            .find_map(|(name, c)| Some((name, c.get("targets")?.get(target)?)))?;
+        match remote.as_str() {
+            Some(remote) => {
+                info!("Using the build server '{}' for {}", remote, target);
+                self.remote = vec![remote.to_owned()];
+                Some(name)
+            }
+            None => {
+                warn!(
+                    "Ignoring the [targets] entry for {}: {} isn't a build server",
+                    target, remote
+                );
+                None
+            }
+        }
+    }
+
+    /// Sets the defaults of options that were given neither on the command line nor in a config.
+    pub fn apply_defaults(&mut self) {
+        if self.build_env.is_empty() {
+            self.build_env.push("RUST_BACKTRACE=1".to_owned());
+        }
+        if self.env.is_empty() {
+            self.env.push("~/.cargo/env".to_owned());
+        }
+        if self.build_path.is_none() {
+            self.build_path = Some("~/remote-builds".to_owned());
+        }
+        if self.isolate.is_none() {
+            self.isolate = Some(Isolation::Host);
+        }
+        if self.ssh_bin.is_none() {
+            self.ssh_bin = Some("ssh".to_owned());
+        }
+        if self.remote_shell.is_none() {
+            self.remote_shell = Some("bash".to_owned());
+        }
+        if self.rsync_bin.is_none() {
+            self.rsync_bin = Some("rsync".to_owned());
+        }
+        if self.regression_threshold.is_none() {
+            self.regression_threshold = Some(25);
+        }
+        if self.stale_lock_grace.is_none() {
+            self.stale_lock_grace = Some(Duration::from_secs(30));
+        }
+        if self.space_floor.is_none() {
+            self.space_floor = Some(1 << 30);
+        }
+        if self.space_monitor_interval.is_none() {
+            self.space_monitor_interval = Some(Duration::from_secs(10));
+        }
+    }
+}
+
+/// What besides the local project path tells the remote build directories apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// Checkouts at the same path share a directory, even on different machines.
+    None,
+    /// Every machine gets its own directory.
+    Host,
+    /// Every machine and git branch gets its own directory.
+    Branch,
+}
+
+impl FromStr for Isolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Isolation::None),
+            "host" => Ok(Isolation::Host),
+            "branch" => Ok(Isolation::Branch),
+            _ => Err(format!(
+                "unknown isolation '{}', expected none, host or branch",
+                s
+            )),
+        }
+    }
+}
+
+/// Types that can be read from a config value.
+trait FromConfig: Sized {
+    fn from_config(value: &Value) -> Option<Self>;
+}
+
+impl FromConfig for String {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_str().map(String::from)
+    }
+}
+
+impl FromConfig for bool {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_bool()
+    }
+}
+
+impl FromConfig for u64 {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_integer().filter(|i| *i >= 0).map(|i| i as u64)
+    }
+}
+
+impl FromConfig for u32 {
+    fn from_config(value: &Value) -> Option<Self> {
+        value
+            .as_integer()
+            .filter(|i| (0..=i64::from(u32::MAX)).contains(i))
+            .map(|i| i as u32)
+    }
+}
+
+/// Accepts either a single string or an array of strings.
+impl FromConfig for Vec<String> {
+    fn from_config(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(vec![s.clone()]),
+            Value::Array(values) => values.iter().map(String::from_config).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl FromConfig for Isolation {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_str()?.parse().ok()
+    }
+}
+
+impl FromConfig for LocalShell {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_str()?.parse().ok()
+    }
+}
+
+impl FromConfig for WebhookOn {
+    fn from_config(value: &Value) -> Option<Self> {
+        value.as_str()?.parse().ok()
+    }
+}
+
+/// Accepts either a string like `"30m"` or a number of seconds.
+impl FromConfig for Duration {
+    fn from_config(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(secs) if *secs >= 0 => Some(Duration::from_secs(*secs as u64)),
+            Value::String(s) => parse_duration(s).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Top-level keys that aren't options but are read from the configs directly.
+const TABLE_KEYS: [&str; 3] = ["remotes", "targets", "webhook_headers"];
+
+/// The keys of a config that had no effect.
+#[derive(Default)]
+pub struct UnusedKeys {
+    /// Keys this version of cargo-remote doesn't know, each with the known key it's likely a typo
+    /// of, if any.
+    pub unknown: Vec<(String, Option<String>)>,
+    /// Keys whose option was already set by the command line, a config with a higher precedence
+    /// or another key of the same config.
+    pub shadowed: Vec<String>,
+}
+
+/// A config while options are completed from it, which remembers the keys that were looked at and
+/// the ones that were present but had no effect.
+struct Source<'a> {
+    value: &'a Value,
+    read: RefCell<BTreeSet<String>>,
+    shadowed: RefCell<BTreeSet<String>>,
+    /// The flags set by this config or one with a higher precedence, to either value.
+    decided: &'a RefCell<BTreeSet<String>>,
+}
+
+impl<'a> Source<'a> {
+    fn new(value: &'a Value, decided: &'a RefCell<BTreeSet<String>>) -> Self {
+        Source {
+            value,
+            read: RefCell::default(),
+            shadowed: RefCell::default(),
+            decided,
+        }
+    }
+
+    fn decided(&self, flag: &str) -> bool {
+        self.decided.borrow().contains(flag)
+    }
+
+    fn decide(&self, flag: &str) {
+        self.decided.borrow_mut().insert(flag.to_owned());
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.read.borrow_mut().insert(key.to_owned());
+        self.value.get(key)
+    }
+
+    /// Records that [`key`] has no effect if it's present, as its option is set already.
+    fn shadowed(&self, key: &str) {
+        if self.get(key).is_some() {
+            self.shadowed.borrow_mut().insert(key.to_owned());
+        }
+    }
+
+    fn unused(&self) -> UnusedKeys {
+        let read = self.read.borrow();
+        let unknown = self
+            .value
+            .as_table()
+            .map(|table| {
+                table
+                    .keys()
+                    .filter(|key| !read.contains(*key) && !TABLE_KEYS.contains(&key.as_str()))
+                    .map(|key| {
+                        let known = read.iter().map(String::as_str).chain(TABLE_KEYS);
+                        (key.clone(), closest_key(key, known))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        UnusedKeys {
+            unknown,
+            shadowed: self.shadowed.borrow().iter().cloned().collect(),
+        }
+    }
+}
+
+/// The key of [`known`] that [`key`] is most likely a typo of, e.g. `remote` for `remtoe` or
+/// `build_env` for `buildenv`: the closest one by edit distance, if it's close enough.
+fn closest_key<'k>(key: &str, known: impl Iterator<Item = &'k str>) -> Option<String> {
+    let max_distance = (key.chars().count() / 3).max(1);
+    known
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate.to_owned())
+}
+
+/// The Levenshtein distance between [`a`] and [`b`], counting a swap of two neighbouring
+/// characters as one edit, as that's a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // rows[i][j] is the distance between the first i characters of a and the first j of b
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + substitution);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Reads [`key`] from [`config`], logging a warning if it has the wrong type.
+fn config_field<T: FromConfig>(config: &Source, key: &str) -> Option<T> {
+    let value = config.get(key)?;
+    let parsed = T::from_config(value);
+    if parsed.is_none() {
+        warn!("Ignoring config key '{}': invalid value {}", key, value);
+    }
+    parsed
+}
+
+fn complete_option<T: FromConfig>(field: &mut Option<T>, config: &Source, key: &str) {
+    if field.is_none() {
+        *field = config_field(config, key);
+    } else {
+        config.shadowed(key);
+    }
+}
+
+fn complete_list(field: &mut Vec<String>, config: &Source, key: &str) {
+    if field.is_empty() {
+        *field = config_field(config, key).unwrap_or_default();
+    } else {
+        config.shadowed(key);
+    }
+}
+
+fn complete_flag(field: &mut bool, config: &Source, key: &str) {
+    complete_bool(field, config, key, key, false);
+}
+
+/// Completes the flag [`field`] named [`flag`] from its other name [`alias`].
+fn complete_flag_alias(field: &mut bool, config: &Source, flag: &str, alias: &str) {
+    complete_bool(field, config, flag, alias, false);
+}
+
+/// Completes the `no_*` flag [`field`] named [`flag`] from [`key`], which is the positive option,
+/// e.g. `compress = false` for `no_compress`.
+fn complete_negated_flag(field: &mut bool, config: &Source, flag: &str, key: &str) {
+    complete_bool(field, config, flag, key, true);
+}
+
+/// Sets the flag [`field`] named [`flag`] from [`key`] of [`config`], inverted if [`negated`].
+/// The flag is set by the command line if it's true already, a config with a higher precedence
+/// may also have set it to false, which a config with a lower precedence can't turn around.
+fn complete_bool(field: &mut bool, config: &Source, flag: &str, key: &str, negated: bool) {
+    if *field || config.decided(flag) {
+        config.shadowed(key);
+    } else if let Some(value) = config_field::<bool>(config, key) {
+        *field = value != negated;
+        config.decide(flag);
+    }
+}
+
+/// Tries to parse the file [`config_path`]. Logs warnings and returns [`None`] if errors occur
+/// during reading or parsing, [`Some(Value)`] otherwise.
+pub fn config_from_file(config_path: &Path, silence: bool) -> Option<Value> {
+    let config_file = std::fs::read_to_string(config_path)
+        .map_err(|e| {
+            if !silence {
+                warn!(
+                    "Can't parse config file '{}' (error: {})",
+                    config_path.to_string_lossy(),
+                    e
+                );
+            }
+        })
+        .ok()?;
+
+    let value = config_file
+        .parse::<Value>()
+        .map_err(|e| {
+            if !silence {
+                warn!(
+                    "Can't parse config file '{}' (error: {})",
+                    config_path.to_string_lossy(),
+                    e
+                );
+            }
+        })
+        .ok()?;
+
+    Some(value)
+}
+
+/// Extracts the `[workspace.metadata.cargo-remote]` table from the workspace's `Cargo.toml`.
+pub fn config_from_workspace_metadata(workspace_root: &Path) -> Option<Value> {
+    config_from_file(&workspace_root.join("Cargo.toml"), true)?
+        .get("workspace")?
+        .get("metadata")?
+        .get("cargo-remote")
+        .cloned()
+}
+
+/// Extracts the `[package.metadata.cargo-remote]` table of the package defined by
+/// [`manifest_path`] from the already loaded cargo metadata, or from the manifest itself if the
+/// metadata has no packages because there's no local cargo.
+pub fn config_from_package_metadata(
+    metadata: &cargo_metadata::Metadata,
+    manifest_path: &Path,
+) -> Option<Value> {
+    if metadata.packages.is_empty() {
+        return config_from_file(manifest_path, true)?
+            .get("package")?
+            .get("metadata")?
+            .get("cargo-remote")
+            .cloned();
+    }
+    let package = metadata
+        .packages
+        .iter()
+        .find(|p| p.manifest_path == manifest_path)?;
+    let section = package.metadata.get("cargo-remote")?;
+
+    Value::try_from(section)
+        .map_err(|e| {
+            warn!(
+                "Can't parse [package.metadata.cargo-remote] in '{}' (error: {})",
+                manifest_path.to_string_lossy(),
+                e
+            );
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    /// Held by the tests that parse a command line, as `CARGO_REMOTE_HOST` is read from the
+    /// environment of the process the tests share.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn parse(args: &[&str]) -> RemoteConfig {
+        RemoteConfig::from_iter(std::iter::once("cargo-remote").chain(args.iter().copied()))
+    }
+
+    fn toml(text: &str) -> Value {
+        text.parse().unwrap()
+    }
+
+    /// Completes the command line [`args`] from the project and the global config.
+    fn resolve(args: &[&str], project: &str, global: &str) -> RemoteConfig {
+        let mut config = parse(args);
+        config.complete_from_configs(&[toml(project), toml(global)]);
+        config
+    }
+
+    #[test]
+    fn global_config_applies_without_flag() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(&[], "", "rustup_default = \"nightly\"\nhidden = true");
+        assert_eq!(config.rustup_default.as_deref(), Some("nightly"));
+        assert!(config.hidden);
+    }
+
+    #[test]
+    fn flag_beats_global_config() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(
+            &["--rustup-default", "stable", "-e", "~/.profile"],
+            "",
+            "rustup_default = \"nightly\"\nenv = [\"~/.cargo/env\"]",
+        );
+        assert_eq!(config.rustup_default.as_deref(), Some("stable"));
+        assert_eq!(config.env, ["~/.profile"]);
+    }
+
+    #[test]
+    fn project_config_beats_global_config() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(
+            &[],
+            "build_env = [\"CC=clang\"]\ncopy_back = true",
+            "build_env = [\"CC=gcc\"]\ncopy_back = [\"release/app\"]",
+        );
+        assert_eq!(config.build_env, ["CC=clang"]);
+        assert_eq!(config.copy_back, [""]);
+    }
+
+    #[test]
+    fn project_config_turns_off_global_flag() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(&[], "hidden = false", "hidden = true");
+        assert!(!config.hidden);
+        let config = resolve(&[], "copy_lock = true", "no_copy_lock = true");
+        assert!(!config.no_copy_lock);
+        let config = resolve(&[], "no_copy_lock = false", "copy_lock = false");
+        assert!(!config.no_copy_lock);
+    }
+
+    #[test]
+    fn project_config_turns_on_global_flag() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(&[], "hidden = true", "hidden = false");
+        assert!(config.hidden);
+        let config = resolve(&[], "copy_lock = false", "copy_lock = true");
+        assert!(config.no_copy_lock);
+    }
+
+    #[test]
+    fn flag_beats_config_turning_it_off() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = resolve(
+            &["--transfer-hidden", "--no-copy-lock"],
+            "hidden = false",
+            "copy_lock = true",
+        );
+        assert!(config.hidden);
+        assert!(config.no_copy_lock);
+    }
+
+    #[test]
+    fn overridden_keys_are_reported() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let mut config = parse(&["--rustup-default", "stable"]);
+        let unused = config.complete_from_configs(&[
+            toml("hidden = false"),
+            toml("hidden = true\nrustup_default = \"nightly\""),
+        ]);
+        assert!(unused[0].shadowed.is_empty());
+        assert_eq!(unused[1].shadowed, ["hidden", "rustup_default"]);
+    }
+
+    /// A workspace with the package `app`, each with a cargo-remote table in its manifest, and a
+    /// `.cargo-remote.toml`. Removed when the test ends.
+    struct Workspace(PathBuf);
+
+    impl Workspace {
+        fn new(name: &str) -> Workspace {
+            let root = std::env::temp_dir().join(format!(
+                "cargo-remote-metadata-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(root.join("app")).unwrap();
+            let write =
+                |path: &str, contents: &str| std::fs::write(root.join(path), contents).unwrap();
+            write(
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"app\"]\n\n[workspace.metadata.cargo-remote]\n\
+                 remote = \"workspace-host\"\nrustup_default = \"stable\"\nhidden = true\n",
+            );
+            write(
+                "app/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n\
+                 [package.metadata.cargo-remote]\nremote = \"package-host\"\n\
+                 rustup_default = \"beta\"\n",
+            );
+            write(".cargo-remote.toml", "rustup_default = \"nightly\"\n");
+            Workspace(root)
+        }
+
+        fn manifest(&self) -> PathBuf {
+            self.0.join("app/Cargo.toml")
+        }
+
+        /// What cargo metadata reports, with the package `app` unless there's no local cargo.
+        fn metadata(
+            &self,
+            package_metadata: Option<serde_json::Value>,
+        ) -> cargo_metadata::Metadata {
+            let packages: Vec<_> = package_metadata
+                .into_iter()
+                .map(|metadata| {
+                    serde_json::json!({
+                        "name": "app",
+                        "version": "0.1.0",
+                        "id": "app 0.1.0",
+                        "source": null,
+                        "dependencies": [],
+                        "targets": [],
+                        "features": {},
+                        "manifest_path": self.manifest(),
+                        "metadata": metadata,
+                    })
+                })
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "packages": packages,
+                "workspace_members": [],
+                "resolve": null,
+                "workspace_root": self.0,
+                "target_directory": self.0.join("target"),
+                "version": 1,
+            }))
+            .unwrap()
+        }
+    }
+
+    impl Drop for Workspace {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn metadata_tables() {
+        let workspace = Workspace::new("tables");
+        assert_eq!(
+            config_from_workspace_metadata(&workspace.0),
+            Some(toml(
+                "remote = \"workspace-host\"\nrustup_default = \"stable\"\nhidden = true"
+            ))
+        );
+        // a package is no workspace
+        assert_eq!(
+            config_from_workspace_metadata(&workspace.0.join("app")),
+            None
+        );
+
+        // without a local cargo, from the manifest
+        let package = toml("remote = \"package-host\"\nrustup_default = \"beta\"");
+        let no_packages = workspace.metadata(None);
+        assert_eq!(
+            config_from_package_metadata(&no_packages, &workspace.manifest()),
+            Some(package)
+        );
+        assert_eq!(
+            config_from_package_metadata(&no_packages, &workspace.0.join("Cargo.toml")),
+            None
+        );
+
+        // otherwise as cargo reports it
+        let metadata = workspace.metadata(Some(serde_json::json!({
+            "cargo-remote": {"remote": "reported", "copy_back": ["debug/app"]},
+            "other-tool": {"remote": "ignored"},
+        })));
+        assert_eq!(
+            config_from_package_metadata(&metadata, &workspace.manifest()),
+            Some(toml("remote = \"reported\"\ncopy_back = [\"debug/app\"]"))
+        );
+        assert_eq!(
+            config_from_package_metadata(&metadata, &workspace.0.join("Cargo.toml")),
+            None
+        );
+        let without_table = workspace.metadata(Some(serde_json::json!({"other-tool": {}})));
+        assert_eq!(
+            config_from_package_metadata(&without_table, &workspace.manifest()),
+            None
+        );
+    }
+
+    #[test]
+    fn metadata_precedence() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let workspace = Workspace::new("precedence");
+        let configs = crate::load_named_configs(&workspace.metadata(None), &workspace.manifest());
+        // .cargo-remote.toml, then the package, then the workspace, then the global config
+        assert!(configs.len() >= 3, "{:?}", configs);
+        assert_eq!(configs[0].1, toml("rustup_default = \"nightly\""));
+
+        let mut config = parse(&[]);
+        config.complete_from_configs(configs.iter().map(|(_, c)| c));
+        assert_eq!(config.rustup_default.as_deref(), Some("nightly"));
+        assert_eq!(config.remote, ["package-host"]);
+        assert!(config.hidden);
+
+        let mut config = parse(&["--rustup-default", "1.70", "-r", "flag"]);
+        config.complete_from_configs(configs.iter().map(|(_, c)| c));
+        assert_eq!(config.rustup_default.as_deref(), Some("1.70"));
+        assert_eq!(config.remote, ["flag"]);
+
+        // without the package table, the workspace's applies
+        std::fs::write(workspace.manifest(), "[package]\nname = \"app\"\n").unwrap();
+        let configs = crate::load_named_configs(&workspace.metadata(None), &workspace.manifest());
+        let mut config = parse(&[]);
+        config.complete_from_configs(configs.iter().map(|(_, c)| c));
+        assert_eq!(config.rustup_default.as_deref(), Some("nightly"));
+        assert_eq!(config.remote, ["workspace-host"]);
+    }
+}
@@ -0,0 +1,86 @@
+//! `cargo remote config`: the effective values of the options that decide where and how a build
+//! runs, each with the flag, config file or default it came from, to debug which config won.
+
+use std::fmt;
+
+use toml::Value;
+
+use crate::config::{RemoteConfig, REMOTE_ENV};
+
+/// Where the value of an option came from.
+pub enum Origin {
+    CommandLine,
+    /// The environment variable with this name.
+    Environment(&'static str),
+    /// The config with this name, e.g. its path.
+    Config(String),
+    /// Not set anywhere, so the default or a value derived from the project.
+    Default(&'static str),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::CommandLine => write!(f, "command line"),
+            Origin::Environment(name) => write!(f, "environment variable {}", name),
+            Origin::Config(name) => write!(f, "{}", name),
+            Origin::Default(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+/// The origins of the printed options, taken before the configs complete [`RemoteConfig`].
+pub struct Origins {
+    pub remote: Origin,
+    pub build_env: Origin,
+    pub rustup_default: Origin,
+    pub env: Origin,
+    pub build_path: Origin,
+}
+
+impl Origins {
+    /// Finds the origins of the options of [`cli`], which holds only the command line and the
+    /// build server of [`REMOTE_ENV`] if [`remote_from_env`] so far, and the [`named_configs`],
+    /// highest precedence first.
+    pub fn new(
+        cli: &RemoteConfig,
+        remote_from_env: bool,
+        named_configs: &[(String, Value)],
+    ) -> Origins {
+        let origin = |set: bool, key: &str| {
+            if set {
+                return Origin::CommandLine;
+            }
+            named_configs
+                .iter()
+                .find(|(_, config)| config.get(key).is_some())
+                .map_or(Origin::Default("default"), |(name, _)| {
+                    Origin::Config(name.clone())
+                })
+        };
+        Origins {
+            remote: if remote_from_env {
+                Origin::Environment(REMOTE_ENV)
+            } else {
+                origin(!cli.remote.is_empty(), "remote")
+            },
+            build_env: origin(!cli.build_env.is_empty(), "build_env"),
+            rustup_default: origin(cli.rustup_default.is_some(), "rustup_default"),
+            env: origin(!cli.env.is_empty(), "env"),
+            build_path: origin(cli.build_path.is_some(), "build_path"),
+        }
+    }
+}
+
+/// Prints the [`values`] with their origins as TOML, so they can be pasted into a config.
+pub fn print(values: &[(&str, Value, &Origin)]) {
+    let width = values
+        .iter()
+        .map(|(key, value, _)| key.len() + value.to_string().len() + 3)
+        .max()
+        .unwrap_or(0);
+    for (key, value, origin) in values {
+        let line = format!("{} = {}", key, value);
+        println!("{:width$}  # {}", line, origin, width = width);
+    }
+}
@@ -0,0 +1,286 @@
+//! Cargo options that control the same thing as an option of cargo-remote. The cargo option
+//! always wins, the conflict is reported so the outcome doesn't come as a surprise.
+
+use std::fmt;
+
+use log::warn;
+
+use crate::error::Error;
+use crate::option_values;
+
+/// The options of cargo-remote that cargo options can conflict with.
+pub struct Settings<'a> {
+    pub target: Option<&'a str>,
+    pub build_env: &'a [String],
+    pub copies_back: bool,
+    pub force_tty: bool,
+}
+
+/// An option of cargo-remote and a cargo option that both control [`Conflict::knob`].
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub ours: String,
+    pub cargo: String,
+    pub knob: &'static str,
+    /// What the cargo option winning means.
+    pub outcome: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} and the cargo option {} both set {}, the cargo option wins: {}",
+            self.ours, self.cargo, self.knob, self.outcome
+        )
+    }
+}
+
+/// Environment variables of the build that cargo options override.
+const ENV_OPTIONS: &[(&str, &[&str], &str)] = &[
+    ("CARGO_TERM_COLOR", &["--color"], "the color choice"),
+    ("CARGO_BUILD_JOBS", &["--jobs", "-j"], "the number of jobs"),
+    (
+        "CARGO_NET_OFFLINE",
+        &["--offline", "--frozen"],
+        "network access",
+    ),
+    ("CARGO_BUILD_TARGET", &["--target"], "the target triple"),
+    (
+        "CARGO_TARGET_DIR",
+        &["--target-dir"],
+        "the target directory",
+    ),
+];
+
+/// Finds the conflicts of the cargo [`options`] of one step with the cargo-remote [`settings`].
+pub fn detect(settings: &Settings, options: &[String]) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+
+    if let (Some(ours), Some(theirs)) = (settings.target, option_values(options, "--target").last())
+    {
+        if ours != theirs {
+            conflicts.push(Conflict {
+                ours: format!("--target {}", ours),
+                cargo: format!("--target {}", theirs),
+                knob: "the target triple",
+                outcome: format!("artifacts are copied back from target/{}/", theirs),
+            });
+        }
+    }
+
+    if settings.copies_back && has_option(options, "--target-dir") {
+        conflicts.push(Conflict {
+            ours: "--copy-back".to_owned(),
+            cargo: "--target-dir".to_owned(),
+            knob: "where the artifacts are",
+            outcome: "artifacts are still copied back from target/, which cargo doesn't write to"
+                .to_owned(),
+        });
+    }
+
+    if settings.force_tty
+        && option_values(options, "--message-format").any(|f| f.starts_with("json"))
+    {
+        conflicts.push(Conflict {
+            ours: "--force-tty".to_owned(),
+            cargo: "--message-format=json".to_owned(),
+            knob: "how the output is forwarded",
+            outcome: "no TTY is forced, so stdout stays machine readable".to_owned(),
+        });
+    }
+
+    for (variable, names, knob) in ENV_OPTIONS {
+        let assigned = settings
+            .build_env
+            .iter()
+            .find(|e| e.split('=').next() == Some(variable));
+        let option = names.iter().find(|name| has_option(options, name));
+        if let (Some(assignment), Some(option)) = (assigned, option) {
+            conflicts.push(Conflict {
+                ours: format!("--build-env {}", assignment),
+                cargo: (*option).to_owned(),
+                knob,
+                outcome: format!("cargo ignores {}", variable),
+            });
+        }
+    }
+    conflicts
+}
+
+/// Warns about the conflicts of the cargo [`options`] of all steps, each one once. With [`strict`]
+/// (`--strict-flags`) they fail the run.
+pub fn report<'a>(
+    settings: &Settings,
+    options: impl Iterator<Item = &'a [String]>,
+    strict: bool,
+) -> Result<Vec<Conflict>, Error> {
+    let mut found: Vec<Conflict> = Vec::new();
+    for conflict in options.flat_map(|options| detect(settings, options)) {
+        if !found.contains(&conflict) {
+            found.push(conflict);
+        }
+    }
+    for conflict in &found {
+        warn!("{}", conflict);
+    }
+    if strict && !found.is_empty() {
+        return Err(Error::FlagConflicts(found.len()));
+    }
+    Ok(found)
+}
+
+/// Whether the cargo option [`name`] is given, with or without a value. Short options like `-j`
+/// may have their value attached, as in `-j4`.
+fn has_option(options: &[String], name: &str) -> bool {
+    let options = options.split(|o| o == "--").next().unwrap_or(&[]);
+    options.iter().any(|option| {
+        option == name
+            || option
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('=') || !name.starts_with("--"))
+    })
+}
+
+/// What keeps cargo off the network in the cargo [`options`] of the steps or the [`build_env`]:
+/// `--offline`, `--frozen` or `CARGO_NET_OFFLINE=true`. cargo-remote follows along, it leaves
+/// Cargo.lock and the registry alone and doesn't install anything on the build server.
+pub fn offline<'a>(
+    mut options: impl Iterator<Item = &'a [String]>,
+    build_env: &[String],
+) -> Option<&'static str> {
+    let from_options = options.find_map(|options| {
+        ["--offline", "--frozen"]
+            .iter()
+            .copied()
+            .find(|name| has_option(options, name))
+    });
+    from_options.or_else(|| {
+        build_env
+            .iter()
+            .any(|e| e == "CARGO_NET_OFFLINE=true")
+            .then_some("CARGO_NET_OFFLINE=true")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| (*v).to_owned()).collect()
+    }
+
+    fn settings<'a>(target: Option<&'a str>, build_env: &'a [String]) -> Settings<'a> {
+        Settings {
+            target,
+            build_env,
+            copies_back: true,
+            force_tty: true,
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        // the cargo options and the option of cargo-remote and cargo that conflict, if any
+        type Case<'a> = (&'a [&'a str], Option<(&'a str, &'a str)>);
+        let cases: &[Case] = &[
+            (&["--release"], None),
+            (&["--target", "x86_64-unknown-linux-gnu"], None),
+            (
+                &["--target", "aarch64-unknown-linux-gnu"],
+                Some((
+                    "--target x86_64-unknown-linux-gnu",
+                    "--target aarch64-unknown-linux-gnu",
+                )),
+            ),
+            (
+                &["--target=aarch64-unknown-linux-gnu"],
+                Some((
+                    "--target x86_64-unknown-linux-gnu",
+                    "--target aarch64-unknown-linux-gnu",
+                )),
+            ),
+            (
+                &["--target-dir", "out"],
+                Some(("--copy-back", "--target-dir")),
+            ),
+            (&["--target-dir=out"], Some(("--copy-back", "--target-dir"))),
+            (
+                &["--message-format=json"],
+                Some(("--force-tty", "--message-format=json")),
+            ),
+            (
+                &["--message-format", "json-diagnostic-rendered-ansi"],
+                Some(("--force-tty", "--message-format=json")),
+            ),
+            (&["--message-format", "short"], None),
+            (&["-j4"], Some(("--build-env CARGO_BUILD_JOBS=8", "-j"))),
+            (
+                &["--jobs", "4"],
+                Some(("--build-env CARGO_BUILD_JOBS=8", "--jobs")),
+            ),
+            (&["--color=never"], None),
+            // the options after `--` belong to the program, not to cargo
+            (&["--", "--target-dir", "out", "-j4"], None),
+        ];
+        let build_env = strings(&["CARGO_BUILD_JOBS=8"]);
+        let settings = settings(Some("x86_64-unknown-linux-gnu"), &build_env);
+        for (options, expected) in cases {
+            let found = detect(&settings, &strings(options));
+            let found: Vec<_> = found
+                .iter()
+                .map(|c| (c.ours.as_str(), c.cargo.as_str()))
+                .collect();
+            assert_eq!(
+                found,
+                expected.iter().copied().collect::<Vec<_>>(),
+                "{:?}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn no_conflicts_without_our_options() {
+        let settings = Settings {
+            target: None,
+            build_env: &[],
+            copies_back: false,
+            force_tty: false,
+        };
+        let options = strings(&[
+            "--target=a",
+            "--target-dir=out",
+            "--message-format=json",
+            "-j4",
+        ]);
+        assert_eq!(detect(&settings, &options), []);
+    }
+
+    #[test]
+    fn strict_flags_fail_the_run() {
+        let build_env = strings(&["CARGO_NET_OFFLINE=true"]);
+        let settings = settings(None, &build_env);
+        let steps = [
+            strings(&["--offline", "--target-dir", "out"]),
+            strings(&["--frozen"]),
+            strings(&["--target-dir", "out"]),
+        ];
+        let steps = || steps.iter().map(Vec::as_slice);
+
+        // the same conflict in several steps is reported once
+        let found = report(&settings, steps(), false).unwrap();
+        assert_eq!(found.len(), 3);
+
+        let error = report(&settings, steps(), true).unwrap_err();
+        assert!(matches!(error, Error::FlagConflicts(3)));
+        assert_eq!(error.exit_code(), 17);
+
+        let clean = [strings(&["--release"])];
+        assert_eq!(
+            report(&settings, clean.iter().map(Vec::as_slice), true).unwrap(),
+            []
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! `cargo remote doc --open`: a browser on the build server is of no use, so the documentation is
+//! transferred back and opened locally instead.
+
+use std::path::Path;
+
+use log::warn;
+
+use crate::{interactive, option_values, Step};
+
+/// The files next to the crate directories that every page of the documentation needs.
+const SHARED_FILES: [&str; 3] = ["static.files/", "search-index*.js", "crates.js"];
+
+/// Removes `--open` from the `doc` steps, so cargo doesn't look for a browser on the build server.
+/// Returns whether one of them had it.
+pub fn take_open(steps: &mut [Step]) -> bool {
+    let mut open = false;
+    for step in steps
+        .iter_mut()
+        .filter(|step| step.command == "doc" || step.command == "d")
+    {
+        // the options after `--` go to rustdoc
+        let end = step
+            .options
+            .iter()
+            .position(|o| o == "--")
+            .unwrap_or(step.options.len());
+        let before = step.options.len();
+        let mut i = 0;
+        step.options.retain(|o| {
+            i += 1;
+            i > end || o != "--open"
+        });
+        open |= step.options.len() != before;
+    }
+    open
+}
+
+/// The files of the documentation below `target/doc/` that [`crate_name`] needs, for a transfer
+/// of just that crate.
+pub fn crate_files(crate_name: &str) -> Vec<String> {
+    std::iter::once(format!("{}/", crate_name))
+        .chain(std::iter::once(format!("search.desc/{}/", crate_name)))
+        .chain(SHARED_FILES.iter().map(|file| (*file).to_owned()))
+        .collect()
+}
+
+/// The name of the crate `cargo doc --open` would open: the one of the package selected with `-p`
+/// in [`steps`], else the package of [`manifest_path`], else the first workspace member. Like in
+/// the directory names of rustdoc, dashes are replaced by underscores.
+pub fn crate_name(
+    metadata: &cargo_metadata::Metadata,
+    manifest_path: &Path,
+    steps: &[Step],
+) -> Option<String> {
+    let selected = steps
+        .iter()
+        .filter(|step| step.command == "doc" || step.command == "d")
+        .find_map(|step| {
+            option_values(&step.options, "--package")
+                .chain(option_values(&step.options, "-p"))
+                .next()
+        });
+    let package = match selected {
+        Some(name) => metadata.packages.iter().find(|p| p.name == name),
+        None => metadata
+            .packages
+            .iter()
+            .find(|p| p.manifest_path == manifest_path)
+            .or_else(|| {
+                let first = metadata.workspace_members.first()?;
+                metadata.packages.iter().find(|p| &p.id == first)
+            }),
+    }?;
+    // the library is documented under its own name, which may differ from the package name
+    let target = package
+        .targets
+        .iter()
+        .find(|t| t.kind.iter().any(|k| k == "lib" || k.ends_with("lib")))
+        .or_else(|| package.targets.first())?;
+    Some(target.name.replace('-', "_"))
+}
+
+/// Prints where the documentation is and opens it in the local browser if there is a terminal,
+/// as a browser can't be expected in a CI job.
+pub fn open(index: &Path) {
+    if !index.is_file() {
+        warn!(
+            "The documentation wasn't found at {}, pass --doc-crate to choose the crate",
+            index.to_string_lossy()
+        );
+        return;
+    }
+    eprintln!("Documentation: {}", index.to_string_lossy());
+    if interactive::stdout() {
+        if let Err(e) = opener::open(index) {
+            warn!(
+                "Failed to open the documentation in a browser (error: {})",
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Errors that abort a cargo-remote run and the exit codes they are reported with.
+
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::audit;
+use crate::units::format_duration;
+
+/// A failure that ends the current run. Each variant exits with its own code, see
+/// [`Error::exit_code`].
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Neither the command line nor a config defines a build server.
+    #[error(
+        "No remote build server was defined (use config file, --remote flag or CARGO_REMOTE_HOST)"
+    )]
+    NoRemote,
+    /// Transferring the sources to the build server failed.
+    #[error("Failed to transfer project to build server ({0})")]
+    Sync(String),
+    /// The remote command couldn't be run.
+    #[error("Failed to run cargo command remotely ({0})")]
+    Build(String),
+    /// Transferring artifacts back failed.
+    #[error("Failed to transfer artifacts back ({0})")]
+    CopyBack(String),
+    /// Transferring the Cargo.lock file back failed.
+    #[error("Failed to transfer Cargo.lock back ({0})")]
+    CopyLock(String),
+    /// The local project couldn't be found or loaded.
+    #[error("Failed to load the project ({0})")]
+    Project(String),
+    /// The directory relative to the workspace root couldn't be computed.
+    #[error("Failed to compute the relative path ({0})")]
+    RelativePath(String),
+    /// The user refused to sync over a build directory of another checkout.
+    #[error("Sync aborted (use --force to sync anyway)")]
+    SyncAborted,
+    /// A phase took longer than its timeout.
+    #[error("The {phase} phase timed out after {}", format_duration(*.after))]
+    Timeout {
+        phase: &'static str,
+        after: Duration,
+    },
+    /// `cargo remote init` couldn't write the config.
+    #[error("{0}")]
+    Init(String),
+    /// The user pressed Ctrl-C.
+    #[error("Interrupted during the {phase} phase")]
+    Interrupted { phase: &'static str },
+    /// The remote build directory couldn't be removed.
+    #[error("Failed to remove the remote build directory ({0})")]
+    Clean(String),
+    /// A local environment variable that should be forwarded isn't set.
+    #[error("The environment variable '{0}' should be forwarded, but isn't set locally")]
+    ForwardEnv(String),
+    /// A `${NAME}` placeholder in the config value [`key`] can't be expanded.
+    #[error("Can't expand the placeholders of '{key}': {reason}")]
+    Placeholder { key: &'static str, reason: String },
+    /// `--use-direnv` is set, but direnv can't be used on the build server.
+    #[error("Can't use direnv on the build server ({0})")]
+    Direnv(String),
+    /// `--strict-flags` is set and this many cargo options conflict with cargo-remote options.
+    #[error(
+        "{0} cargo option(s) conflict with cargo-remote options (see the warnings above, \
+         --strict-flags turns them into errors)"
+    )]
+    FlagConflicts(usize),
+    /// The rustup toolchain isn't installed on the build server and couldn't be installed.
+    #[error("The toolchain isn't usable on the build server ({0})")]
+    Toolchain(String),
+    /// A local program, e.g. ssh or rsync, isn't installed.
+    #[error("'{0}' was not found, is it installed and in PATH?")]
+    NotInstalled(String),
+    /// ssh couldn't connect to the build server.
+    #[error(
+        "Can't connect to the build server ({0}), check that it is up and that `ssh` to it works"
+    )]
+    Unreachable(String),
+}
+
+impl Error {
+    /// The code the process exits with when this error ends the run. A failing remote command
+    /// isn't an error, the run exits with its exit code instead.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NoRemote => 3,
+            Error::Sync(_) => 4,
+            Error::Build(_) => 5,
+            Error::CopyBack(_) => 6,
+            Error::CopyLock(_) => 7,
+            Error::Project(_) => 8,
+            Error::RelativePath(_) => 9,
+            Error::SyncAborted => 10,
+            Error::Timeout { .. } => 11,
+            Error::Init(_) => 12,
+            Error::Clean(_) => 13,
+            Error::ForwardEnv(_) => 14,
+            Error::Placeholder { .. } => 15,
+            Error::Direnv(_) => 16,
+            Error::FlagConflicts(_) => 17,
+            Error::Toolchain(_) => 18,
+            Error::NotInstalled(_) => 19,
+            Error::Unreachable(_) => 20,
+            Error::Interrupted { .. } => 130,
+        }
+    }
+}
+
+/// Spawns [`cmd`]. Unlike [`Command::spawn`] the error names the program that couldn't be
+/// started, see [`spawn_error`].
+pub fn spawn(cmd: &mut Command, on_error: fn(String) -> Error) -> Result<Child, Error> {
+    cmd.spawn().map_err(|e| {
+        audit::record(cmd, None);
+        spawn_error(cmd, e, on_error)
+    })
+}
+
+/// Describes why the program of [`cmd`] couldn't be run: [`Error::NotInstalled`] if it wasn't
+/// found, the error made by [`on_error`] otherwise.
+pub fn spawn_error(cmd: &Command, error: io::Error, on_error: fn(String) -> Error) -> Error {
+    let program = cmd.get_program().to_string_lossy();
+    if error.kind() == io::ErrorKind::NotFound {
+        Error::NotInstalled(program.into_owned())
+    } else {
+        on_error(format!("can't run '{}': {}", program, error))
+    }
+}
+
+/// Describes the failed remote command [`what`] that exited with [`status`], with [`on_error`]
+/// unless ssh couldn't connect.
+pub fn remote_error(what: &str, status: ExitStatus, on_error: fn(String) -> Error) -> Error {
+    let message = format!("{} exited with {}", what, status);
+    if status.code() == Some(255) {
+        Error::Unreachable(message)
+    } else {
+        on_error(message)
+    }
+}
+
+/// Like [`remote_error`] for rsync, with a hint if the build server has no rsync.
+pub fn rsync_error(status: ExitStatus, on_error: fn(String) -> Error) -> Error {
+    match status.code() {
+        // rsync passes on the exit code of ssh failing to connect
+        Some(255) => Error::Unreachable(format!("rsync exited with {}", status)),
+        // the remote shell doesn't find rsync, older versions only see the protocol break
+        Some(12) | Some(127) => on_error(format!(
+            "rsync exited with {}, is rsync installed on the build server?",
+            status
+        )),
+        _ => on_error(format!("rsync exited with {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn exited(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exited(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    #[test]
+    fn exit_codes() {
+        let text = || "reason".to_owned();
+        let errors = [
+            (Error::NoRemote, 3),
+            (Error::Sync(text()), 4),
+            (Error::Build(text()), 5),
+            (Error::CopyBack(text()), 6),
+            (Error::CopyLock(text()), 7),
+            (Error::Project(text()), 8),
+            (Error::RelativePath(text()), 9),
+            (Error::SyncAborted, 10),
+            (
+                Error::Timeout {
+                    phase: "build",
+                    after: Duration::from_secs(60),
+                },
+                11,
+            ),
+            (Error::Init(text()), 12),
+            (Error::Clean(text()), 13),
+            (Error::ForwardEnv(text()), 14),
+            (
+                Error::Placeholder {
+                    key: "remote",
+                    reason: text(),
+                },
+                15,
+            ),
+            (Error::Direnv(text()), 16),
+            (Error::FlagConflicts(1), 17),
+            (Error::Toolchain(text()), 18),
+            (Error::NotInstalled(text()), 19),
+            (Error::Unreachable(text()), 20),
+            (Error::Interrupted { phase: "sync" }, 130),
+        ];
+        for (error, code) in &errors {
+            assert_eq!(error.exit_code(), *code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn ssh_failing_to_connect_is_unreachable() {
+        let error = remote_error("ssh", exited(255), Error::Build);
+        assert!(matches!(error, Error::Unreachable(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 20);
+        let error = rsync_error(exited(255), Error::Sync);
+        assert!(matches!(error, Error::Unreachable(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 20);
+    }
+
+    #[test]
+    fn other_failures_keep_their_cause() {
+        let error = remote_error("ssh", exited(1), Error::Build);
+        assert!(matches!(error, Error::Build(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 5);
+        let error = rsync_error(exited(23), Error::CopyBack);
+        assert!(matches!(error, Error::CopyBack(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 6);
+        for code in [12, 127] {
+            match rsync_error(exited(code), Error::Sync) {
+                Error::Sync(message) => {
+                    assert!(message.contains("is rsync installed"), "{}", message)
+                }
+                error => panic!("{:?}", error),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_programs_are_not_installed() {
+        let mut cmd = Command::new("cargo-remote-no-such-program");
+        let error = spawn(&mut cmd, Error::Build).unwrap_err();
+        assert!(matches!(&error, Error::NotInstalled(p) if p == "cargo-remote-no-such-program"));
+        assert_eq!(error.exit_code(), 19);
+    }
+}
@@ -0,0 +1,136 @@
+//! Running the same command on several build servers at once, e.g. `-r x86 -r arm` or
+//! `--all-remotes`. Every build server gets its own cargo-remote process with the same arguments,
+//! whose output is prefixed with the alias of the build server.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::Ordering;
+
+use log::warn;
+
+use crate::audit;
+use crate::error::{spawn, Error};
+use crate::INTERRUPTED;
+
+/// Tells a cargo-remote process started by [`run`] the alias of its build server.
+const ALIAS_ENV: &str = "CARGO_REMOTE_FAN_OUT_ALIAS";
+/// Tells a cargo-remote process started by [`run`] its build server.
+const SERVER_ENV: &str = "CARGO_REMOTE_FAN_OUT_SERVER";
+
+/// A build server of a fan-out and the name its output is prefixed with.
+pub struct Member {
+    pub alias: String,
+    pub server: String,
+}
+
+/// The build server this process was started for by [`run`], if it was.
+pub fn current() -> Option<Member> {
+    Some(Member {
+        alias: std::env::var(ALIAS_ENV).ok()?,
+        server: std::env::var(SERVER_ENV).ok()?,
+    })
+}
+
+/// Runs cargo-remote with the arguments of this process for every one of [`members`] at once and
+/// prints a summary once all finished. Returns the exit code of the first member that failed.
+pub fn run(members: &[Member]) -> Result<i32, Error> {
+    let program = std::env::current_exe()
+        .map_err(|e| Error::Build(format!("can't find the cargo-remote executable: {}", e)))?;
+    let width = members.iter().map(|m| m.alias.len()).max().unwrap_or(0);
+
+    let mut running = Vec::new();
+    for member in members {
+        let mut cmd = Command::new(&program);
+        cmd.args(std::env::args_os().skip(1))
+            .env(ALIAS_ENV, &member.alias)
+            .env(SERVER_ENV, &member.server)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let prefix = format!("[{:>width$}] ", member.alias, width = width);
+        match spawn(&mut cmd, Error::Build) {
+            Ok(mut child) => {
+                let forwards = vec![
+                    child
+                        .stdout
+                        .take()
+                        .map(|out| forward(out, prefix.clone(), false)),
+                    child
+                        .stderr
+                        .take()
+                        .map(|err| forward(err, prefix.clone(), true)),
+                ];
+                running.push((member, cmd, Some(child), forwards));
+            }
+            Err(e) => {
+                warn!("Failed to start the build on {}: {}", member.alias, e);
+                running.push((member, cmd, None, Vec::new()));
+            }
+        }
+    }
+
+    let mut results = Vec::new();
+    for (member, cmd, child, forwards) in running {
+        let status = child.map(|mut child| child.wait());
+        if let Some(status) = &status {
+            audit::record(&cmd, status.as_ref().ok());
+        }
+        for forward in forwards.into_iter().flatten() {
+            let _ = forward.join();
+        }
+        results.push((member, status));
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(Error::Interrupted { phase: "fan-out" });
+    }
+
+    eprintln!("Summary:");
+    let mut exit_code = 0;
+    for (member, status) in results {
+        let (outcome, code) = match status {
+            Some(Ok(status)) if status.success() => ("ok".to_owned(), 0),
+            Some(Ok(status)) => (format!("failed ({})", status), status.code().unwrap_or(1)),
+            Some(Err(e)) => (format!("failed ({})", e), 1),
+            None => ("not started".to_owned(), 1),
+        };
+        eprintln!("  {:<width$}  {}", member.alias, outcome, width = width);
+        if exit_code == 0 {
+            exit_code = code;
+        }
+    }
+    Ok(exit_code)
+}
+
+/// Prints the lines of [`output`] with [`prefix`] to stdout, or to stderr with [`to_stderr`].
+fn forward(
+    output: impl Read + Send + 'static,
+    prefix: String,
+    to_stderr: bool,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        let mut line = Vec::new();
+        while output.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+            let mut out: Box<dyn Write> = if to_stderr {
+                Box::new(std::io::stderr().lock())
+            } else {
+                Box::new(std::io::stdout().lock())
+            };
+            let written = out
+                .write_all(prefix.as_bytes())
+                .and_then(|_| out.write_all(&line))
+                .and_then(|_| {
+                    if line.ends_with(b"\n") {
+                        Ok(())
+                    } else {
+                        out.write_all(b"\n")
+                    }
+                })
+                .and_then(|_| out.flush());
+            if written.is_err() {
+                break;
+            }
+            line.clear();
+        }
+    })
+}
@@ -0,0 +1,175 @@
+//! Phase timings and transfer sizes of past runs, kept per project in the data directory.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::app_dirs;
+use crate::units::{format_duration, format_elapsed, format_size};
+
+/// Only this many runs are kept per project.
+const MAX_RUNS: usize = 50;
+
+/// What happened during one run.
+#[derive(Clone)]
+pub struct Run {
+    /// Unix time the run finished at.
+    pub finished_at: u64,
+    pub command: String,
+    pub success: bool,
+    pub sync: Duration,
+    pub build: Duration,
+    pub copy_back: Duration,
+    /// Bytes sent to the build server.
+    pub synced: u64,
+    /// Bytes received from the build server.
+    pub fetched: u64,
+}
+
+impl Run {
+    fn to_json(&self) -> Value {
+        json!({
+            "finished_at": self.finished_at,
+            "command": self.command,
+            "success": self.success,
+            "sync_ms": self.sync.as_millis() as u64,
+            "build_ms": self.build.as_millis() as u64,
+            "copy_back_ms": self.copy_back.as_millis() as u64,
+            "synced": self.synced,
+            "fetched": self.fetched,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Run> {
+        let millis = |key| value.get(key)?.as_u64().map(Duration::from_millis);
+        Some(Run {
+            finished_at: value.get("finished_at")?.as_u64()?,
+            command: value.get("command")?.as_str()?.to_owned(),
+            success: value.get("success")?.as_bool()?,
+            sync: millis("sync_ms")?,
+            build: millis("build_ms")?,
+            copy_back: millis("copy_back_ms")?,
+            synced: value.get("synced")?.as_u64()?,
+            fetched: value.get("fetched")?.as_u64()?,
+        })
+    }
+}
+
+fn history_file(project_hash: u64) -> Option<PathBuf> {
+    app_dirs::place_data_file(&format!("history/{}.json", project_hash))
+}
+
+/// Loads the recorded runs of the project, oldest first.
+pub fn load(project_hash: u64) -> Vec<Run> {
+    history_file(project_hash)
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|history| serde_json::from_str::<Value>(&history).ok())
+        .and_then(|history| {
+            history
+                .as_array()
+                .map(|runs| runs.iter().filter_map(Run::from_json).collect())
+        })
+        .unwrap_or_default()
+}
+
+/// Appends [`run`] to the history of the project, dropping the oldest runs beyond [`MAX_RUNS`].
+pub fn record(project_hash: u64, run: &Run) {
+    let mut runs = load(project_hash);
+    let path = match history_file(project_hash) {
+        Some(path) => path,
+        None => {
+            warn!("Can't determine the data directory, the run isn't recorded");
+            return;
+        }
+    };
+    runs.push(run.clone());
+    let skip = runs.len().saturating_sub(MAX_RUNS);
+    let history = Value::Array(runs.iter().skip(skip).map(Run::to_json).collect());
+    if let Err(e) = std::fs::write(&path, history.to_string()) {
+        warn!(
+            "Failed to record the run in '{}' (error: {})",
+            path.to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Formats the `--stats` line of the phases that finished, e.g.
+/// `sync 4.2s (13.0 MiB) | build 2m31s | copy-back 8.1s (112.0 MiB)`.
+pub fn phase_summary(
+    sync: Option<(Duration, u64)>,
+    build: Option<Duration>,
+    copy_back: Option<(Duration, u64)>,
+) -> String {
+    let sync = sync
+        .map(|(time, bytes)| format!("sync {} ({})", format_phase_time(time), format_size(bytes)));
+    let build = build.map(|time| format!("build {}", format_phase_time(time)));
+    let copy_back = copy_back.map(|(time, bytes)| {
+        format!(
+            "copy-back {} ({})",
+            format_phase_time(time),
+            format_size(bytes)
+        )
+    });
+    [sync, build, copy_back]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Like [`format_duration`], but with tenths of a second for short phases.
+fn format_phase_time(time: Duration) -> String {
+    if time.as_secs() < 60 {
+        format!("{:.1}s", time.as_secs_f64())
+    } else {
+        format_duration(time)
+    }
+}
+
+/// Formats the summary printed at the end of a run, comparing the build time with [`previous`].
+/// The second value tells whether the build got slower by more than [`threshold`] percent.
+pub fn summary(run: &Run, previous: Option<&Run>, threshold: u64) -> (String, bool) {
+    let mut line = format!("build: {}", format_duration(run.build));
+    let mut regression = false;
+    if let Some(previous) = previous {
+        let (now, before) = (run.build.as_secs(), previous.build.as_secs());
+        let diff = if now >= before {
+            format!("+{}", format_duration(Duration::from_secs(now - before)))
+        } else {
+            format!("-{}", format_duration(Duration::from_secs(before - now)))
+        };
+        line += &format!(" ({} vs last run)", diff);
+        regression = before > 0 && now.saturating_sub(before) * 100 > before * threshold;
+    }
+    line += &format!(
+        ", synced {}, fetched {}",
+        format_size(run.synced),
+        format_size(run.fetched)
+    );
+    (line, regression)
+}
+
+/// Prints [`runs`] as a table, newest first.
+pub fn print_table(runs: &[Run]) {
+    println!(
+        "{:<10} {:<10} {:<7} {:>8} {:>8} {:>10} {:>11} {:>11}",
+        "finished", "command", "result", "sync", "build", "copy-back", "synced", "fetched"
+    );
+    for run in runs.iter().rev() {
+        println!(
+            "{:<10} {:<10} {:<7} {:>8} {:>8} {:>10} {:>11} {:>11}",
+            format_elapsed(run.finished_at),
+            run.command,
+            if run.success { "ok" } else { "failed" },
+            format_duration(run.sync),
+            format_duration(run.build),
+            format_duration(run.copy_back),
+            format_size(run.synced),
+            format_size(run.fetched)
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! The host triples of the local builds and of the build server. Artifacts copied back from a
+//! Linux build server into a `target/` that also holds builds of e.g. macOS confuse cargo's
+//! fingerprints, which shows as spurious rebuilds and "invalid metadata" errors.
+
+use std::path::Path;
+
+use serde_json::Value;
+
+/// The file cargo caches the output of `rustc -vV` in, in the target directory.
+const RUSTC_INFO: &str = ".rustc_info.json";
+
+/// The host triple in the output of `rustc -vV`, e.g. `x86_64-unknown-linux-gnu`.
+pub fn from_rustc_version(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(str::trim)
+}
+
+/// The host triple of the rustc that built in the local [`target_dir`], [`None`] if nothing was
+/// built there or cargo's cache can't be read.
+pub fn of_builds_in(target_dir: &Path) -> Option<String> {
+    let info = std::fs::read_to_string(target_dir.join(RUSTC_INFO)).ok()?;
+    let info = serde_json::from_str::<Value>(&info).ok()?;
+    info.get("outputs")?
+        .as_object()?
+        .values()
+        .filter_map(|output| output.get("stdout")?.as_str())
+        .find_map(from_rustc_version)
+        .map(str::to_owned)
+}
@@ -0,0 +1,84 @@
+//! Warns about copied-back artifacts that git would pick up, so they aren't committed by accident.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::warn;
+
+use crate::audit;
+
+/// Returns the [`paths`] that the ignore rules of the git repository around [`project_dir`] don't
+/// match, tracked files included. Returns [`None`] if it isn't a git repository or git fails.
+/// All paths are checked with a single git invocation.
+fn unignored(project_dir: &Path, paths: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C")
+        .arg(project_dir)
+        .args(["check-ignore", "--stdin", "-z"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    let mut git = cmd.spawn().ok()?;
+    let mut input = Vec::new();
+    for path in paths {
+        input.extend_from_slice(path.to_string_lossy().as_bytes());
+        input.push(0);
+    }
+    git.stdin.take()?.write_all(&input).ok()?;
+    let output = git.wait_with_output().ok()?;
+    audit::record(&cmd, Some(&output.status));
+    // 1 means that none of the paths is ignored, 128 that this isn't a repository
+    if !matches!(output.status.code(), Some(0) | Some(1)) {
+        return None;
+    }
+    let ignored = output
+        .stdout
+        .split(|b| *b == 0)
+        .map(|p| PathBuf::from(String::from_utf8_lossy(p).into_owned()))
+        .collect::<BTreeSet<_>>();
+    Some(
+        paths
+            .iter()
+            .filter(|p| !ignored.contains(*p))
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Warns about the copied-back [`paths`] below [`project_dir`] that git doesn't ignore, and
+/// suggests patterns for the `.gitignore` of the project.
+pub fn check(project_dir: &Path, paths: &[PathBuf]) {
+    let existing = paths
+        .iter()
+        .filter(|p| p.exists())
+        .cloned()
+        .collect::<Vec<_>>();
+    if existing.is_empty() {
+        return;
+    }
+    let unignored = match unignored(project_dir, &existing) {
+        Some(unignored) if !unignored.is_empty() => unignored,
+        _ => return,
+    };
+    let patterns = unignored
+        .iter()
+        .filter_map(
+            |p| match p.strip_prefix(project_dir).ok()?.components().next()? {
+                Component::Normal(first) => Some(format!("/{}/", first.to_string_lossy())),
+                _ => None,
+            },
+        )
+        .collect::<BTreeSet<_>>();
+    warn!(
+        "These copied-back artifacts aren't ignored by git and may be committed by accident:\n  {}\n\
+         Consider adding {} to the .gitignore of the project (or pass --no-hygiene-check)",
+        unignored
+            .iter()
+            .map(|p| p.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n  "),
+        patterns.into_iter().collect::<Vec<_>>().join(", ")
+    );
+}
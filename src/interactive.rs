@@ -0,0 +1,51 @@
+//! Whether cargo-remote talks to a person at a terminal, or runs from a script, a cron job or a CI
+//! step with redirected or closed stdio. Questions, TTY allocation, progress and colors all ask
+//! here, so they agree with each other and with `--non-interactive`.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+
+/// Set by `--non-interactive`, which makes every check below answer no.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Behaves as if no stdio stream was a terminal from now on.
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether questions can be answered on stdin.
+pub fn stdin() -> bool {
+    !DISABLED.load(Ordering::SeqCst) && std::io::stdin().is_terminal()
+}
+
+/// Whether stdout is a terminal.
+pub fn stdout() -> bool {
+    !DISABLED.load(Ordering::SeqCst) && std::io::stdout().is_terminal()
+}
+
+/// Whether stderr is a terminal.
+pub fn stderr() -> bool {
+    !DISABLED.load(Ordering::SeqCst) && std::io::stderr().is_terminal()
+}
+
+/// Whether stdin, stdout and stderr are all terminals, as a TTY on the build server needs.
+pub fn all() -> bool {
+    stdin() && stdout() && stderr()
+}
+
+/// Asks the user a yes/no question on the terminal, defaulting to no. Without a terminal on stdin
+/// the question isn't asked and the answer is no.
+pub fn confirm(question: &str) -> bool {
+    if !stdin() {
+        info!("{} No (not asked, stdin isn't a terminal)", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,78 @@
+//! Running without a local cargo, e.g. from a bastion host that has ssh and rsync but no Rust
+//! toolchain. `cargo metadata` can't run there, so the workspace root is found by reading the
+//! manifests instead and everything that needs the packages of the workspace is left out.
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::Metadata;
+use serde_json::json;
+
+use crate::config::config_from_file;
+use crate::error::Error;
+
+/// Whether `cargo metadata` can run: `$CARGO` if it's set, else a `cargo` on the `PATH`.
+pub fn available() -> bool {
+    if let Some(cargo) = std::env::var_os("CARGO") {
+        return is_executable(Path::new(&cargo));
+    }
+    std::env::var_os("PATH").is_some_and(|path| {
+        let cargo = format!("cargo{}", std::env::consts::EXE_SUFFIX);
+        std::env::split_paths(&path).any(|dir| is_executable(&dir.join(&cargo)))
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Windows has no executable bit, the name of the file decides.
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// The workspace root of the package at [`manifest_path`]: the closest directory above it whose
+/// Cargo.toml has a `[workspace]` table, else the outermost directory with a Cargo.toml.
+pub fn workspace_root(manifest_path: &Path) -> PathBuf {
+    let dir = manifest_path.parent().unwrap_or_else(|| Path::new("."));
+    let manifests = dir
+        .ancestors()
+        .map(|dir| (dir, dir.join("Cargo.toml")))
+        .filter(|(_, manifest)| manifest.is_file());
+    let mut outermost = dir;
+    for (dir, manifest) in manifests {
+        let is_workspace = config_from_file(&manifest, true)
+            .is_some_and(|manifest| manifest.get("workspace").is_some());
+        if is_workspace {
+            return dir.to_owned();
+        }
+        outermost = dir;
+    }
+    outermost.to_owned()
+}
+
+/// Metadata of the workspace of [`manifest_path`] with the workspace root and the target
+/// directory only, no packages.
+pub fn metadata(manifest_path: &Path) -> Result<Metadata, Error> {
+    let workspace_root = workspace_root(manifest_path);
+    // a cargo config that moves the target directory can't be resolved without cargo
+    let target_directory = match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => std::env::current_dir()
+            .map(|cwd| cwd.join(dir))
+            .map_err(|e| Error::Project(format!("can't obtain the current path: {}", e)))?,
+        None => workspace_root.join("target"),
+    };
+    serde_json::from_value(json!({
+        "packages": [],
+        "workspace_members": [],
+        "resolve": null,
+        "workspace_root": workspace_root,
+        "target_directory": target_directory,
+        "version": 1,
+    }))
+    .map_err(|e| Error::Project(format!("can't describe the workspace: {}", e)))
+}
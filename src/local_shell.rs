@@ -0,0 +1,169 @@
+//! How rsync and ssh are run on this machine and how they name local paths. On Linux and macOS
+//! that's plain, but on Windows rsync is a Cygwin or MSYS2 program, or only exists inside WSL,
+//! and none of them understands `C:\Users\me\proj`.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Where the local rsync and ssh come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LocalShell {
+    /// Run directly. On Windows, drives are named like Cygwin and cwRsync do: `/cygdrive/c/`.
+    Native,
+    /// Run directly, with drives named like MSYS2 and Git for Windows do: `/c/`.
+    Msys,
+    /// Run inside WSL through `wsl.exe`, which mounts drives at `/mnt/c/`.
+    Wsl,
+}
+
+impl LocalShell {
+    /// The directory the drive letter is appended to, to name a drive.
+    fn drive_prefix(self) -> &'static str {
+        match self {
+            LocalShell::Native => "/cygdrive/",
+            LocalShell::Msys => "/",
+            LocalShell::Wsl => "/mnt/",
+        }
+    }
+}
+
+impl FromStr for LocalShell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(LocalShell::Native),
+            "msys" => Ok(LocalShell::Msys),
+            "wsl" => Ok(LocalShell::Wsl),
+            _ => Err(format!(
+                "unknown local shell '{}', expected native, msys or wsl",
+                s
+            )),
+        }
+    }
+}
+
+/// Set once from `--local-shell`.
+static SHELL: OnceLock<LocalShell> = OnceLock::new();
+
+/// Runs rsync and ssh through [`shell`] from now on.
+pub fn set(shell: LocalShell) {
+    let _ = SHELL.set(shell);
+}
+
+fn get() -> LocalShell {
+    SHELL.get().copied().unwrap_or(LocalShell::Native)
+}
+
+/// Whether rsync and ssh run inside WSL.
+pub fn is_wsl() -> bool {
+    get() == LocalShell::Wsl
+}
+
+/// A command running the local [`program`], e.g. rsync or ssh.
+pub fn command(program: impl AsRef<OsStr>) -> Command {
+    if is_wsl() {
+        let mut wsl = Command::new("wsl.exe");
+        wsl.arg("--exec").arg(program);
+        wsl
+    } else {
+        Command::new(program)
+    }
+}
+
+/// Names the local [`path`] for rsync. Only Windows paths are translated, elsewhere a backslash
+/// is a valid part of a file name.
+pub fn path(path: &Path) -> String {
+    if cfg!(windows) {
+        translate(&path.to_string_lossy(), get())
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// Names the local directory [`dir`] for rsync, with a trailing slash so its content is
+/// transferred instead of the directory itself.
+pub fn dir(dir: &Path) -> String {
+    let mut dir = path(dir);
+    if !dir.ends_with('/') {
+        dir.push('/');
+    }
+    dir
+}
+
+/// Joins the components of the relative [`path`] with slashes, as the build server and rsync
+/// filters expect them.
+pub fn slashes(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Translates the Windows [`path`] into the form [`shell`] expects, e.g. `C:\Users\me\proj` into
+/// `/mnt/c/Users/me/proj` for WSL. The `\\?\` prefix of canonicalized paths is dropped, and paths
+/// without a drive only get their separators replaced. The path is taken apart as text, so the
+/// translation is the same on every host.
+fn translate(path: &str, shell: LocalShell) -> String {
+    let path = path.replace('\\', "/");
+    let path = match path.strip_prefix("//?/") {
+        // network shares keep their Windows name, which Cygwin and MSYS2 understand
+        Some(verbatim) => match verbatim.strip_prefix("UNC/") {
+            Some(share) => format!("//{}", share),
+            None => verbatim.to_owned(),
+        },
+        None => path,
+    };
+    match path.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => format!(
+            "{}{}/{}",
+            shell.drive_prefix(),
+            char::from(*drive).to_ascii_lowercase(),
+            path[2..].trim_start_matches('/')
+        ),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drives() {
+        let path = r"C:\Users\me\proj";
+        assert_eq!(translate(path, LocalShell::Wsl), "/mnt/c/Users/me/proj");
+        assert_eq!(
+            translate(path, LocalShell::Native),
+            "/cygdrive/c/Users/me/proj"
+        );
+        assert_eq!(translate(path, LocalShell::Msys), "/c/Users/me/proj");
+        assert_eq!(translate(r"D:\", LocalShell::Wsl), "/mnt/d/");
+        assert_eq!(translate("e:/data", LocalShell::Msys), "/e/data");
+    }
+
+    #[test]
+    fn canonicalized_paths() {
+        assert_eq!(
+            translate(r"\\?\C:\Users\me\proj", LocalShell::Wsl),
+            "/mnt/c/Users/me/proj"
+        );
+        assert_eq!(
+            translate(r"\\?\UNC\server\share\proj", LocalShell::Native),
+            "//server/share/proj"
+        );
+    }
+
+    #[test]
+    fn paths_without_a_drive() {
+        assert_eq!(
+            translate(r"\\server\share\proj", LocalShell::Msys),
+            "//server/share/proj"
+        );
+        assert_eq!(translate(r"src\main.rs", LocalShell::Wsl), "src/main.rs");
+        assert_eq!(translate("/home/me/proj", LocalShell::Wsl), "/home/me/proj");
+    }
+}
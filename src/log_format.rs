@@ -0,0 +1,163 @@
+//! `--log-format json`: one JSON object per log event on stderr, for scripts that wrap
+//! cargo-remote and would otherwise parse the human-oriented log lines. Every event names the
+//! phase of the run it happened in, and the run ends with an `exit` event.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use simple_logger::SimpleLogger;
+
+/// How log events are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `INFO [cargo_remote] ...` lines, for people.
+    Text,
+    /// One JSON object per line, for tools.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+/// The part of a run a log event belongs to. The names are stable, tools match on them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum LogPhase {
+    /// Reading the config and the workspace, preparing the build server.
+    Setup,
+    /// Transferring the sources to the build server.
+    Upload,
+    /// Running the cargo commands.
+    Build,
+    /// Transferring artifacts and files back.
+    CopyBack,
+}
+
+impl LogPhase {
+    fn name(self) -> &'static str {
+        match self {
+            LogPhase::Setup => "setup",
+            LogPhase::Upload => "upload",
+            LogPhase::Build => "build",
+            LogPhase::CopyBack => "copy_back",
+        }
+    }
+
+    fn from_u8(value: u8) -> LogPhase {
+        match value {
+            1 => LogPhase::Upload,
+            2 => LogPhase::Build,
+            3 => LogPhase::CopyBack,
+            _ => LogPhase::Setup,
+        }
+    }
+}
+
+/// Set once by [`init`].
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// The [`LogPhase`] the run is in.
+static PHASE: AtomicU8 = AtomicU8::new(LogPhase::Setup as u8);
+
+static JSON_LOGGER: JsonLogger = JsonLogger;
+
+/// Writes the log events of [`level`] and above in [`format`] from now on.
+pub fn init(format: LogFormat, level: LevelFilter) {
+    let _ = FORMAT.set(format);
+    match format {
+        LogFormat::Text => SimpleLogger::new().with_level(level).init().unwrap(),
+        LogFormat::Json => {
+            log::set_logger(&JSON_LOGGER).unwrap();
+            log::set_max_level(level);
+        }
+    }
+}
+
+/// Whether the log events are written as JSON.
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&LogFormat::Json)
+}
+
+/// Marks the log events from now on as part of [`phase`].
+pub fn enter(phase: LogPhase) {
+    PHASE.store(phase as u8, Ordering::Relaxed);
+}
+
+/// Logs the lifecycle [`event`] of the run, e.g. `sync_finished`, as an info [`message`]. The JSON
+/// event names it in an `event` field and carries the [`fields`] as well, e.g. the `duration_ms`
+/// and the transferred `bytes`, so tools don't have to parse the message.
+pub fn milestone(event: &str, message: &str, fields: Value) {
+    if !is_json() {
+        info!("{}", message);
+        return;
+    }
+    if Level::Info > log::max_level() {
+        return;
+    }
+    let mut object = json!({
+        "level": "info",
+        "phase": current().name(),
+        "message": message,
+        "event": event,
+    });
+    if let (Some(object), Value::Object(fields)) = (object.as_object_mut(), fields) {
+        object.extend(fields);
+    }
+    eprintln!("{}", object);
+}
+
+fn current() -> LogPhase {
+    LogPhase::from_u8(PHASE.load(Ordering::Relaxed))
+}
+
+/// Writes the terminal `exit` event with the exit [`code`] of cargo-remote, whatever the level.
+/// Nothing is written with the text format, the exit code speaks for itself there.
+pub fn exit(code: i32) {
+    if is_json() {
+        eprintln!(
+            "{}",
+            json!({
+                "level": "info",
+                "phase": "exit",
+                "message": format!("exit code {}", code),
+                "exit_code": code,
+            })
+        );
+    }
+}
+
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        eprintln!(
+            "{}",
+            json!({
+                "level": record.level().as_str().to_ascii_lowercase(),
+                "phase": current().name(),
+                "message": record.args().to_string(),
+            })
+        );
+    }
+
+    fn flush(&self) {}
+}
@@ -1,74 +1,281 @@
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
 use std::hash::{Hash, Hasher};
-use std::path::{Path, PathBuf};
-use std::process::{exit, Command, Stdio};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{exit, Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 use toml::Value;
 
+use bug_report::{BugReport, OutputTail};
+use config::{
+    config_from_file, config_from_package_metadata, config_from_workspace_metadata, Isolation,
+    RemoteConfig, UnusedKeys,
+};
+use config_origin::{Origin, Origins};
+use error::{remote_error, rsync_error, spawn, spawn_error, Error};
+use history::Run;
+use log_format::{LogFormat, LogPhase};
+use retry::RemoteOutput;
+use serde_json::json;
+use stale_lock::{remove_stale_lock, LockWatch, Recovery, LAST_PID_SUFFIX};
+use sync_back::{Installed, SyncBackFile};
+use sync_state::{list_files, SyncState, STATE_FILE};
+use transport::{local_build_dir, Transport};
+use units::{format_duration, format_elapsed, format_size};
+use webhook::WebhookOn;
+
 use log::{error, info, warn, LevelFilter};
-use simple_logger::SimpleLogger;
-use std::ffi::OsString;
-use std::str::FromStr;
+
+mod advisory_db;
+mod app_dirs;
+mod audit;
+mod bug_report;
+mod build_script_logs;
+mod coalesce;
+mod config;
+mod config_origin;
+mod conflicts;
+mod docs;
+mod error;
+mod fan_out;
+mod history;
+mod host_triple;
+mod hygiene;
+mod interactive;
+mod local_cargo;
+mod local_shell;
+mod log_format;
+mod package_scope;
+mod placeholders;
+mod process_group;
+mod remote_dirs;
+mod retry;
+mod skip_unchanged;
+mod space_monitor;
+mod stale_lock;
+mod sync_back;
+mod sync_root;
+mod sync_state;
+mod target_gc;
+mod toolchain;
+mod transport;
+mod units;
+mod webhook;
+mod which;
 
 const PROGRESS_FLAG: &str = "--info=progress2";
 
+/// How long an idle ControlMaster connection is kept open, in case it isn't closed at the end.
+const CONTROL_PERSIST: &str = "60s";
+
+/// How long an idle connection through the `--ssh-control-path` socket is kept open for the next
+/// runs.
+const SHARED_CONTROL_PERSIST: &str = "10m";
+
+/// Cargo commands (and their aliases) that may create or modify the Cargo.lock file.
+const LOCK_MODIFYING_COMMANDS: &[&str] = &[
+    "build",
+    "b",
+    "check",
+    "c",
+    "test",
+    "t",
+    "run",
+    "r",
+    "bench",
+    "clippy",
+    "doc",
+    "d",
+    "update",
+    "generate-lockfile",
+    "fetch",
+];
+
+/// Cargo commands that don't leave any artifacts in `target/` that could be copied back.
+const NO_ARTIFACT_COMMANDS: &[&str] = &[
+    "clean",
+    "fmt",
+    "fetch",
+    "update",
+    "generate-lockfile",
+    "tree",
+    "metadata",
+    "locate-project",
+    "pkgid",
+    "verify-project",
+    "search",
+    "version",
+    "help",
+    "audit",
+    "deny",
+];
+
+/// Cargo commands that take the package to work on with `-p`.
+const PACKAGE_COMMANDS: &[&str] = &[
+    "build", "b", "check", "c", "test", "t", "run", "r", "bench", "clippy", "doc", "d", "rustc",
+    "rustdoc", "fix", "clean", "fmt", "tree",
+];
+
+/// File in the remote build directory that records which local checkout synced it last.
+const ORIGIN_MARKER: &str = ".cargo-remote-origin";
+
+/// Where rsync keeps the part of an interrupted transfer to resume it, next to the files.
+const PARTIAL_DIR: &str = ".cargo-remote-partial";
+
+/// Version of the scheme the build directory names are derived with, recorded in the
+/// [`ORIGIN_MARKER`]. Directories of older schemes are found by [`legacy_project_dirs`].
+const BUILD_PATH_SCHEME: i64 = 3;
+
+/// Up to this many changed files are uploaded by name instead of letting rsync compare the trees.
+const INCREMENTAL_SYNC_MAX_FILES: usize = 100;
+
+/// File in the remote build directory that holds the pid of the running remote command.
+const PID_FILE: &str = ".cargo-remote-pid";
+
+/// Subdirectory of the local target directory that `--copy-back-isolate` transfers artifacts into.
+const ISOLATED_TARGET: &str = "remote";
+
+/// Shell commands that run on the build server before and after the cargo command.
+struct Hooks {
+    pre: Vec<String>,
+    post: Vec<String>,
+}
+
+/// A cargo command and its options that run on the build server.
+#[derive(Debug)]
+struct Step {
+    command: String,
+    options: Vec<String>,
+}
+
+impl Step {
+    /// The command line as shown in logs, e.g. `clippy -- -D warnings`.
+    fn description(&self) -> String {
+        std::iter::once(&self.command)
+            .chain(&self.options)
+            .map(|word| shell_quote(word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Splits an `--and-then` value into the cargo command and its options, with shell quoting rules.
+fn parse_step(input: &str) -> Result<Step, String> {
+    let mut words =
+        shell_words::split(input).map_err(|e| format!("can't split '{}': {}", input, e))?;
+    if words.is_empty() {
+        return Err("the command is empty".to_owned());
+    }
+    let command = words.remove(0);
+    Ok(Step {
+        command,
+        options: words,
+    })
+}
+
+// parsed only once, so the size difference between the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cargo-remote", bin_name = "cargo")]
 enum Opts {
-    #[structopt(name = "remote")]
+    // the options of the cargo command are taken as they are, see parse_args
+    #[structopt(
+        name = "remote",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
     Remote {
-        #[structopt(short = "r", long = "remote", help = "Remote ssh build server")]
-        remote: Option<String>,
+        #[structopt(flatten)]
+        config: RemoteConfig,
 
         #[structopt(
-            short = "b",
-            long = "build-env",
-            help = "Set remote environment variables. RUST_BACKTRACE, CC, LIB, etc. ",
-            default_value = "RUST_BACKTRACE=1"
+            long = "dry-run",
+            help = "Print the commands that would be executed instead of running them"
         )]
-        build_env: String,
+        dry_run: bool,
 
         #[structopt(
-            short = "d",
-            long = "rustup-default",
-            help = "Rustup default (stable|beta|nightly)",
-            default_value = "stable"
+            long = "bug-report",
+            help = "Run the command and bundle the configuration, tool versions, commands and build output into cargo-remote-report-<timestamp>.zip"
         )]
-        rustup_default: String,
+        bug_report: bool,
 
         #[structopt(
-            short = "e",
-            long = "env",
-            help = "Environment profile. default_value = source ~/.cargo/env",
-            default_value = "~/.cargo/env"
+            long = "force",
+            help = "Sync even if the remote directory was last synced from a different checkout without asking (not synced if non-interactive)"
         )]
-        env: String,
+        force: bool,
 
         #[structopt(
-            short = "c",
-            long = "copy-back",
-            help = "Transfer the target folder or specific file from that folder back to the local machine"
+            long = "no-sync",
+            help = "Don't transfer the sources, run the command on what was synced before"
         )]
-        copy_back: Option<Option<String>>,
+        no_sync: bool,
 
         #[structopt(
-            long = "no-copy-lock",
-            help = "don't transfer the Cargo.lock file back to the local machine"
+            long = "clean",
+            help = "Remove the remote build directory before syncing, so the build starts from scratch"
         )]
-        no_copy_lock: bool,
+        clean: bool,
 
         #[structopt(
-            short = "h",
-            long = "transfer-hidden",
-            help = "Transfer hidden files and directories to the build server"
+            long = "clean-only",
+            help = "Remove the remote build directory and exit without building"
         )]
-        hidden: bool,
+        clean_only: bool,
+
+        #[structopt(
+            long = "fresh",
+            help = "Remove the remote target directory before building, like a remote cargo clean"
+        )]
+        fresh: bool,
 
         #[structopt(long = "debug", help = "Show all the info logs")]
         debug: bool,
 
-        #[structopt(help = "cargo command that will be executed remotely")]
-        command: String,
+        #[structopt(
+            short = "q",
+            long = "quiet",
+            help = "Only show errors, no rsync progress and no summary at the end, implied by cargo's -q unless --debug is given"
+        )]
+        quiet: bool,
+
+        #[structopt(
+            long = "log-format",
+            help = "Write the logs as text or as one JSON object per event with its level, message and phase (json shows the info logs as well)",
+            default_value = "text"
+        )]
+        log_format: LogFormat,
+
+        #[structopt(
+            long = "progress",
+            help = "Show rsync's progress even if the output isn't a terminal or --quiet is given"
+        )]
+        progress: bool,
+
+        #[structopt(
+            long = "all-remotes",
+            help = "Run the command on every build server of the [remotes] config table at once"
+        )]
+        all_remotes: bool,
+
+        #[structopt(
+            long = "and-then",
+            help = "Run this cargo command with its options (e.g. \"clippy -- -D warnings\") in the same session if the previous ones succeeded (repeatable)",
+            raw(number_of_values = "1"),
+            parse(try_from_str = "parse_step")
+        )]
+        and_then: Vec<Step>,
+
+        #[structopt(
+            help = "cargo command that will be executed remotely",
+            raw(required_unless = r#""clean_only""#)
+        )]
+        command: Option<String>,
 
         #[structopt(
             help = "cargo options and flags that will be applied remotely",
@@ -76,252 +283,5150 @@ enum Opts {
         )]
         options: Vec<String>,
     },
+
+    #[structopt(
+        name = "init",
+        about = "Write a commented .cargo-remote.toml into the workspace root",
+        usage = "cargo remote init [FLAGS] [OPTIONS]"
+    )]
+    Init {
+        #[structopt(short = "r", long = "remote", help = "Remote ssh build server")]
+        remote: Option<String>,
+
+        #[structopt(long = "force", help = "Overwrite an existing config file")]
+        force: bool,
+    },
+
+    #[structopt(
+        name = "history",
+        about = "Show the timings and transfer sizes of the last runs of this project",
+        usage = "cargo remote history"
+    )]
+    History,
+
+    #[structopt(
+        name = "clean-remote",
+        about = "List the remote build directories of all checkouts at this path and delete them",
+        usage = "cargo remote clean-remote [FLAGS] [OPTIONS]"
+    )]
+    CleanRemote {
+        #[structopt(flatten)]
+        config: RemoteConfig,
+
+        #[structopt(
+            short = "y",
+            long = "yes",
+            help = "Delete without asking (nothing is deleted if non-interactive)"
+        )]
+        yes: bool,
+
+        #[structopt(long = "debug", help = "Show all the info logs")]
+        debug: bool,
+    },
+
+    #[structopt(
+        name = "which",
+        about = "Print where a local path ends up on the build server and whether it is synced",
+        usage = "cargo remote which [FLAGS] [OPTIONS] [path]"
+    )]
+    Which {
+        #[structopt(flatten)]
+        config: RemoteConfig,
+
+        #[structopt(
+            long = "exists",
+            help = "Also check whether the remote path exists, which connects to the build server"
+        )]
+        exists: bool,
+
+        #[structopt(long = "debug", help = "Show all the info logs")]
+        debug: bool,
+
+        #[structopt(
+            help = "Local path, relative to the current directory [default: the project root]",
+            parse(from_os_str)
+        )]
+        path: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "config",
+        about = "Print the build server, environment, toolchain and build directory a run would use and where each comes from",
+        usage = "cargo remote config [FLAGS] [OPTIONS]"
+    )]
+    Config {
+        #[structopt(flatten)]
+        config: RemoteConfig,
+
+        #[structopt(long = "debug", help = "Show all the info logs")]
+        debug: bool,
+    },
+
+    #[structopt(
+        name = "audit-log",
+        about = "Print the commands the last run with --audit recorded in the audit log",
+        usage = "cargo remote audit-log [OPTIONS]"
+    )]
+    AuditLog {
+        #[structopt(flatten)]
+        config: RemoteConfig,
+
+        #[structopt(long = "debug", help = "Show all the info logs")]
+        debug: bool,
+    },
 }
 
-/// Tries to parse the file [`config_path`]. Logs warnings and returns [`None`] if errors occur
-/// during reading or parsing, [`Some(Value)`] otherwise.
-fn config_from_file(config_path: &Path, silence: bool) -> Option<Value> {
-    let config_file = std::fs::read_to_string(config_path)
-        .map_err(|e| {
-            if !silence {
-                warn!(
-                    "Can't parse config file '{}' (error: {})",
-                    config_path.to_string_lossy(),
-                    e
-                );
-            }
+/// Template for `cargo remote init`, `{remote}` is replaced by the `remote` line.
+const CONFIG_TEMPLATE: &str = r#"# cargo-remote configuration, see `cargo remote --help` for all options.
+# Options given on the command line take precedence over this file, which takes
+# precedence over the global ~/.config/cargo-remote/cargo-remote.toml.
+
+# Remote ssh build server, or a list of them to build on all at once. ${NAME} placeholders are
+# expanded from the local environment
+{remote}
+
+# Remote environment variables set for the cargo command
+# build_env = ["RUST_BACKTRACE=1"]
+
+# Local environment variables passed to the cargo command, patterns like "CARGO_*" are allowed
+# forward_env = []
+
+# Shell commands run in the build directory before and after the cargo command
+# pre_hooks = []
+# post_hooks = []
+
+# Toolchain to build with, run as cargo +<toolchain> without changing the remote default.
+# Defaults to the toolchain of the project's rust-toolchain file, else stable.
+# rustup_default = "stable"
+
+# Install the rustup_default or rust-toolchain file toolchain on the build server if it's missing
+# install_toolchain = false
+
+# Build inside a container of this image on the build server, which pins the toolchain
+# docker_image = "rust:1.75"
+
+# Target triple to build for, and whether to `rustup target add` it first
+# target = "aarch64-unknown-linux-musl"
+# rustup_target_add = true
+
+# Workspace package to sync with its path dependencies and build with -p
+# package = "my-crate"
+
+# An ancestor of the workspace root to sync from, so path dependencies outside of the workspace
+# (e.g. path = "../shared-protos") are transferred with the same layout, relative to the root
+# sync_parent = ".."
+
+# Build servers for specific target triples, used unless --remote is given
+# [targets]
+# aarch64-unknown-linux-musl = "arm-builder"
+
+# Build servers that can be named by alias with --remote, --all-remotes runs on all of them at once
+# [remotes]
+# x86 = "builds@x86-builder"
+# arm = "builds@arm-builder"
+
+# Environment profiles sourced before building
+# env = ["~/.cargo/env"]
+
+# Remote directory the project directories are created in
+# build_path = "~/remote-builds"
+
+# Use build_path itself as project directory instead of a hashed subdirectory
+# build_path_no_hash = false
+
+# Target directory of the remote build, relative to the project directory or absolute
+# remote_target_dir = "target"
+
+# Which checkouts share a remote build directory (none|host|branch)
+# isolate = "host"
+
+# Give every toolchain its own build directory, which costs remote disk space but keeps the
+# incremental state of each when switching between them
+# isolate_toolchain = false
+
+# Move build directories of older cargo-remote versions without asking
+# migrate_remote_dir = false
+
+# Transfer the target folder (true) or specific paths or glob patterns below it back
+# copy_back = false
+
+# Transfer the Cargo.lock file back after commands that may modify it
+# copy_lock = true
+
+# Transfer these files back if they changed, replacing the default of just Cargo.lock, e.g. the
+# workspace-hack crate regenerated by cargo hakari generate
+# sync_back_files = ["Cargo.lock", "workspace-hack/Cargo.toml"]
+
+# Transfer artifacts back even if the remote command failed
+# copy_back_on_failure = false
+
+# Transfer the workspace binaries of the selected profile back
+# copy_back_bins = false
+
+# Transfer artifacts back into target/remote/, e.g. if the local builds are for another host
+# copy_back_isolate = false
+
+# The crate whose documentation `doc --open` transfers back and opens, instead of all of them
+# doc_crate = "my_crate"
+
+# Remote files collected into target/remote-failure-<timestamp>/ if the remote command
+# failed, relative to the build directory or absolute (e.g. "core*", "/tmp/myapp-*.log")
+# collect_on_failure = []
+
+# Transfer the output of the build scripts a failed build names into target/remote-build-logs/
+# build_script_logs = true
+
+# Remote paths or glob patterns relative to the build directory that are transferred back to the
+# same place in the project after a successful build (e.g. "tarpaulin-report.html")
+# fetch = []
+
+# Warn about copied-back artifacts that git doesn't ignore
+# hygiene_check = true
+
+# Transfer hidden files and directories to the build server
+# hidden = false
+
+# Transfer hidden files except these rsync patterns, .git is never transferred this way
+# hidden_except = [".direnv/"]
+
+# Keep excluding hidden files but transfer these rsync patterns
+# hidden_only = [".sqlx/"]
+
+# Transfer .cargo/config.toml even though hidden files aren't transferred
+# transfer_cargo_config = false
+
+# Copy ~/.cargo/credentials.toml to the build server without asking
+# transfer_credentials = false
+
+# Share the advisory databases of cargo-audit and cargo-deny with the build server
+# sync_advisory_db = false
+
+# Run cargo through `direnv exec` with the .envrc of the project, allowing it without asking
+# use_direnv = false
+
+# Additional rsync patterns that are never transferred
+# excludes = []
+
+# Don't transfer files ignored by .gitignore files
+# respect_gitignore = false
+
+# Delete remote files that don't exist locally when syncing, turning it off may leave stale
+# files that make builds differ from local ones
+# delete = true
+
+# Don't start rsync if no source file changed since the last successful sync to the build server
+# skip_unchanged = false
+
+# Skip the remote user's profile and rc files
+# remote_clean_shell = false
+
+# The shell the remote command runs in as a login shell, whatever the account's default shell is
+# remote_shell = "bash"
+
+# Transfer files matching these rsync patterns even if they are hidden or excluded
+# includes = [".sqlx/"]
+
+# Extra arguments for ssh, one argument per entry (e.g. "-p2222")
+# ssh_options = []
+
+# Bastion host(s) to connect through, comma separated (ssh -J)
+# jump_host = "user@bastion"
+
+# The ssh and local rsync programs, e.g. a newer rsync than the one first on PATH
+# ssh_bin = "ssh"
+# rsync_bin = "rsync"
+
+# On Windows, whether rsync and ssh are Cygwin (native), MSYS2 (msys) or WSL (wsl) programs
+# local_shell = "native"
+
+# Share a single ssh connection between the transfers and the build
+# control_master = true
+
+# Share the ssh connection through this socket, kept open after the run for the next ones
+# ssh_control_path = "~/.ssh/cm-%r@%h"
+
+# Retry the upload and the remote command this many times if the connection failed
+# retries = 0
+
+# Check that the build server is reachable and has rsync and cargo before syncing
+# probe = true
+
+# Give up connecting to the build server after this long
+# connect_timeout = "10s"
+
+# Limit the bandwidth of all transfers, in KiB/s or like "2MB", and compress them
+# bwlimit = 500
+# compress = true
+
+# Resume interrupted transfers from a .cargo-remote-partial directory instead of starting over
+# partial = true
+
+# Always allocate a TTY on the build server (merges the remote stdout and stderr)
+# force_tty = false
+
+# Never ask questions, allocate a TTY or show progress, as if no terminal was attached
+# non_interactive = false
+
+# Let the remote command write into a local pseudo terminal when its output is rewritten
+# pty_bridge = true
+
+# Remove cargo's file locks left behind by a crashed run once the build waited this long for them
+# stale_lock_recovery = true
+# stale_lock_grace = "30s"
+
+# Let a burst of runs of the same command, e.g. from an editor, wait this many milliseconds and
+# run only once
+# coalesce = 500
+
+# Warn when the free space of the remote filesystem drops below space_floor during the build
+# space_monitor = true
+# space_floor = "1GiB"
+# space_monitor_interval = "10s"
+
+# Keep the remote target directory below this size by removing the oldest incremental
+# compilation sessions after a successful build
+# max_remote_target_size = "20G"
+
+# Warn if a build takes this many percent longer than the last run of the same command
+# regression_threshold = 25
+
+# Print the duration and transfer size of every phase at the end of a run
+# stats = false
+
+# Fail instead of warning if cargo options conflict with cargo-remote options
+# strict_flags = false
+
+# Warn about config keys that are unknown or overridden by the command line or another config
+# config_warnings = true
+
+# Append every command run locally and on the build server to an audit log (JSON lines),
+# by default audit.jsonl in the cargo-remote data directory
+# audit = false
+# audit_log = "/var/log/cargo-remote/audit.jsonl"
+
+# POST a JSON summary of every run (success, failure or always) to a URL with the local curl,
+# with extra headers whose values may use ${NAME} placeholders from the environment
+# webhook = "https://ci.example.com/hooks/cargo-remote"
+# webhook_on = "always"
+# [webhook_headers]
+# Authorization = "Bearer ${BUILD_HOOK_TOKEN}"
+
+# Per-phase timeouts like "90s", "30m" or "1h30m"
+# sync_timeout = "10m"
+# build_timeout = "1h"
+# copy_back_timeout = "10m"
+"#;
+
+/// Parses the command line [`args`]. `cargo remote init` and `cargo remote history` run
+/// [`Opts::Init`] and [`Opts::History`] instead of a cargo command on the build server.
+fn parse_args(mut args: Vec<OsString>) -> Opts {
+    let subcommands = [
+        "init",
+        "history",
+        "clean-remote",
+        "which",
+        "config",
+        "audit-log",
+    ];
+    if args.len() > 2 && args[1] == "remote" && subcommands.iter().any(|s| args[2] == *s) {
+        args.remove(1);
+    }
+    if let Some(command) = command_position(&args) {
+        if is_copy_back(&args[command - 1]) {
+            args[command - 1] = "--copy-back=".into();
+        }
+        // everything after the cargo command is the command's, even `--` and words that look
+        // like options of cargo-remote
+        args.insert(command + 1, "--".into());
+    }
+    mark_bare_copy_back(&mut args);
+    Opts::from_iter(args.iter())
+}
+
+/// The position of the cargo command in [`args`] of `cargo remote`: the first word clap doesn't
+/// take as the value of an option of cargo-remote. A command after `--` is left to clap. If no
+/// word is left for the command, a `-c` right before one is bare, e.g. in `cargo remote -c clean`.
+fn command_position(args: &[OsString]) -> Option<usize> {
+    if args.get(1).is_none_or(|arg| arg != "remote") {
+        return None;
+    }
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let candidates = || (2..end).filter(|&i| !args[i].to_string_lossy().starts_with('-'));
+    candidates()
+        .find(|&i| has_command(args[..=i].to_vec()))
+        .or_else(|| {
+            candidates().find(|&i| {
+                let mut prefix = args[..=i].to_vec();
+                is_copy_back(&prefix[i - 1]) && {
+                    prefix[i - 1] = "--copy-back=".into();
+                    has_command(prefix)
+                }
+            })
         })
-        .ok()?;
+}
 
-    let value = config_file
-        .parse::<Value>()
-        .map_err(|e| {
-            if !silence {
-                warn!(
-                    "Can't parse config file '{}' (error: {})",
-                    config_path.to_string_lossy(),
-                    e
-                );
-            }
+/// Whether clap finds the cargo command in [`args`], the words up to the value of an option lack
+/// it.
+fn has_command(mut args: Vec<OsString>) -> bool {
+    mark_bare_copy_back(&mut args);
+    Opts::clap()
+        .get_matches_from_safe(&args)
+        .ok()
+        .and_then(|matches| {
+            matches
+                .subcommand_matches("remote")
+                .and_then(|remote| remote.value_of_os("command").map(|_| ()))
         })
-        .ok()?;
+        .is_some()
+}
 
-    Some(value)
+fn is_copy_back(arg: &OsString) -> bool {
+    arg == "-c" || arg == "--copy-back"
 }
 
-fn main() {
-    let Opts::Remote {
-        remote,
-        build_env,
-        rustup_default,
-        env,
-        copy_back,
-        no_copy_lock,
-        hidden,
-        debug,
-        command,
-        options,
-    } = Opts::from_args();
+/// A `-c` without a path copies back the whole target folder. As `--copy-back` is repeatable, it
+/// always takes a value, so a bare one is turned into an empty path before parsing.
+fn mark_bare_copy_back(args: &mut [OsString]) {
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    for i in 0..end {
+        let bare = is_copy_back(&args[i])
+            && args[..end]
+                .get(i + 1)
+                .is_none_or(|next| next.to_string_lossy().starts_with('-'));
+        if bare {
+            args[i] = "--copy-back=".into();
+        }
+    }
+}
 
-    if !debug {
-        SimpleLogger::new()
-            .with_level(LevelFilter::Warn)
-            .init()
-            .unwrap();
+fn init_logger(debug: bool, quiet: bool, format: LogFormat) {
+    log_format::init(format, log_level(debug, quiet, format));
+}
+
+/// The level cargo-remote logs at. `--debug` wins over `--quiet`.
+fn log_level(debug: bool, quiet: bool, format: LogFormat) -> LevelFilter {
+    if debug {
+        LevelFilter::Trace
+    } else if quiet {
+        LevelFilter::Error
+    } else if format == LogFormat::Json {
+        // the milestones are what a tool follows the run by
+        LevelFilter::Info
     } else {
-        SimpleLogger::new().init().unwrap();
+        LevelFilter::Warn
     }
+}
 
-    let current_path = std::env::current_dir().unwrap_or_else(|e| {
-        error!("Failed to obtain the current path (error: {})", e);
-        exit(-8);
-    });
+/// Whether the run is quiet: with `--quiet`, or if a step asks cargo to be quiet, unless `--debug`
+/// asked for the logs. Asking cargo to be verbose doesn't change cargo-remote.
+fn run_is_quiet(quiet: bool, debug: bool, steps: &[Step]) -> bool {
+    quiet || (!debug && steps.iter().any(|step| is_quiet(&step.options)))
+}
 
-    let mut cargo_file_path = current_path.clone().into_os_string().into_string().unwrap();
-    loop {
-        let path = format!("{}/Cargo.toml", cargo_file_path);
-        if Path::new(path.as_str()).exists() {
-            break;
-        } else {
-            let new_path = Path::new(cargo_file_path.as_str())
-                .parent()
-                .unwrap_or_else(|| {
-                    error!("Failed to find the Cargo.toml file");
-                    exit(-8);
-                })
-                .to_path_buf()
-                .into_os_string()
-                .into_string()
-                .unwrap();
-            cargo_file_path = new_path.clone();
-        }
-    }
+/// Searches [`current_path`] and its parents for the `Cargo.toml` of the current package.
+fn find_manifest(current_path: &Path) -> Result<PathBuf, Error> {
+    current_path
+        .ancestors()
+        .map(|dir| dir.join("Cargo.toml"))
+        .find(|manifest| manifest.exists())
+        .ok_or_else(|| Error::Project("no Cargo.toml found".to_owned()))
+}
+
+fn current_dir() -> Result<PathBuf, Error> {
+    std::env::current_dir()
+        .map_err(|e| Error::Project(format!("can't obtain the current path: {}", e)))
+}
 
+/// Runs `cargo metadata` for the workspace of [`manifest_path`]. Without a [`local_cargo`], the
+/// workspace is described from its manifests instead, without packages.
+fn load_metadata(
+    manifest_path: &Path,
+    local_cargo: bool,
+) -> Result<cargo_metadata::Metadata, Error> {
+    if !local_cargo {
+        return local_cargo::metadata(manifest_path);
+    }
     let mut metadata_cmd = cargo_metadata::MetadataCommand::new();
+    metadata_cmd.manifest_path(manifest_path).no_deps();
     metadata_cmd
-        .manifest_path(format!("{}/Cargo.toml", cargo_file_path))
-        .no_deps();
-
-    let project_metadata = metadata_cmd.exec().unwrap();
-    let project_dir = project_metadata.workspace_root;
-
-    let configs = vec![
-        config_from_file(&project_dir.join(".cargo-remote.toml"), true),
-        xdg::BaseDirectories::with_prefix("cargo-remote")
-            .ok()
-            .and_then(|base| base.find_config_file("cargo-remote.toml"))
-            .and_then(|p: PathBuf| config_from_file(&p, false)),
-    ];
+        .exec()
+        .map_err(|e| Error::Project(format!("cargo metadata failed: {}", e)))
+}
 
-    info!("Project dir: {:?}", project_dir);
+/// Writes a commented `.cargo-remote.toml` with all supported keys into the workspace root.
+fn init_config(remote: Option<String>, force: bool) -> Result<(), Error> {
+    let manifest_path = find_manifest(&current_dir()?)?;
+    let config_path = load_metadata(&manifest_path, local_cargo::available())?
+        .workspace_root
+        .join(".cargo-remote.toml");
 
-    // TODO: move Opts::Remote fields into own type and implement complete_from_config(&mut self, config: &Value)
-    let build_server = remote
-        .or_else(|| {
-            configs
-                .into_iter()
-                .flat_map(|config| config.and_then(|c| c["remote"].as_str().map(String::from)))
-                .next()
-        })
-        .unwrap_or_else(|| {
-            error!("No remote build server was defined (use config file or --remote flag)");
-            exit(-3);
-        });
+    if config_path.exists() && !force {
+        return Err(Error::Init(format!(
+            "'{}' already exists (use --force to overwrite it)",
+            config_path.to_string_lossy()
+        )));
+    }
 
-    // generate a unique build path by using the hashed project dir as folder on the remote machine
-    let mut hasher = DefaultHasher::new();
-    project_dir.hash(&mut hasher);
-    let build_path = format!("~/remote-builds/{}/", hasher.finish());
+    let remote_line = match remote {
+        Some(remote) => format!("remote = {}", Value::String(remote)),
+        None => "# remote = \"user@build-server\"".to_owned(),
+    };
+    std::fs::write(
+        &config_path,
+        CONFIG_TEMPLATE.replace("{remote}", &remote_line),
+    )
+    .map_err(|e| {
+        Error::Init(format!(
+            "Failed to write '{}' (error: {})",
+            config_path.to_string_lossy(),
+            e
+        ))
+    })?;
+    println!("Wrote {}", config_path.to_string_lossy());
+    Ok(())
+}
 
-    info!("Transferring sources to build server.");
-    // transfer project to build server
-    let mut rsync_to = Command::new("rsync");
-    rsync_to
-        .arg("-a".to_owned())
-        .arg("--delete")
-        .arg("--compress")
-        .arg("--info=progress2")
-        .arg("--exclude")
-        .arg("target");
+/// Prints the recorded runs of the current project.
+fn show_history() -> Result<(), Error> {
+    let manifest_path = find_manifest(&current_dir()?)?;
+    let project_dir = load_metadata(&manifest_path, local_cargo::available())?.workspace_root;
+    let runs = history::load(project_hash(&project_dir));
+    if runs.is_empty() {
+        println!("No runs recorded for {}", project_dir.to_string_lossy());
+    } else {
+        history::print_table(&runs);
+    }
+    Ok(())
+}
 
-    if !hidden {
-        rsync_to.arg("--exclude").arg(".*");
+/// Returns [`path`] relative to [`base`], `.` if both are the same directory. Both are
+/// canonicalized first, so symlinks don't keep them from being compared. Like
+/// `realpath --relative-to`, which isn't available on macOS or Windows, but always separated by
+/// slashes, as the path is used on the build server.
+fn relative_path(base: &Path, path: &Path) -> Result<String, Error> {
+    let canonicalize = |p: &Path| {
+        p.canonicalize()
+            .map_err(|e| Error::RelativePath(format!("{}: {}", p.to_string_lossy(), e)))
+    };
+    let (base, path) = (canonicalize(base)?, canonicalize(path)?);
+    let common = base
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let relative: PathBuf = base
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(path.components().skip(common))
+        .collect();
+    match relative.to_str() {
+        Some("") => Ok(".".to_owned()),
+        Some(_) => Ok(local_shell::slashes(&relative)),
+        None => Err(Error::RelativePath(format!(
+            "'{}' isn't valid UTF-8",
+            relative.to_string_lossy()
+        ))),
     }
+}
 
-    rsync_to
-        .arg("--rsync-path")
-        .arg("mkdir -p remote-builds && rsync")
-        .arg(format!("{}/", project_dir.to_string_lossy()))
-        .arg(format!("{}:{}", build_server, build_path))
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to transfer project to build server (error: {})", e);
-            exit(-4);
-        });
+/// Returns the directory [`dir`] relative to the workspace root [`project_dir`], where the remote
+/// command runs. A directory that isn't inside the root even after canonicalizing, e.g. reached
+/// through a bind mount, falls back to the root with a warning instead of a path escaping the
+/// remote build directory.
+fn project_relative_dir(project_dir: &Path, dir: &Path) -> Result<String, Error> {
+    let relative = relative_path(project_dir, dir)?;
+    if relative == ".." || relative.starts_with("../") {
+        warn!(
+            "{} isn't inside the workspace root {}, running the remote command in the root",
+            dir.to_string_lossy(),
+            project_dir.to_string_lossy()
+        );
+        return Ok(".".to_owned());
+    }
+    Ok(relative)
+}
 
-    let mut get_relative_path = Command::new("realpath");
+/// Quotes a single word so that it reaches the remote command verbatim.
+fn shell_quote(word: &str) -> Cow<'_, str> {
+    shell_escape::unix::escape(Cow::Borrowed(word))
+}
 
-    let current_relative_path = String::from_utf8(
-        get_relative_path
-            .arg(format!("--relative-to={}", project_dir.to_string_lossy()))
-            .arg(current_path.into_os_string())
-            .output()
-            .unwrap_or_else(|e| {
-                error!("Failed to compute the relative path (error: {})", e);
-                exit(-9);
-            })
-            .stdout,
-    )
-    .unwrap_or_else(|e| {
-        error!("Failed to compute the relative path (error: {})", e);
-        exit(-9);
+/// Renders [`cmd`] as a command line a shell runs as is, for `--dry-run` and bug reports.
+fn command_line(cmd: &Command) -> String {
+    let assignments = cmd.get_envs().filter_map(|(name, value)| {
+        Some(format!(
+            "{}={}",
+            name.to_string_lossy(),
+            shell_quote(&value?.to_string_lossy())
+        ))
     });
+    let argv = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()).into_owned());
+    assignments.chain(argv).collect::<Vec<_>>().join(" ")
+}
 
-    info!("Build ENV: {:?}", build_env);
-    info!("Environment profile: {:?}", env);
-    info!("Build path: {:?}", build_path);
-    info!("Sub directory: {:?}", current_relative_path.trim());
-    let build_command = format!(
-        "source {}; rustup default {}; cd {}; cd {}; {} cargo {} {}",
-        env,
-        rustup_default,
-        build_path,
-        current_relative_path.trim(),
-        build_env,
-        command,
-        options.join(" ")
-    );
-
-    info!("Starting build process.");
-    let output = Command::new("ssh")
-        .arg("-t")
-        .arg(&build_server)
-        .arg(build_command)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()
-        .unwrap_or_else(|e| {
-            error!("Failed to run cargo command remotely (error: {})", e);
-            exit(-5);
-        });
-
-    if let Some(file_name) = copy_back {
-        info!("Transferring artifacts back to client.");
-        let file_name = file_name.unwrap_or_else(String::new);
-        Command::new("rsync")
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("--info=progress2")
-            .arg(format!(
-                "{}:{}/target/{}",
-                build_server, build_path, file_name
-            ))
-            .arg(format!(
-                "{}/target/{}",
-                project_dir.to_string_lossy(),
-                file_name
-            ))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer target back to local machine (error: {})",
-                    e
-                );
-                exit(-6);
-            });
+/// Quotes a remote path, leaving a leading `~/` unquoted so the remote shell still expands it to
+/// the home directory of the build user.
+fn shell_quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some("") => "~/".to_owned(),
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(path).into_owned(),
     }
+}
 
-    if !no_copy_lock {
-        info!("Transferring Cargo.lock file back to client.");
-        Command::new("rsync")
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("--info=progress2")
-            .arg(format!("{}:{}/Cargo.lock", build_server, build_path))
-            .arg(format!("{}/Cargo.lock", project_dir.to_string_lossy()))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .stdin(Stdio::inherit())
-            .output()
-            .unwrap_or_else(|e| {
-                error!(
-                    "Failed to transfer Cargo.lock back to local machine (error: {})",
-                    e
-                );
-                exit(-7);
-            });
+/// Quotes the value of a `KEY=VALUE` environment assignment. Values without a variable name are
+/// quoted as a whole.
+fn shell_quote_env(assignment: &str) -> String {
+    match assignment.find('=') {
+        Some(idx) if idx > 0 => format!(
+            "{}={}",
+            &assignment[..idx],
+            shell_quote(&assignment[idx + 1..])
+        ),
+        _ => shell_quote(assignment).into_owned(),
     }
+}
 
-    if !output.status.success() {
-        exit(output.status.code().unwrap_or(1))
+/// Assembles the shell command that is executed on the build server. Every component is quoted
+/// so that spaces, quotes, `$` or `;` in options, paths and env values are not interpreted by the
+/// remote shell. Several [`steps`] run one after the other until the first one fails, which
+/// names the failed step on stderr and exits with its code. The [`hooks`] run in the same
+/// directory, a failing hook aborts the command as well. With [`direnv`] the `.envrc` of the
+/// build directory is allowed and cargo runs in its environment. A [`target_dir`] is exported as
+/// `CARGO_TARGET_DIR`. Without a [`toolchain`], rustup picks the one of a toolchain file or the
+/// remote default.
+#[allow(clippy::too_many_arguments)]
+fn build_remote_command(
+    env: &[String],
+    toolchain: Option<&str>,
+    rustup_target: Option<&str>,
+    build_path: &str,
+    relative_path: &str,
+    target_dir: Option<&str>,
+    build_env: &[String],
+    hooks: &Hooks,
+    direnv: bool,
+    steps: &[Step],
+) -> String {
+    // `.` rather than `source`, which a plain sh lacks
+    let sources: String = env
+        .iter()
+        .map(|e| format!(". {}; ", shell_quote_path(e)))
+        .collect();
+
+    let cargo_commands = steps.iter().enumerate().map(|(i, step)| {
+        let mut cargo_command: Vec<String> = build_env.iter().map(|e| shell_quote_env(e)).collect();
+        if direnv {
+            cargo_command.push(format!("direnv exec {}", shell_quote_path(build_path)));
+        }
+        cargo_command.push("cargo".to_owned());
+        if let Some(toolchain) = toolchain {
+            cargo_command.push(shell_quote(&format!("+{}", toolchain)).into_owned());
+        }
+        cargo_command.push(shell_quote(&step.command).into_owned());
+        cargo_command.extend(step.options.iter().map(|o| shell_quote(o).into_owned()));
+        let cargo_command = cargo_command.join(" ");
+        if steps.len() == 1 {
+            return cargo_command;
+        }
+        let failure = format!(
+            "cargo-remote: step {}/{} (cargo {}) failed",
+            i + 1,
+            steps.len(),
+            step.description()
+        );
+        format!(
+            "{} || {{ s=$?; echo {} >&2; exit $s; }}",
+            cargo_command,
+            shell_quote(&failure)
+        )
+    });
+    let cargo_command = cargo_commands.collect::<Vec<_>>().join("; ");
+    let cargo_command = if hooks.post.is_empty() {
+        cargo_command
+    } else {
+        // the post-hooks run in any case, the subshell keeps a failing step from exiting early
+        let post_hooks: String = hooks.post.iter().map(|h| post_hook_command(h)).collect();
+        format!(
+            "{}; CARGO_REMOTE_STATUS=$?; export CARGO_REMOTE_STATUS; {}exit $CARGO_REMOTE_STATUS",
+            if steps.len() == 1 {
+                cargo_command
+            } else {
+                format!("( {} )", cargo_command)
+            },
+            post_hooks
+        )
+    };
+    let mut pre_hooks: String = hooks.pre.iter().map(|h| pre_hook_command(h)).collect();
+    if direnv {
+        pre_hooks.insert_str(
+            0,
+            &format!("direnv allow {} || exit $?; ", shell_quote_path(build_path)),
+        );
+    }
+
+    // run in the build directory, so rustup adds the target to a toolchain pinned there
+    let target_add = rustup_target
+        .map(|target| match toolchain {
+            Some(toolchain) => format!(
+                "rustup target add --toolchain {} {}; ",
+                shell_quote(toolchain),
+                shell_quote(target)
+            ),
+            None => format!("rustup target add {}; ", shell_quote(target)),
+        })
+        .unwrap_or_default();
+
+    let export_target_dir = target_dir
+        .map(|dir| format!("export CARGO_TARGET_DIR={}; ", shell_quote_path(dir)))
+        .unwrap_or_default();
+
+    let cd_relative = if relative_path == "." {
+        String::new()
+    } else {
+        format!("cd {}; ", shell_quote(relative_path))
+    };
+
+    format!(
+        "{}{}cd {}; {}{}{}{}",
+        sources,
+        export_target_dir,
+        shell_quote_path(build_path),
+        target_add,
+        cd_relative,
+        pre_hooks,
+        cargo_command
+    )
+}
+
+/// Where the remote build directory is mounted in the container of `--docker-image`.
+const DOCKER_WORKDIR: &str = "/work/";
+/// Where a remote target directory outside the build directory is mounted in the container.
+const DOCKER_TARGET_DIR: &str = "/cargo-target";
+
+/// Wraps [`build_command`] to run in a container of [`image`], with [`build_path`] and
+/// [`outside_target`], a target directory outside of it, mounted. The container runs as the remote
+/// user, so the artifacts don't end up owned by root, and gets a TTY if [`tty`] is set.
+fn docker_command(
+    image: &str,
+    build_path: &str,
+    relative_path: &str,
+    outside_target: Option<&str>,
+    tty: bool,
+    build_command: &str,
+) -> String {
+    let mut docker = vec![
+        "docker run --rm -i".to_owned(),
+        "--user \"$(id -u):$(id -g)\"".to_owned(),
+    ];
+    if tty {
+        docker.push("-t".to_owned());
+    }
+    docker.push(format!(
+        "-v {}:{}",
+        shell_quote_path(build_path.trim_end_matches('/')),
+        DOCKER_WORKDIR.trim_end_matches('/')
+    ));
+    if let Some(target) = outside_target {
+        docker.push(format!(
+            "-v {}:{}",
+            shell_quote_path(target),
+            DOCKER_TARGET_DIR
+        ));
+    }
+    let workdir = if relative_path == "." {
+        DOCKER_WORKDIR.trim_end_matches('/').to_owned()
+    } else {
+        format!("{}{}", DOCKER_WORKDIR, relative_path)
+    };
+    docker.push(format!("-w {}", shell_quote(&workdir)));
+    docker.push(shell_quote(image).into_owned());
+    docker.push(format!("sh -c {}", shell_quote(build_command)));
+    docker.join(" ")
+}
+
+/// Maps the [`path`] of a file in the container to where it is on the build server: below
+/// [`mounted_dir`] for the directory mounted at [`DOCKER_WORKDIR`], below [`remote_target`] for a
+/// mounted target directory outside of it.
+fn docker_host_path(path: &str, mounted_dir: &str, remote_target: &str) -> String {
+    if let Some(rest) = path.strip_prefix(DOCKER_WORKDIR) {
+        format!("{}{}", mounted_dir, rest)
+    } else if let Some(rest) = path.strip_prefix(DOCKER_TARGET_DIR) {
+        format!("{}{}", remote_target, rest)
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Sources the environment profiles [`env`] like the build does, for the checks before it. These
+/// run in the login shell of the account, so only POSIX `.` is used, and a missing profile is
+/// skipped since `.` would end a POSIX shell.
+fn quiet_sources(env: &[String]) -> String {
+    env.iter()
+        .map(|e| {
+            let path = shell_quote_path(e);
+            format!("[ -f {0} ] && . {0}; ", path)
+        })
+        .collect()
+}
+
+/// How long the probe waits for the connection unless `--connect-timeout` says otherwise.
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes sure the build server can be reached and has rsync and cargo or rustup, with the
+/// environment profiles [`env`] sourced like for the build, and logs what kind of machine it is.
+/// With [`docker`] the build runs in a container, so docker is needed instead of cargo. With
+/// [`batch`] ssh fails instead of asking for a password. Returns the host triple of the remote
+/// rustc if there is one.
+fn probe_build_server(
+    transport: &Transport,
+    env: &[String],
+    docker: bool,
+    batch: bool,
+) -> Result<Option<String>, Error> {
+    let toolchain_check = if docker {
+        "command -v docker >/dev/null || exit 5; "
+    } else {
+        "command -v cargo >/dev/null || command -v rustup >/dev/null || exit 4; "
+    };
+    let script = format!(
+        "{}command -v rsync >/dev/null || exit 3; {}uname -m; rustc -vV 2>/dev/null",
+        quiet_sources(env),
+        toolchain_check
+    );
+    let timeout = format!("ConnectTimeout={}", PROBE_CONNECT_TIMEOUT.as_secs());
+    let mut flags = vec!["-o", &timeout];
+    if batch {
+        flags.extend(["-o", "BatchMode=yes"]);
+    }
+    let mut probe = transport.shell_with(&flags, &script);
+    let output = audit::output(probe.stdin(Stdio::null()).stderr(Stdio::inherit()))
+        .map_err(|e| spawn_error(&probe, e, Error::Sync))?;
+    match output.status.code() {
+        Some(3) => Err(Error::Sync(
+            "rsync isn't installed on the build server".to_owned(),
+        )),
+        Some(4) => Err(Error::Toolchain(
+            "neither cargo nor rustup was found, install rustup or pass --env pointing at your \
+             cargo env file"
+                .to_owned(),
+        )),
+        Some(5) => Err(Error::Toolchain(
+            "docker isn't installed on the build server, which --docker-image needs".to_owned(),
+        )),
+        Some(255) => Err(remote_error("ssh", output.status, Error::Sync)),
+        _ => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let mut lines = stdout.lines();
+            info!(
+                "Build server: {}, {}",
+                lines.next().unwrap_or("unknown architecture"),
+                lines.next().unwrap_or("rustc not found")
+            );
+            Ok(host_triple::from_rustc_version(&stdout).map(str::to_owned))
+        }
+    }
+}
+
+/// Makes sure direnv can be run on the build server, with the environment profiles [`env`]
+/// sourced like for the build.
+fn check_direnv(transport: &Transport, env: &[String]) -> Result<(), Error> {
+    let sources = quiet_sources(env);
+    let mut check = transport.shell(&format!("{}command -v direnv >/dev/null", sources));
+    let status = audit::status(&mut check).map_err(|e| spawn_error(&check, e, Error::Direnv))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(255) => Err(remote_error("looking for direnv", status, Error::Direnv)),
+        _ => Err(Error::Direnv(format!(
+            "looking for direnv exited with {}, is it installed and in PATH?",
+            status
+        ))),
+    }
+}
+
+/// Makes sure the rustup [`toolchain`] is installed on the build server, installing it with the
+/// options [`install_args`] if [`install`] is set and cargo isn't kept [`offline`]. The stderr of
+/// rustup is shown, so a broken remote setup is obvious.
+fn check_toolchain(
+    transport: &Transport,
+    env: &[String],
+    toolchain: &str,
+    install: bool,
+    install_args: &[String],
+    offline: Option<&str>,
+) -> Result<(), Error> {
+    let sources = quiet_sources(env);
+    let mut list = transport.shell(&format!("{}rustup toolchain list", sources));
+    let output = audit::output(list.stderr(Stdio::inherit()))
+        .map_err(|e| spawn_error(&list, e, Error::Toolchain))?;
+    if !output.status.success() {
+        return Err(remote_error(
+            "listing the toolchains",
+            output.status,
+            Error::Toolchain,
+        ));
+    }
+    let installed = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|name| toolchain_matches(toolchain, name));
+    if installed {
+        return Ok(());
+    }
+    if let Some(offline) = offline {
+        return Err(Error::Toolchain(format!(
+            "'{}' isn't installed and cargo runs with {}, which rules out installing it",
+            toolchain, offline
+        )));
+    }
+    if !install {
+        return Err(Error::Toolchain(format!(
+            "'{}' isn't installed, pass --install-toolchain to install it",
+            toolchain
+        )));
+    }
+
+    info!(
+        "Installing the toolchain {} on the build server.",
+        toolchain
+    );
+    let mut install = transport.shell(&format!(
+        "{}rustup toolchain install {} {}>&2",
+        sources,
+        install_args
+            .iter()
+            .map(|arg| format!("{} ", shell_quote(arg)))
+            .collect::<String>(),
+        shell_quote(toolchain)
+    ));
+    let status =
+        audit::status(&mut install).map_err(|e| spawn_error(&install, e, Error::Toolchain))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Toolchain(format!(
+            "installing '{}' exited with {}",
+            toolchain, status
+        )))
+    }
+}
+
+/// Whether the installed toolchain [`name`] listed by rustup is what [`toolchain`] selects. Names
+/// are listed with the host triple appended, e.g. `nightly-2024-01-01-x86_64-unknown-linux-gnu`,
+/// which `nightly` must not match.
+fn toolchain_matches(toolchain: &str, name: &str) -> bool {
+    match name.strip_prefix(toolchain) {
+        Some("") => true,
+        Some(rest) => rest
+            .strip_prefix('-')
+            .and_then(|host| host.chars().next())
+            .is_some_and(|c| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+/// Wraps the user supplied shell command [`hook`] so that it exits the remote command with its
+/// exit code if it fails. It runs in the shell of the cargo command, so it may export variables.
+/// Its stdout goes to stderr, where it can't mix with machine readable cargo output.
+fn pre_hook_command(hook: &str) -> String {
+    let failure = format!("cargo-remote: pre-hook ({}) failed", hook);
+    // the newline ends a trailing comment or `&` of the hook before the brace
+    format!(
+        "{{ {}\n}} >&2 || {{ s=$?; echo {} >&2; exit $s; }}; ",
+        hook,
+        shell_quote(&failure)
+    )
+}
+
+/// Like [`pre_hook_command`], but a failure is only reported, so that it can't mask the exit code
+/// of the cargo command. The subshell keeps an `exit` of the hook from ending the remote command.
+fn post_hook_command(hook: &str) -> String {
+    let failure = format!("cargo-remote: post-hook ({}) failed with exit code", hook);
+    format!(
+        "( {}\n) >&2 || {{ s=$?; echo {} $s >&2; }}; ",
+        hook,
+        shell_quote(&failure)
+    )
+}
+
+/// Returns `true` if the cargo options ask cargo to be quiet. Arguments after a `--` separator
+/// belong to the executed program and are ignored.
+fn is_quiet(options: &[String]) -> bool {
+    let options = options.split(|o| o == "--").next().unwrap_or(&[]);
+    options.iter().any(|o| o == "-q" || o == "--quiet")
+}
+
+/// Returns `true` if the cargo options request machine readable output on stdout, which must not
+/// be polluted by anything the remote login shell prints.
+fn is_output_sensitive(options: &[String]) -> bool {
+    option_values(options, "--message-format").any(|f| f.starts_with("json"))
+}
+
+/// Returns all values of the cargo option [`name`], given either as `--name value` or as
+/// `--name=value`. Arguments after a `--` separator belong to the executed program and are ignored.
+fn option_values<'a>(options: &'a [String], name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let options = options.split(|o| o == "--").next().unwrap_or(&[]);
+    options.iter().enumerate().filter_map(move |(i, option)| {
+        if option == name {
+            options.get(i + 1).map(String::as_str)
+        } else {
+            option.strip_prefix(name)?.strip_prefix('=')
+        }
+    })
+}
+
+/// Returns the directory below `target/` that holds the artifacts of the profile selected by the
+/// cargo options.
+fn profile_dir(options: &[String]) -> String {
+    let cargo_options = options.split(|o| o == "--").next().unwrap_or(&[]);
+    let profile = option_values(options, "--profile").last();
+    match profile {
+        Some("dev") | Some("test") => "debug".to_owned(),
+        Some("bench") => "release".to_owned(),
+        Some(profile) => profile.to_owned(),
+        None if cargo_options.iter().any(|o| o == "--release" || o == "-r") => "release".to_owned(),
+        None => "debug".to_owned(),
+    }
+}
+
+/// Moves [`path`] below `target/` into the directory cargo uses for the artifacts of the target
+/// triple [`target`], unless it already points there. The whole target folder stays as it is.
+fn target_artifact_path(target: &str, path: String) -> String {
+    if path.is_empty() || path == target || path.starts_with(&format!("{}/", target)) {
+        path
+    } else {
+        format!("{}/{}", target, path)
+    }
+}
+
+/// Wraps [`command`] so it is executed by a bash that doesn't read any profile or rc files, which
+/// keeps stray output of the user's shell init out of the command's stdout.
+fn clean_shell_command(command: &str) -> String {
+    format!("exec bash --noprofile --norc -c {}", shell_quote(command))
+}
+
+/// Wraps [`command`] to run in a login shell of [`shell`], so it's parsed by a known shell rather
+/// than the default shell of the account, which may be fish or a restricted sh.
+fn remote_shell_command(shell: &str, command: &str) -> String {
+    format!("exec {} -lc {}", shell_quote(shell), shell_quote(command))
+}
+
+/// Reads the local environment variables named by [`patterns`] as `KEY=VALUE` assignments. A
+/// pattern may contain `*` and `?` wildcards, a plain name must be set.
+fn forwarded_env(patterns: &[String]) -> Result<Vec<String>, Error> {
+    let mut assignments = Vec::new();
+    for pattern in patterns {
+        if pattern.contains(['*', '?']) {
+            let mut matches = std::env::vars()
+                .filter(|(name, _)| wildcard_match(pattern, name))
+                .collect::<Vec<_>>();
+            matches.sort();
+            if matches.is_empty() {
+                warn!("No environment variable matches '{}'", pattern);
+            }
+            assignments.extend(
+                matches
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value)),
+            );
+        } else {
+            let value =
+                std::env::var(pattern).map_err(|_| Error::ForwardEnv(pattern.to_owned()))?;
+            assignments.push(format!("{}={}", pattern, value));
+        }
+    }
+    Ok(assignments)
+}
+
+/// Matches [`text`] against [`pattern`], in which `*` stands for any sequence and `?` for any
+/// single character.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    match pattern.chars().next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(text.len()))
+                .any(|i| wildcard_match(rest, &text[i..]))
+        }
+        Some(c) => {
+            let mut chars = text.chars();
+            match chars.next() {
+                Some(t) if c == '?' || c == t => {
+                    wildcard_match(&pattern[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Returns `true` if the copy-back path contains wildcards that the remote rsync expands.
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// What cargo-remote itself keeps in the local target directory, as rsync filters relative to it.
+/// A transfer of the whole target folder with `--delete` must leave them alone, the run lock and
+/// the coalescing state are held while it runs.
+fn local_only_files() -> Vec<String> {
+    vec![
+        format!("/{}", STATE_FILE),
+        format!("/{}", coalesce::LOCK_FILE),
+        format!("/{}", coalesce::STATE_FILE),
+        format!("/{}/", build_script_logs::LOCAL_DIR),
+        "/remote-failure-*/".to_owned(),
+        format!("/{}/", ISOLATED_TARGET),
+        // the local target directories of a fan-out
+        format!("/{}-*/", ISOLATED_TARGET),
+    ]
+}
+
+/// Tells why nothing is transferred back after `cargo [command]`.
+fn skipping_copy_back(command: &str) {
+    info!(
+        "'cargo {}' doesn't produce artifacts, skipping the copy-back.",
+        command
+    );
+}
+
+/// Describes a copy-back path for the logs, an empty one stands for the whole target folder.
+fn copy_back_description(path: &str) -> String {
+    if path.is_empty() {
+        "the target folder".to_owned()
+    } else {
+        format!("target/{}", path)
+    }
+}
+
+/// Creates an rsync command with the flags shared by all transfers, including the progress,
+/// compression, bandwidth limit and partial directory in [`transfer_options`]. The summary
+/// requested by `--info=stats1` is picked up by [`Phase::run_transfer`]. The ssh options of the
+/// [`transport`] are passed via `-e`.
+fn rsync(program: &str, transport: &Transport, transfer_options: &[String]) -> Command {
+    let mut rsync = local_shell::command(program);
+    rsync
+        .arg("-a")
+        .args(transfer_options)
+        .arg("--info=stats1")
+        .args(transport.rsync_args());
+    rsync
+}
+
+/// The 64 bit FNV-1a hash of [`bytes`]. Unlike [`DefaultHasher`] it's stable across Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Hashes [`project_dir`] into the first part of the name of its remote build directory.
+fn project_hash(project_dir: &Path) -> u64 {
+    fnv1a(project_dir.to_string_lossy().as_bytes())
+}
+
+/// Names the remote build directory of [`project_dir`]. It starts with the [`project_hash`], so
+/// the directories of all checkouts at this path can be found by their prefix. With a
+/// [`toolchain`] it ends with its name, so every toolchain keeps its own target directory.
+fn build_dir_name(project_dir: &Path, isolation: Isolation, toolchain: Option<&str>) -> String {
+    let suffix = toolchain
+        .map(|toolchain| format!("-{}", placeholders::slug(toolchain)))
+        .unwrap_or_default();
+    let project_hash = project_hash(project_dir);
+    let mut key = match isolation {
+        Isolation::None => return format!("{}{}", project_hash, suffix),
+        Isolation::Host | Isolation::Branch => local_hostname(),
+    };
+    if isolation == Isolation::Branch {
+        match git_branch(project_dir) {
+            Some(branch) => {
+                key.push('\0');
+                key.push_str(&branch);
+            }
+            None => warn!("Not on a git branch, isolating the build directory by host only"),
+        }
+    }
+    format!("{}-{}{}", project_hash, fnv1a(key.as_bytes()), suffix)
+}
+
+/// The git branch checked out in [`project_dir`], [`None`] if it isn't a repository or the
+/// `HEAD` is detached.
+fn git_branch(project_dir: &Path) -> Option<String> {
+    let output = audit::output(
+        Command::new("git")
+            .arg("-C")
+            .arg(project_dir)
+            .args(["symbolic-ref", "--short", "-q", "HEAD"])
+            .stderr(Stdio::null()),
+    )
+    .ok()
+    .filter(|o| o.status.success())?;
+    let branch = String::from_utf8(output.stdout).ok()?;
+    Some(branch.trim().to_owned()).filter(|b| !b.is_empty())
+}
+
+/// Expands the `${NAME}` placeholders of the config values that name the build server, the build
+/// directory and the hooks. Names are looked up in the local environment, `GIT_BRANCH`,
+/// `GIT_BRANCH_SLUG` and `GIT_COMMIT` fall back to the git checkout in [`project_dir`].
+fn expand_placeholders(config: &mut RemoteConfig, project_dir: &Path) -> Result<(), Error> {
+    let lookup = |name: &str| {
+        std::env::var(name).ok().or_else(|| match name {
+            "GIT_BRANCH" => git_branch(project_dir),
+            "GIT_BRANCH_SLUG" => git_branch(project_dir).map(|b| placeholders::slug(&b)),
+            "GIT_COMMIT" => git_commit(project_dir),
+            _ => None,
+        })
+    };
+    let expand = |key: &'static str, value: &mut String| {
+        *value = placeholders::expand(value, lookup)
+            .map_err(|reason| Error::Placeholder { key, reason })?;
+        Ok(())
+    };
+    for remote in &mut config.remote {
+        expand("remote", remote)?;
+    }
+    if let Some(build_path) = &mut config.build_path {
+        expand("build_path", build_path)?;
+    }
+    for hook in &mut config.pre_hooks {
+        expand("pre_hooks", hook)?;
+    }
+    for hook in &mut config.post_hooks {
+        expand("post_hooks", hook)?;
+    }
+    Ok(())
+}
+
+/// The abbreviated hash of the commit checked out in [`project_dir`].
+fn git_commit(project_dir: &Path) -> Option<String> {
+    let output = audit::output(
+        Command::new("git")
+            .arg("-C")
+            .arg(project_dir)
+            .args(["rev-parse", "--short", "HEAD"])
+            .stderr(Stdio::null()),
+    )
+    .ok()
+    .filter(|o| o.status.success())?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+    Some(commit.trim().to_owned()).filter(|c| !c.is_empty())
+}
+
+/// Returns the build directories older versions of cargo-remote used for [`project_dir`], newest
+/// scheme first.
+fn legacy_project_dirs(build_base: &str, project_dir: &Path, isolation: Isolation) -> Vec<String> {
+    let mut dirs = Vec::new();
+    // scheme 2: the project hash alone, as still used without isolation
+    if isolation != Isolation::None {
+        dirs.push(format!("{}/{}/", build_base, project_hash(project_dir)));
+    }
+    // scheme 1: DefaultHasher, whose output may change with every Rust release
+    let mut hasher = DefaultHasher::new();
+    project_dir.hash(&mut hasher);
+    dirs.push(format!("{}/{}/", build_base, hasher.finish()));
+    dirs
+}
+
+/// Picks the legacy directory to migrate to [`target`] from the remote directories that exist.
+/// Nothing is migrated if [`target`] exists already.
+fn migration_source<'a>(
+    existing: &[String],
+    target: &str,
+    legacy: &'a [String],
+) -> Option<&'a str> {
+    if existing.iter().any(|d| d == target) {
+        return None;
+    }
+    legacy
+        .iter()
+        .find(|d| existing.contains(d))
+        .map(String::as_str)
+}
+
+/// Lists which of [`dirs`] exist on the build server.
+fn existing_remote_dirs(transport: &Transport, dirs: &[String]) -> Vec<String> {
+    let checks = dirs
+        .iter()
+        .map(|d| format!("[ -d {} ] && echo {};", shell_quote_path(d), shell_quote(d)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    audit::output(
+        transport
+            .shell(&format!("{} true", checks))
+            .stderr(Stdio::inherit()),
+    )
+    .ok()
+    .and_then(|o| String::from_utf8(o.stdout).ok())
+    .map(|out| {
+        out.lines()
+            .filter(|l| dirs.iter().any(|d| d == l))
+            .map(String::from)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Moves the build directory of an older naming scheme to [`build_path`], so the next build
+/// doesn't start cold and the old directory isn't leaked. Asks first unless [`force`] is set.
+fn migrate_build_dir(transport: &Transport, build_path: &str, legacy: &[String], force: bool) {
+    let candidates = std::iter::once(build_path.to_owned())
+        .chain(legacy.iter().cloned())
+        .collect::<Vec<_>>();
+    let existing = existing_remote_dirs(transport, &candidates);
+    let source = match migration_source(&existing, build_path, legacy) {
+        Some(source) => source,
+        None => return,
+    };
+
+    warn!(
+        "Found the build directory '{}' of an older cargo-remote version, the new one is '{}'",
+        source, build_path
+    );
+    if !force && !interactive::confirm("Move it?") {
+        info!("Not migrating (use --migrate-remote-dir to migrate without asking)");
+        return;
+    }
+
+    let target = build_path.trim_end_matches('/');
+    let parent = target.rsplit_once('/').map_or(".", |(parent, _)| parent);
+    let status = audit::status(&mut transport.shell(&format!(
+        "mkdir -p {} && mv {} {}",
+        shell_quote_path(if parent.is_empty() { "/" } else { parent }),
+        shell_quote_path(source.trim_end_matches('/')),
+        shell_quote_path(target)
+    )));
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        warn!("Failed to move the old build directory, building from scratch");
+    }
+}
+
+/// The command that removes the remote build directory [`build_path`].
+fn clean_command(transport: &Transport, build_path: &str) -> Result<Command, Error> {
+    let path = build_path.trim_end_matches('/');
+    // a misconfigured build_path must not take the home or root directory with it
+    if path.is_empty() || path == "~" || path == "." {
+        return Err(Error::Clean(format!("refusing to remove '{}'", build_path)));
+    }
+    Ok(transport.shell(&format!("rm -rf {}", shell_quote_path(path))))
+}
+
+/// Removes the remote build directory so the next build starts from scratch.
+fn clean_remote_dir(
+    mut clean: Command,
+    transport: &Transport,
+    build_path: &str,
+) -> Result<(), Error> {
+    warn!(
+        "Removing the remote build directory {}:{}",
+        transport, build_path
+    );
+    let status = audit::status(&mut clean).map_err(|e| spawn_error(&clean, e, Error::Clean))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(remote_error("rm", status, Error::Clean))
+    }
+}
+
+/// Lists the remote build directories of all checkouts at the path of the current project, i.e.
+/// of every host and branch, with their sizes and removes them after asking unless [`yes`] is set.
+fn clean_remote(mut config: RemoteConfig, yes: bool) -> Result<(), Error> {
+    let workspace = Workspace::load(&config)?;
+    let project_dir = &workspace.dir;
+    complete_config(&mut config, &workspace.named_configs, None, None);
+    if config.audit {
+        match config
+            .audit_log
+            .clone()
+            .map(PathBuf::from)
+            .or_else(audit::default_path)
+        {
+            Some(audit_log) => audit::start(audit_log, project_dir),
+            None => warn!("Can't determine the data directory, nothing is audited (set audit_log)"),
+        }
+    }
+    if resolve_remotes(&mut config, &workspace, false, None)?.is_some() {
+        return Err(Error::Clean(
+            "it removes the directories of one build server, pass a single --remote".to_owned(),
+        ));
+    }
+    expand_placeholders(&mut config, project_dir)?;
+    if config.build_path_no_hash {
+        return Err(Error::Clean(
+            "the build path isn't hashed, use --clean-only to remove it".to_owned(),
+        ));
+    }
+    let pinned = toolchain::pinned(project_dir);
+    let paths = BuildPaths::new(&config, &workspace, pinned.as_ref(), None)?;
+    let connection = Connection::new(&config, project_dir, &paths);
+    let transport = &connection.transport;
+    audit::set_transport(transport);
+    let _control_master = connection.control_master.then(|| {
+        ControlMaster::adopt(
+            config.ssh_bin.as_deref().unwrap_or_default(),
+            &connection.ssh_options,
+            &paths.build_server,
+        )
+    });
+
+    let base = shell_quote_path(&paths.build_base);
+    let project_hash = project_hash(project_dir);
+    let mut patterns = vec![
+        format!("{}/{}", base, project_hash),
+        format!("{}/{}-*", base, project_hash),
+    ];
+    patterns.extend(
+        legacy_project_dirs(&paths.build_base, project_dir, Isolation::None)
+            .iter()
+            .map(|d| shell_quote_path(d.trim_end_matches('/'))),
+    );
+    let dirs = remote_dirs::list(transport, &patterns).ok_or_else(|| {
+        Error::Clean(format!("can't list the build directories on {}", transport))
+    })?;
+    if dirs.is_empty() {
+        println!(
+            "No build directories of {} on {}",
+            project_dir.to_string_lossy(),
+            transport
+        );
+        return Ok(());
+    }
+
+    let toolchain = if config.isolate_toolchain {
+        Some(toolchain::isolation_key(
+            config.rustup_default.as_deref(),
+            config.docker_image.as_deref(),
+            pinned.as_ref(),
+        ))
+    } else {
+        None
+    };
+    let current = build_dir_name(
+        project_dir,
+        config.isolate.unwrap_or(Isolation::Host),
+        toolchain.as_deref(),
+    );
+    remote_dirs::print_table(&dirs, &current);
+    if !yes && !interactive::confirm(&format!("Delete these {} directories?", dirs.len())) {
+        info!("Nothing deleted (use --yes to delete without asking)");
+        return Ok(());
+    }
+    remote_dirs::remove(transport, &dirs)
+}
+
+/// Copies the local cargo registry credentials into the cargo home of the build server, readable
+/// only by the remote user. They are piped through ssh, as scp doesn't accept all ssh options.
+fn copy_credentials(transport: &Transport) {
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")));
+    let credentials = cargo_home.and_then(|home| {
+        ["credentials.toml", "credentials"]
+            .iter()
+            .map(|file| home.join(file))
+            .find(|path| path.is_file())
+    });
+    let credentials = match credentials.map(std::fs::File::open) {
+        Some(Ok(file)) => file,
+        Some(Err(e)) => {
+            warn!("Failed to read the cargo credentials (error: {})", e);
+            return;
+        }
+        None => {
+            warn!("There are no cargo credentials to transfer");
+            return;
+        }
+    };
+
+    info!("Transferring cargo credentials to build server.");
+    let status = audit::status(
+        transport
+            .shell(
+                "umask 077 && mkdir -p \"${CARGO_HOME:-$HOME/.cargo}\" && \
+             cat > \"${CARGO_HOME:-$HOME/.cargo}/credentials.toml\"",
+            )
+            .stdin(credentials)
+            .stderr(Stdio::inherit()),
+    );
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        warn!("Failed to transfer the cargo credentials to the build server");
+    }
+}
+
+/// Identifies the local checkout a sync originates from as `user@host:path`.
+fn local_origin(project_dir: &Path) -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+    format!(
+        "{}@{}:{}",
+        user,
+        local_hostname(),
+        project_dir.to_string_lossy()
+    )
+}
+
+/// The name of the local machine, `unknown` if it can't be determined.
+fn local_hostname() -> String {
+    audit::output(&mut Command::new("hostname"))
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Renders the content of the origin marker written on every sync.
+fn origin_marker(origin: &str) -> String {
+    let synced_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut marker = toml::value::Table::new();
+    marker.insert("origin".to_owned(), Value::String(origin.to_owned()));
+    marker.insert("synced_at".to_owned(), Value::Integer(synced_at as i64));
+    marker.insert("scheme".to_owned(), Value::Integer(BUILD_PATH_SCHEME));
+    Value::Table(marker).to_string()
+}
+
+/// Reads the origin marker of the remote build directory. Returns the origin and the unix time of
+/// the last sync, or [`None`] if the directory was never synced or the marker can't be read.
+fn read_remote_origin(transport: &Transport, build_path: &str) -> Option<(String, u64)> {
+    let output = audit::output(
+        transport
+            .shell(&format!(
+                "cat {}{} 2>/dev/null",
+                shell_quote_path(build_path),
+                ORIGIN_MARKER
+            ))
+            .stderr(Stdio::inherit()),
+    )
+    .ok()
+    .filter(|o| o.status.success())?;
+    let marker = String::from_utf8(output.stdout)
+        .ok()?
+        .parse::<Value>()
+        .ok()?;
+    let origin = marker.get("origin")?.as_str()?.to_owned();
+    let synced_at = marker.get("synced_at")?.as_integer()? as u64;
+    Some((origin, synced_at))
+}
+
+/// Resolves the absolute path of the remote build directory, which may be given relative to the
+/// remote home directory.
+fn remote_absolute_path(transport: &Transport, build_path: &str) -> Option<String> {
+    let output = audit::output(
+        transport
+            .shell(&format!("cd {} && pwd", shell_quote_path(build_path)))
+            .stderr(Stdio::inherit()),
+    )
+    .ok()
+    .filter(|o| o.status.success())?;
+    // the login shell may print something before the path
+    String::from_utf8(output.stdout)
+        .ok()?
+        .lines()
+        .rfind(|l| !l.is_empty())
+        .map(|l| l.trim_end_matches('/').to_owned())
+}
+
+/// Rewrites all paths below [`remote_dir`] in a line of cargo's JSON output to point below
+/// [`local_dir`] instead. Returns [`None`] if the line isn't JSON.
+fn remap_json_line(line: &str, remote_dir: &str, local_dir: &str) -> Option<String> {
+    fn remap(value: &mut serde_json::Value, remote_dir: &str, local_dir: &str) {
+        match value {
+            serde_json::Value::String(s) if s.contains(remote_dir) => {
+                *s = replace_dir(s, remote_dir, local_dir)
+            }
+            serde_json::Value::Array(values) => values
+                .iter_mut()
+                .for_each(|v| remap(v, remote_dir, local_dir)),
+            serde_json::Value::Object(fields) => fields
+                .values_mut()
+                .for_each(|v| remap(v, remote_dir, local_dir)),
+            _ => {}
+        }
+    }
+
+    let mut message = serde_json::from_str::<serde_json::Value>(line.trim_end()).ok()?;
+    remap(&mut message, remote_dir, local_dir);
+    Some(message.to_string())
+}
+
+/// Replaces [`dir`] in [`text`] with [`replacement`] where it names the directory itself, not a
+/// sibling whose name starts like it.
+fn replace_dir(text: &str, dir: &str, replacement: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find(dir) {
+        let end = at + dir.len();
+        let whole =
+            !rest[end..].starts_with(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        replaced.push_str(&rest[..at]);
+        replaced.push_str(if whole { replacement } else { dir });
+        rest = &rest[end..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Set once SIGINT was received.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Pid of the child process of the running [`Phase`], 0 if there is none.
+static CHILD_PID: AtomicU32 = AtomicU32::new(0);
+
+/// Makes Ctrl-C wait for the running child instead of killing cargo-remote right away. The signal
+/// is forwarded to the child, which may not have received it itself (e.g. if cargo-remote was
+/// signalled by `kill`).
+fn install_interrupt_handler() {
+    let result = ctrlc::set_handler(|| {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        let pid = CHILD_PID.load(Ordering::SeqCst);
+        if pid != 0 {
+            process_group::interrupt(pid);
+        }
+    });
+    if let Err(e) = result {
+        warn!("Failed to install the Ctrl-C handler (error: {})", e);
+    }
+}
+
+/// What reads along the output of the remote command besides the local terminal.
+#[derive(Clone, Default)]
+struct Observers {
+    /// Scans for cargo waiting on a lock.
+    lock_watch: Option<Arc<LockWatch>>,
+    /// Records the last lines for the bug report.
+    tail: Option<Arc<OutputTail>>,
+    /// Picks up the build script directories named in errors.
+    build_scripts: Option<Arc<build_script_logs::Hints>>,
+    /// Notices whether the remote command printed anything, to tell a dropped connection apart.
+    remote_output: Option<Arc<RemoteOutput>>,
+}
+
+impl Observers {
+    fn is_empty(&self) -> bool {
+        self.lock_watch.is_none()
+            && self.tail.is_none()
+            && self.build_scripts.is_none()
+            && self.remote_output.is_none()
+    }
+
+    fn observe(&self, line: &[u8]) {
+        if let (Some(watch), Ok(text)) = (&self.lock_watch, std::str::from_utf8(line)) {
+            watch.observe(text);
+        }
+        if let Some(build_scripts) = &self.build_scripts {
+            build_scripts.observe(line);
+        }
+        if let Some(tail) = &self.tail {
+            tail.record(line);
+        }
+        if let Some(remote_output) = &self.remote_output {
+            remote_output.observe(line);
+        }
+    }
+}
+
+/// A step of the remote build (sync, build, copy-back) that is aborted once its timeout passed.
+struct Phase {
+    name: &'static str,
+    start: Instant,
+    deadline: Option<Instant>,
+}
+
+impl Phase {
+    fn start(name: &'static str, timeout: Option<Duration>) -> Phase {
+        let start = Instant::now();
+        Phase {
+            name,
+            start,
+            deadline: timeout.map(|t| start + t),
+        }
+    }
+
+    /// Spawns [`cmd`] and waits for it to exit. If it can't be run, [`on_error`] turns the reason
+    /// into the error of the step. If the phase's deadline passes first, the process is killed
+    /// and [`Error::Timeout`] is returned. If the user interrupted the phase,
+    /// [`Error::Interrupted`] is returned once the child is gone, so no further phase runs against
+    /// a half-finished state.
+    fn run(&self, cmd: &mut Command, on_error: fn(String) -> Error) -> Result<ExitStatus, Error> {
+        let child = self.spawn(cmd, on_error)?;
+        self.wait(child, cmd, on_error)
+    }
+
+    /// Spawns [`cmd`], in a process group of its own if the phase has a timeout, so the timeout
+    /// kills what it started as well.
+    fn spawn(&self, cmd: &mut Command, on_error: fn(String) -> Error) -> Result<Child, Error> {
+        if self.deadline.is_some() {
+            process_group::isolate(cmd);
+        }
+        spawn(cmd, on_error)
+    }
+
+    /// Like [`Phase::run`], but every line the process writes to stdout is passed through
+    /// [`filter`]. Lines the filter returns [`None`] for are forwarded unchanged. With
+    /// [`pty_bridge`] set and a terminal on stdout, the process writes into a pseudo terminal
+    /// instead of a pipe, so programs don't switch to block buffering. Unless there are no
+    /// [`observers`], stderr is read as well and both outputs are passed to them.
+    fn run_filtered<F>(
+        &self,
+        cmd: &mut Command,
+        on_error: fn(String) -> Error,
+        pty_bridge: bool,
+        observers: Observers,
+        filter: F,
+    ) -> Result<ExitStatus, Error>
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        // Windows has no pseudo terminals
+        let pty = if pty_bridge && cfg!(unix) && interactive::stdout() {
+            open_pty()
+                .map_err(|e| {
+                    warn!(
+                        "Failed to open a pseudo terminal, using a pipe (error: {})",
+                        e
+                    )
+                })
+                .ok()
+        } else {
+            None
+        };
+        let read_stderr = !observers.is_empty();
+        if read_stderr {
+            cmd.stderr(Stdio::piped());
+        }
+        let (mut child, output): (Child, Box<dyn Read + Send>) = match pty {
+            Some((master, slave)) => {
+                let child = self.spawn(cmd.stdout(slave), on_error)?;
+                // the master only sees the end of the output once no slave fd is left open here
+                cmd.stdout(Stdio::inherit());
+                (child, Box::new(master))
+            }
+            None => {
+                let mut child = self.spawn(cmd.stdout(Stdio::piped()), on_error)?;
+                let output = child.stdout.take().expect("stdout is piped");
+                (child, Box::new(output))
+            }
+        };
+        if read_stderr {
+            cmd.stderr(Stdio::inherit());
+        }
+        let forward_stderr = child.stderr.take().map(|errors| {
+            let observers = observers.clone();
+            std::thread::spawn(move || {
+                let mut errors = BufReader::new(errors);
+                let mut line = Vec::new();
+                while errors.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                    observers.observe(&line);
+                    let mut stderr = std::io::stderr().lock();
+                    if stderr
+                        .write_all(&line)
+                        .and_then(|_| stderr.flush())
+                        .is_err()
+                    {
+                        break;
+                    }
+                    line.clear();
+                }
+            })
+        });
+        let mut output = BufReader::new(output);
+        let forward = std::thread::spawn(move || {
+            let mut line = Vec::new();
+            // reading the master of a pty fails instead of returning 0 once the child is gone
+            while output.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                observers.observe(&line);
+                let text = std::str::from_utf8(&line).ok();
+                let filtered = text.and_then(&filter);
+                let mut stdout = std::io::stdout().lock();
+                let written = match filtered {
+                    // keep the \r\n of a remote tty, the local terminal may be in raw mode
+                    Some(filtered) if line.ends_with(b"\r\n") => {
+                        write!(stdout, "{}\r\n", filtered)
+                    }
+                    Some(filtered) => writeln!(stdout, "{}", filtered),
+                    None => stdout.write_all(&line),
+                };
+                if written.and_then(|_| stdout.flush()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        let status = self.wait(child, cmd, on_error);
+        let _ = forward.join();
+        if let Some(forward_stderr) = forward_stderr {
+            let _ = forward_stderr.join();
+        }
+        status
+    }
+
+    /// Like [`Phase::run`] for rsync transfers. rsync's summary is taken out of its output to
+    /// return the bytes sent and received, the rest is forwarded to stdout, or to stderr if
+    /// [`to_stderr`] is set.
+    fn run_transfer(
+        &self,
+        cmd: &mut Command,
+        on_error: fn(String) -> Error,
+        to_stderr: bool,
+    ) -> Result<(ExitStatus, TransferStats), Error> {
+        let mut child = self.spawn(cmd.stdout(Stdio::piped()), on_error)?;
+        let mut output = child.stdout.take().expect("stdout is piped");
+        let forward = std::thread::spawn(move || {
+            let mut out: Box<dyn Write> = if to_stderr {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            };
+            let mut stats = TransferStats::default();
+            let mut line = Vec::new();
+            let mut buffer = [0; 4096];
+            while let Ok(read @ 1..) = output.read(&mut buffer) {
+                // progress updates end with \r, so forward them as they come
+                for &byte in &buffer[..read] {
+                    line.push(byte);
+                    if byte == b'\n' || byte == b'\r' {
+                        if !stats.parse(&line) {
+                            let _ = out.write_all(&line);
+                        }
+                        line.clear();
+                    }
+                }
+                let _ = out.flush();
+            }
+            let _ = out.write_all(&line);
+            stats
+        });
+        let status = self.wait(child, cmd, on_error)?;
+        Ok((status, forward.join().unwrap_or_default()))
+    }
+
+    /// Like [`Phase::run_transfer`], but a transfer that broke off is retried up to [`retries`]
+    /// times after a growing pause, resuming the partially transferred files with
+    /// `--append-verify`. If every attempt broke off, the status of the first one is returned.
+    fn run_transfer_retrying(
+        &self,
+        cmd: &mut Command,
+        on_error: fn(String) -> Error,
+        to_stderr: bool,
+        retries: u32,
+    ) -> Result<(ExitStatus, TransferStats), Error> {
+        let (mut status, mut stats) = self.run_transfer(cmd, on_error, to_stderr)?;
+        let first_status = status;
+        let mut attempt = 0;
+        while attempt < retries && retry::is_transient_transfer_failure(status) {
+            attempt += 1;
+            let delay = retry::backoff(attempt);
+            warn!(
+                "The transfer broke off ({}), retrying in {} (attempt {} of {})",
+                status,
+                format_duration(delay),
+                attempt,
+                retries
+            );
+            std::thread::sleep(delay);
+            if attempt == 1 {
+                // rsync takes options after the paths as well
+                cmd.arg("--partial").arg("--append-verify");
+            }
+            let (retry_status, retry_stats) = self.run_transfer(cmd, on_error, to_stderr)?;
+            status = retry_status;
+            stats.sent += retry_stats.sent;
+            stats.received += retry_stats.received;
+        }
+        if attempt > 0 && retry::is_transient_transfer_failure(status) {
+            status = first_status;
+        }
+        Ok((status, stats))
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn wait(
+        &self,
+        mut child: Child,
+        cmd: &Command,
+        on_error: fn(String) -> Error,
+    ) -> Result<ExitStatus, Error> {
+        CHILD_PID.store(child.id(), Ordering::SeqCst);
+        let status = match self.deadline {
+            None => child.wait().map(Some),
+            Some(deadline) => {
+                let foreground = process_group::Foreground::give(&child);
+                let status = wait_until(&mut child, deadline);
+                // Ctrl-C went to the child's group only
+                let interrupted = status
+                    .as_ref()
+                    .ok()
+                    .and_then(Option::as_ref)
+                    .is_some_and(process_group::interrupted);
+                if foreground.active() && interrupted {
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                }
+                status
+            }
+        };
+        CHILD_PID.store(0, Ordering::SeqCst);
+        audit::record(cmd, status.as_ref().ok().and_then(Option::as_ref));
+
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted { phase: self.name });
+        }
+        match status {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => Err(Error::Timeout {
+                phase: self.name,
+                after: self.deadline.unwrap_or_else(Instant::now) - self.start,
+            }),
+            Err(e) => Err(spawn_error(cmd, e, on_error)),
+        }
+    }
+}
+
+/// Opens a pseudo terminal with the window size of the local terminal. The slave side is raw, so
+/// the output reaches the master unchanged. Returns the master and the slave.
+#[cfg(unix)]
+fn open_pty() -> std::io::Result<(std::fs::File, std::fs::File)> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: the pointers are valid for the duration of the calls and the returned fds are
+    // owned by the files created from them
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        let size_ptr = if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 {
+            &mut size as *mut libc::winsize
+        } else {
+            std::ptr::null_mut()
+        };
+        if libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            size_ptr,
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        let (master, slave) = (
+            std::fs::File::from_raw_fd(master),
+            std::fs::File::from_raw_fd(slave),
+        );
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+        Ok((master, slave))
+    }
+}
+
+#[cfg(not(unix))]
+fn open_pty() -> std::io::Result<(std::fs::File, std::fs::File)> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Bytes an rsync transfer sent and received.
+#[derive(Default, Clone, Copy)]
+struct TransferStats {
+    sent: u64,
+    received: u64,
+}
+
+impl TransferStats {
+    /// Picks the numbers from the summary lines of `rsync --info=stats1`, like
+    /// `sent 1,234 bytes  received 56 bytes  2,580.00 bytes/sec`. Returns `false` if [`line`] is
+    /// not part of the summary.
+    fn parse(&mut self, line: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with("total size is ") {
+            return true;
+        }
+        if !line.starts_with("sent ") {
+            return false;
+        }
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let number = |word: &str| word.replace(',', "").parse::<u64>().ok();
+        match (words.get(1), words.get(3), words.get(4)) {
+            (Some(sent), Some(&"received"), Some(received)) => {
+                self.sent = number(sent).unwrap_or(0);
+                self.received = number(received).unwrap_or(0);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Waits for [`child`] to exit, killing it and the processes of its group if [`deadline`] passes
+/// first.
+fn wait_until(child: &mut Child, deadline: Instant) -> std::io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            process_group::kill(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Returns the ssh options that make all connections share the ControlMaster socket at [`socket`],
+/// which is kept open for [`persist`] once it's idle.
+fn control_master_options(socket: &str, persist: &str) -> Vec<String> {
+    vec![
+        "-o".to_owned(),
+        format!("ControlPath={}", socket),
+        "-o".to_owned(),
+        "ControlMaster=auto".to_owned(),
+        "-o".to_owned(),
+        format!("ControlPersist={}", persist),
+    ]
+}
+
+/// Whether the user already configured connection sharing through [`ssh_options`].
+fn has_control_options(ssh_options: &[String]) -> bool {
+    ssh_options
+        .iter()
+        .any(|o| o.contains("ControlPath") || o.contains("ControlMaster"))
+}
+
+/// The master connection of a run, closed when dropped, so a run that fails or is interrupted
+/// doesn't leave it behind until it times out.
+struct ControlMaster<'a> {
+    ssh: &'a str,
+    ssh_options: &'a [String],
+    build_server: &'a str,
+}
+
+impl<'a> ControlMaster<'a> {
+    /// Opens the master connection in the background. If this fails, the first connection that
+    /// needs the socket becomes the master instead.
+    fn open(ssh: &'a str, ssh_options: &'a [String], build_server: &'a str) -> ControlMaster<'a> {
+        let status = audit::status(
+            local_shell::command(ssh)
+                .args(ssh_options)
+                .arg("-f")
+                .arg("-N")
+                .arg(build_server)
+                .stdin(Stdio::inherit())
+                .stderr(Stdio::inherit()),
+        );
+        if !status.map(|s| s.success()).unwrap_or(false) {
+            warn!("Failed to open the shared ssh connection to the build server");
+        }
+        ControlMaster::adopt(ssh, ssh_options, build_server)
+    }
+
+    /// Takes care of the master connection that the first connection through the socket of
+    /// [`ssh_options`] opens.
+    fn adopt(ssh: &'a str, ssh_options: &'a [String], build_server: &'a str) -> ControlMaster<'a> {
+        ControlMaster {
+            ssh,
+            ssh_options,
+            build_server,
+        }
+    }
+}
+
+impl Drop for ControlMaster<'_> {
+    fn drop(&mut self) {
+        let status = audit::status(
+            local_shell::command(self.ssh)
+                .args(self.ssh_options)
+                .arg("-O")
+                .arg("exit")
+                .arg(self.build_server)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        );
+        if !status.map(|s| s.success()).unwrap_or(false) {
+            info!(
+                "Failed to close the shared ssh connection, it closes itself after {} of inactivity",
+                CONTROL_PERSIST
+            );
+        }
+    }
+}
+
+/// Watches the output of the remote command through [`watch`] until [`finished`] is set. If cargo
+/// waits longer than [`grace`] for a lock left behind by a crashed run, the lock is removed and
+/// the local ssh process killed, so the build can be restarted. Returns whether that happened.
+fn monitor_stale_locks(
+    watch: Arc<LockWatch>,
+    finished: Arc<AtomicBool>,
+    grace: Duration,
+    transport: Transport,
+    target_dir: String,
+    pid_file: String,
+) -> std::thread::JoinHandle<bool> {
+    std::thread::spawn(move || {
+        while !finished.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(100));
+            let lock = match watch.blocked_longer_than(grace) {
+                Some(lock) => lock,
+                None => continue,
+            };
+            let quoted_dir = shell_quote_path(&target_dir);
+            match remove_stale_lock(&transport, &quoted_dir, &pid_file, lock) {
+                Some(Recovery::Removed(files)) if !files.is_empty() => {
+                    for file in &files {
+                        warn!("Removed the stale {} lock {}:{}", lock, transport, file);
+                    }
+                    let pid = CHILD_PID.load(Ordering::SeqCst);
+                    if pid != 0 {
+                        process_group::terminate(pid);
+                    }
+                    return true;
+                }
+                Some(Recovery::Removed(_)) => {
+                    warn!("The {} lock is stale, but its file wasn't found", lock)
+                }
+                Some(Recovery::Held) => info!(
+                    "The {} lock is held by a previous build that is still running",
+                    lock
+                ),
+                Some(Recovery::Unknown) => info!(
+                    "Can't tell whether the {} lock is stale, no previous build was recorded",
+                    lock
+                ),
+                None => warn!("Failed to check whether the {} lock is stale", lock),
+            }
+        }
+        false
+    })
+}
+
+/// Kills the process group of the remote command whose pid was recorded in [`PID_FILE`].
+fn kill_remote_command(transport: &Transport, build_path: &str) {
+    let pid_file = format!("{}{}", shell_quote_path(build_path), PID_FILE);
+    let status = audit::status(&mut transport.shell(&format!(
+        "kill -TERM -- -$(cat {pid}) 2>/dev/null || kill -TERM $(cat {pid}); rm -f {pid}",
+        pid = pid_file
+    )));
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        warn!("Failed to kill the remote command, it may still be running on the build server");
+    }
+}
+
+/// Whether the upload that exited with [`status`] transferred everything. Files that vanished
+/// while rsync ran don't stop the build, but the next sync can't be incremental.
+fn upload_succeeded(status: ExitStatus) -> Result<bool, Error> {
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(24) => {
+            warn!("Some files vanished while they were transferred to the build server");
+            Ok(false)
+        }
+        _ => Err(rsync_error(status, Error::Sync)),
+    }
+}
+
+/// Creates the local directory [`dir`] so rsync can place transferred artifacts there.
+fn create_local_dir(dir: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| Error::CopyBack(format!("can't create '{}': {}", dir.to_string_lossy(), e)))
+}
+
+/// The rsync filters of the sync, relative to the workspace root. [`target_dirs`] are the target
+/// directories besides `target` that live in the workspace, [`hidden`] is set if all hidden files
+/// are transferred.
+#[allow(clippy::too_many_arguments)]
+fn sync_filters(
+    include: &[String],
+    target_dirs: &[String],
+    scope_filters: &[OsString],
+    use_direnv: bool,
+    transfer_cargo_config: bool,
+    exclude: &[String],
+    hidden: bool,
+    hidden_except: &[String],
+    hidden_only: &[String],
+    respect_gitignore: bool,
+    info_exclude: Option<String>,
+) -> Vec<OsString> {
+    let mut filters: Vec<OsString> = vec![
+        "--exclude".into(),
+        format!("/{}", ORIGIN_MARKER).into(),
+        "--exclude".into(),
+        format!("/{}*", PID_FILE).into(),
+        // the leftovers of an interrupted transfer back, e.g. of Cargo.lock
+        "--exclude".into(),
+        format!("{}/", PARTIAL_DIR).into(),
+        "--exclude".into(),
+        format!("/{}/", skip_unchanged::STATE_DIR).into(),
+    ];
+
+    // rsync applies the first matching rule, so the order of the filters is their precedence:
+    // cargo-remote's own excludes, --include, the target directories, the package scope, the
+    // .envrc and cargo config, --exclude, the hidden file rules and finally the .gitignore files
+    for pattern in include {
+        filters.extend(["--include".into(), pattern.into()]);
+    }
+
+    filters.extend(["--exclude".into(), "target".into()]);
+    for dir in target_dirs {
+        filters.extend(["--exclude".into(), format!("/{}/", dir).into()]);
+    }
+    filters.extend(scope_filters.iter().cloned());
+
+    if use_direnv && !hidden {
+        filters.push("--include=/.envrc".into());
+    }
+
+    if transfer_cargo_config && !hidden {
+        // rsync applies the first matching rule, so these must precede the hidden file exclude
+        for rule in &[
+            "--include=/.cargo/",
+            "--include=/.cargo/config.toml",
+            "--include=/.cargo/config",
+            "--exclude=/.cargo/*",
+        ] {
+            filters.push(rule.into());
+        }
+    }
+
+    for pattern in exclude {
+        filters.extend(["--exclude".into(), pattern.into()]);
+    }
+
+    if !hidden_except.is_empty() {
+        // the history of the repository is big and useless for the build
+        for pattern in hidden_except.iter().map(String::as_str).chain([".git"]) {
+            filters.extend(["--exclude".into(), pattern.into()]);
+        }
+    } else if !hidden {
+        for pattern in hidden_only {
+            filters.extend(["--include".into(), pattern.into()]);
+        }
+        filters.extend(["--exclude".into(), ".*".into()]);
+    }
+
+    if respect_gitignore {
+        // the dir-merge rule picks up the .gitignore of every directory, not just the root one
+        filters.push("--filter=:- .gitignore".into());
+        if let Some(info_exclude) = info_exclude {
+            filters.extend(["--exclude-from".into(), info_exclude.into()]);
+        }
+    }
+    filters
+}
+
+fn main() {
+    let result = match parse_args(std::env::args_os().collect()) {
+        Opts::Init { remote, force } => {
+            init_logger(false, false, LogFormat::Text);
+            init_config(remote, force).map(|()| 0)
+        }
+        Opts::History => {
+            init_logger(false, false, LogFormat::Text);
+            show_history().map(|()| 0)
+        }
+        Opts::CleanRemote { config, yes, debug } => {
+            init_logger(debug, false, LogFormat::Text);
+            clean_remote(config, yes).map(|()| 0)
+        }
+        Opts::Which {
+            config,
+            exists,
+            debug,
+            path,
+        } => {
+            init_logger(debug, false, LogFormat::Text);
+            show_which(config, which::Query { path, exists })
+        }
+        Opts::Config { config, debug } => {
+            init_logger(debug, false, LogFormat::Text);
+            show_config(config)
+        }
+        Opts::AuditLog { config, debug } => {
+            init_logger(debug, false, LogFormat::Text);
+            show_audit_log(config).map(|()| 0)
+        }
+        Opts::Remote {
+            config,
+            dry_run,
+            bug_report,
+            force,
+            no_sync,
+            clean,
+            clean_only,
+            fresh,
+            debug,
+            quiet,
+            log_format,
+            progress,
+            all_remotes,
+            and_then,
+            command,
+            options,
+        } => {
+            let steps: Vec<Step> = command
+                .map(|command| Step { command, options })
+                .into_iter()
+                .chain(and_then)
+                .collect();
+            // asking cargo to be quiet quiets cargo-remote as well, unless it was asked for logs
+            let quiet = run_is_quiet(quiet, debug, &steps);
+            init_logger(debug, quiet, log_format);
+            install_interrupt_handler();
+            let flags = RunFlags {
+                dry_run,
+                force,
+                no_sync,
+                clean,
+                clean_only,
+                fresh,
+                quiet,
+                progress,
+                all_remotes,
+            };
+            let mut report = if bug_report {
+                Some(BugReport::default())
+            } else {
+                None
+            };
+            let result = run_remote(config, flags, steps, report.as_mut());
+            if let Some(mut report) = report {
+                write_bug_report(&mut report, &result);
+            }
+            result
+        }
+    };
+
+    let code = match result {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+    webhook::send(code);
+    log_format::exit(code);
+    if code != 0 {
+        exit(code);
+    }
+}
+
+/// Adds the outcome of the run to [`report`] and writes it into the current directory.
+fn write_bug_report(report: &mut BugReport, result: &Result<i32, Error>) {
+    let outcome = match result {
+        Ok(code) => format!("exit code {}\n", code),
+        Err(e) => format!("error: {}\nexit code {}\n", e, e.exit_code()),
+    };
+    report.set("outcome.txt", outcome);
+    match current_dir()
+        .map_err(|e| e.to_string())
+        .and_then(|dir| report.write(&dir))
+    {
+        Ok(path) => eprintln!("Bug report written to {}", path.to_string_lossy()),
+        Err(e) => error!("Failed to write the bug report: {}", e),
+    }
+}
+
+/// The phases of a run that finished so far, printed as `--stats` summary when the run ends, also
+/// if a later phase failed.
+#[derive(Default)]
+struct PhaseStats {
+    enabled: bool,
+    sync: Option<(Duration, u64)>,
+    build: Option<Duration>,
+    copy_back: Option<(Duration, u64)>,
+}
+
+impl PhaseStats {
+    fn summary(&self) -> String {
+        history::phase_summary(self.sync, self.build, self.copy_back)
+    }
+}
+
+impl Drop for PhaseStats {
+    fn drop(&mut self) {
+        if self.enabled && (self.sync.is_some() || self.build.is_some()) {
+            eprintln!("{}", self.summary());
+        }
+    }
+}
+
+/// A transfer of artifacts back to the local machine. It's assembled before anything runs, so
+/// that `--dry-run` can show it.
+struct CopyBackTransfer {
+    /// What is transferred, for the logs.
+    description: String,
+    /// The local directory the transfer writes into, created right before it runs.
+    local_dir: PathBuf,
+    /// Where the artifacts land locally, for the hygiene check.
+    fetched: Vec<PathBuf>,
+    rsync: Command,
+}
+
+/// The flags of a `cargo remote` invocation that aren't part of the config.
+struct RunFlags {
+    dry_run: bool,
+    force: bool,
+    no_sync: bool,
+    clean: bool,
+    clean_only: bool,
+    /// Remove the remote target directory before building.
+    fresh: bool,
+    quiet: bool,
+    /// Show rsync's progress even without a terminal.
+    progress: bool,
+    all_remotes: bool,
+}
+
+/// Reads the config files of the project, highest precedence first, with a name for every config
+/// to tell the user where a key comes from.
+fn load_named_configs(
+    project_metadata: &cargo_metadata::Metadata,
+    manifest_path: &Path,
+) -> Vec<(String, Value)> {
+    let project_dir = &project_metadata.workspace_root;
+    let project_config = project_dir.join(".cargo-remote.toml");
+    let global_config = app_dirs::find_config_file("cargo-remote.toml");
+    let configs = vec![
+        config_from_file(&project_config, true)
+            .map(|c| (project_config.to_string_lossy().into_owned(), c)),
+        config_from_package_metadata(project_metadata, manifest_path).map(|c| {
+            (
+                format!(
+                    "[package.metadata.cargo-remote] of {}",
+                    manifest_path.to_string_lossy()
+                ),
+                c,
+            )
+        }),
+        config_from_workspace_metadata(project_dir).map(|c| {
+            (
+                format!(
+                    "[workspace.metadata.cargo-remote] of {}",
+                    project_dir.join("Cargo.toml").to_string_lossy()
+                ),
+                c,
+            )
+        }),
+        global_config.and_then(|path: PathBuf| {
+            config_from_file(&path, false).map(|c| (path.to_string_lossy().into_owned(), c))
+        }),
+    ];
+    configs.into_iter().flatten().collect()
+}
+
+/// Warns about the keys of the configs [`named_configs`] that had no effect, as [`unused`] lists
+/// them for each.
+fn warn_unused_keys(named_configs: &[(String, Value)], unused: &[UnusedKeys]) {
+    for ((name, _), unused) in named_configs.iter().zip(unused) {
+        for (key, suggestion) in &unused.unknown {
+            match suggestion {
+                Some(suggestion) => warn!(
+                    "Unknown config key '{}' in {}, did you mean '{}'?",
+                    key, name, suggestion
+                ),
+                None => warn!("Unknown config key '{}' in {}", key, name),
+            }
+        }
+        for key in &unused.shadowed {
+            warn!(
+                "Config key '{}' in {} has no effect, the option is set by the command line or \
+                 a config with higher precedence",
+                key, name
+            );
+        }
+    }
+}
+
+/// The workspace a command works on and the configs that apply to it.
+struct Workspace {
+    current_path: PathBuf,
+    manifest_path: PathBuf,
+    /// Whether a local cargo reads the packages of the workspace.
+    local_cargo: bool,
+    metadata: cargo_metadata::Metadata,
+    dir: PathBuf,
+    /// The configs, highest precedence first, see [`load_named_configs`].
+    named_configs: Vec<(String, Value)>,
+}
+
+impl Workspace {
+    /// Finds the workspace of the current directory and reads its configs.
+    fn load(config: &RemoteConfig) -> Result<Workspace, Error> {
+        let current_path = current_dir()?;
+        let manifest_path = find_manifest(&current_path)?;
+        // the config is found through the workspace root, so this is a command line flag only
+        let local_cargo = !config.no_local_cargo && local_cargo::available();
+        if !local_cargo {
+            warn!(
+                "{}, the workspace is read from its manifests and its packages are unknown",
+                if config.no_local_cargo {
+                    "--no-local-cargo is set"
+                } else {
+                    "No cargo found on this machine"
+                }
+            );
+        }
+        let metadata = load_metadata(&manifest_path, local_cargo)?;
+        let dir = metadata.workspace_root.clone();
+        let named_configs = load_named_configs(&metadata, &manifest_path);
+        info!("Project dir: {:?}", dir);
+        Ok(Workspace {
+            current_path,
+            manifest_path,
+            local_cargo,
+            metadata,
+            dir,
+            named_configs,
+        })
+    }
+
+    fn configs(&self) -> Vec<&Value> {
+        self.named_configs.iter().map(|(_, c)| c).collect()
+    }
+}
+
+/// Completes the options of the command line in [`config`] from the environment and the
+/// [`named_configs`], and applies the ones that take effect right away. The build server comes
+/// from `--remote`, else `CARGO_REMOTE_HOST`, else the `[targets]` table for the target, else the
+/// `remote` keys of the configs. [`cargo_target`] is a `--target` of the cargo options, which wins
+/// over the one of cargo-remote. Returns where the options `cargo remote config` prints come from.
+fn complete_config(
+    config: &mut RemoteConfig,
+    named_configs: &[(String, Value)],
+    fan_out_member: Option<&fan_out::Member>,
+    cargo_target: Option<&str>,
+) -> Origins {
+    // a process started for one build server of a fan-out builds there only
+    let remote_from_env = config.complete_from_env();
+    if let Some(member) = fan_out_member {
+        config.remote = vec![member.server.clone()];
+    }
+    let mut origins = Origins::new(config, remote_from_env, named_configs);
+    let remote_from_cli = !config.remote.is_empty();
+    let unused = config.complete_from_configs(named_configs.iter().map(|(_, c)| c));
+    if !remote_from_cli {
+        let target = cargo_target
+            .map(str::to_owned)
+            .or_else(|| config.target.clone());
+        if let Some(name) = target.and_then(|t| config.pick_target_remote(&t, named_configs)) {
+            origins.remote = Origin::Config(format!("[targets] of {}", name));
+        }
+    }
+    // the processes of a fan-out would repeat the warnings of the one that started them
+    if !config.no_config_warnings && fan_out_member.is_none() {
+        warn_unused_keys(named_configs, &unused);
+    }
+    config.apply_defaults();
+    if config.non_interactive {
+        interactive::disable();
+    }
+    if let Some(shell) = config.local_shell {
+        local_shell::set(shell);
+    }
+    origins
+}
+
+/// Replaces the aliases of the [remotes] table in the build servers of [`config`], or takes all
+/// of its build servers with [`all_remotes`]. Returns the build servers to fan out to if the
+/// command runs on more than one.
+fn resolve_remotes(
+    config: &mut RemoteConfig,
+    workspace: &Workspace,
+    all_remotes: bool,
+    fan_out_member: Option<&fan_out::Member>,
+) -> Result<Option<Vec<fan_out::Member>>, Error> {
+    // the [remotes] table of the config with the highest precedence names the build servers
+    let remotes = workspace
+        .configs()
+        .iter()
+        .find_map(|c| c.get("remotes")?.as_table())
+        .map(|table| {
+            table
+                .iter()
+                .filter_map(|(alias, server)| match server.as_str() {
+                    Some(server) => Some((alias.clone(), server.to_owned())),
+                    None => {
+                        warn!(
+                            "Ignoring the [remotes] entry for {}: {} isn't a build server",
+                            alias, server
+                        );
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let members: Vec<fan_out::Member> = if all_remotes && fan_out_member.is_none() {
+        remotes
+            .iter()
+            .map(|(alias, server)| fan_out::Member {
+                alias: alias.clone(),
+                server: server.clone(),
+            })
+            .collect()
+    } else {
+        config
+            .remote
+            .iter()
+            .map(|remote| fan_out::Member {
+                alias: remote.clone(),
+                server: remotes
+                    .iter()
+                    .find(|(alias, _)| alias == remote)
+                    .map_or_else(|| remote.clone(), |(_, server)| server.clone()),
+            })
+            .collect()
+    };
+    if fan_out_member.is_none() && (all_remotes || members.len() > 1) {
+        if members.is_empty() {
+            return Err(Error::NoRemote);
+        }
+        return Ok(Some(members));
+    }
+    config.remote = members.into_iter().map(|member| member.server).collect();
+    Ok(None)
+}
+
+/// The toolchain passed as cargo +<toolchain>, [`None`] if the toolchain file [`pinned`] or the
+/// Docker image decides. A toolchain file is left to rustup, as `rustup default` would change the
+/// toolchain of everyone using the build server.
+fn remote_toolchain(
+    rustup_default: Option<String>,
+    pinned: Option<&toolchain::Pinned>,
+    docker_image: bool,
+) -> Option<String> {
+    match (rustup_default, pinned) {
+        (Some(toolchain), _) => Some(toolchain),
+        (None, Some(pinned)) => {
+            info!(
+                "Using the toolchain {} pinned by {}",
+                pinned.channel, pinned.file
+            );
+            None
+        }
+        // the image pins the toolchain
+        (None, None) if docker_image => None,
+        (None, None) => Some("stable".to_owned()),
+    }
+}
+
+/// Where the project is synced to and built, and where its artifacts end up locally.
+struct BuildPaths {
+    sync_root: sync_root::SyncRoot,
+    /// The workspace below the sync root, with a trailing slash unless it's the sync root.
+    project_subdir: String,
+    build_server: String,
+    /// The base directory of the builds of a `local:` remote.
+    local_dir: Option<String>,
+    /// The directory the build directories of all projects are in, without a trailing slash.
+    build_base: String,
+    /// The directory the sync root is transferred to.
+    sync_path: String,
+    /// The directory the workspace is built in.
+    build_path: String,
+    /// The build directories older versions used for the workspace.
+    legacy_build_paths: Vec<String>,
+    remote_target: String,
+    local_target: PathBuf,
+}
+
+impl BuildPaths {
+    /// Works out the paths on the first build server of [`config`].
+    fn new(
+        config: &RemoteConfig,
+        workspace: &Workspace,
+        pinned: Option<&toolchain::Pinned>,
+        fan_out_member: Option<&fan_out::Member>,
+    ) -> Result<BuildPaths, Error> {
+        let project_dir = &workspace.dir;
+        // path dependencies outside the workspace root are synced from a common ancestor, or not
+        // at all
+        let sync_root = sync_root::resolve(
+            &workspace.metadata,
+            config.sync_parent.as_deref().map(Path::new),
+        )?;
+        let project_subdir = if sync_root.project.is_empty() {
+            String::new()
+        } else {
+            info!(
+                "Syncing from {}, the workspace is in {}",
+                sync_root.dir.to_string_lossy(),
+                sync_root.project
+            );
+            format!("{}/", sync_root.project)
+        };
+
+        let build_server = config.remote.first().cloned().ok_or(Error::NoRemote)?;
+        // a local: remote names the base directory of the builds on this machine
+        let local_dir = local_build_dir(&build_server);
+        if let Some(dir) = &local_dir {
+            info!("Building on the local machine below {}", dir);
+        }
+
+        // the target directory is only reusable by the toolchain that built it
+        let isolated_toolchain = config.isolate_toolchain.then(|| {
+            toolchain::isolation_key(
+                config.rustup_default.as_deref(),
+                config.docker_image.as_deref(),
+                pinned,
+            )
+        });
+        let build_base = local_dir
+            .clone()
+            .or_else(|| config.build_path.clone())
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_owned();
+        let isolation = config.isolate.unwrap_or(Isolation::Host);
+        let sync_path = if config.build_path_no_hash {
+            format!("{}/", build_base)
+        } else {
+            // generate a unique build path by using the hashed project dir as folder on the remote machine
+            format!(
+                "{}/{}/",
+                build_base,
+                build_dir_name(project_dir, isolation, isolated_toolchain.as_deref())
+            )
+        };
+        let build_path = format!("{}{}", sync_path, project_subdir);
+        // the directories of older versions hold just the workspace
+        let legacy_build_paths = if config.build_path_no_hash || !project_subdir.is_empty() {
+            Vec::new()
+        } else {
+            legacy_project_dirs(&build_base, project_dir, isolation)
+        };
+        // cargo resolves a relative CARGO_TARGET_DIR against the directory it runs in, which may
+        // be the one of a member, so the remote target directory is made absolute
+        let remote_target = match &config.remote_target_dir {
+            Some(dir) if dir.starts_with('/') || dir.starts_with("~/") => {
+                dir.trim_end_matches('/').to_owned()
+            }
+            Some(dir) => format!("{}{}", build_path, dir.trim_end_matches('/')),
+            None => format!("{}target", build_path),
+        };
+        // CARGO_TARGET_DIR or a cargo config may have moved the local one. The build servers of a
+        // fan-out each get a subdirectory, so their artifacts and sync states don't mix.
+        let target_directory = &workspace.metadata.target_directory;
+        let local_target = match fan_out_member {
+            Some(member) => {
+                target_directory.join(format!("remote-{}", placeholders::slug(&member.alias)))
+            }
+            None if config.copy_back_isolate => target_directory.join(ISOLATED_TARGET),
+            None => target_directory.clone(),
+        };
+        Ok(BuildPaths {
+            sync_root,
+            project_subdir,
+            build_server,
+            local_dir,
+            build_base,
+            sync_path,
+            build_path,
+            legacy_build_paths,
+            remote_target,
+            local_target,
+        })
+    }
+}
+
+/// How the build server is reached.
+struct Connection {
+    transport: Transport,
+    ssh_options: Vec<String>,
+    /// Whether the run opens a master connection of its own, which it closes when it ends.
+    control_master: bool,
+}
+
+impl Connection {
+    /// The connection to the build server of [`paths`] with the ssh options of [`config`].
+    fn new(config: &RemoteConfig, project_dir: &Path, paths: &BuildPaths) -> Connection {
+        let mut ssh_options = config.ssh_opts.clone();
+        if let Some(jump_host) = &config.jump_host {
+            ssh_options.push("-J".to_owned());
+            ssh_options.push(jump_host.clone());
+        }
+        if let Some(timeout) = config.connect_timeout {
+            ssh_options.push("-o".to_owned());
+            ssh_options.push(format!("ConnectTimeout={}", timeout.as_secs().max(1)));
+        }
+
+        // unix sockets aren't supported by the Windows ssh client
+        let shares_connection =
+            cfg!(unix) && paths.local_dir.is_none() && !has_control_options(&ssh_options);
+        // a socket of its own is opened and closed by this run, a shared one is left to the
+        // first connection through it, which outlives the run
+        let control_master =
+            shares_connection && config.ssh_control_path.is_none() && !config.no_control_master;
+        if control_master {
+            // the pid keeps concurrent runs for the same project apart
+            let socket = std::env::temp_dir().join(format!(
+                "cargo-remote-{}-{}.sock",
+                project_hash(project_dir),
+                std::process::id()
+            ));
+            ssh_options.extend(control_master_options(
+                &socket.to_string_lossy(),
+                CONTROL_PERSIST,
+            ));
+        } else if let Some(socket) = config
+            .ssh_control_path
+            .as_ref()
+            .filter(|_| shares_connection)
+        {
+            ssh_options.extend(control_master_options(socket, SHARED_CONTROL_PERSIST));
+        }
+        let transport = if paths.local_dir.is_some() {
+            Transport::Local
+        } else {
+            Transport::Ssh {
+                program: config.ssh_bin.clone().unwrap_or_default(),
+                server: paths.build_server.clone(),
+                options: ssh_options.clone(),
+            }
+        };
+        Connection {
+            transport,
+            ssh_options,
+            control_master,
+        }
+    }
+}
+
+/// The package of --package and the members it depends on, which are all that's synced.
+fn resolve_package_scope(
+    config: &RemoteConfig,
+    workspace: &Workspace,
+) -> Result<Option<package_scope::Scope>, Error> {
+    match &config.package {
+        Some(_) if !workspace.local_cargo => Err(Error::Project(
+            "--package needs the packages of the workspace, which can't be read without a local cargo"
+                .to_owned(),
+        )),
+        Some(name) => package_scope::resolve(&workspace.metadata, name).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The rsync filters of the sync, which starts at the sync root. [`scope`] leaves out the members
+/// the package doesn't depend on.
+fn upload_filters(
+    config: &RemoteConfig,
+    project_dir: &Path,
+    paths: &BuildPaths,
+    scope: Option<&package_scope::Scope>,
+    use_direnv: bool,
+) -> Vec<OsString> {
+    let hidden = config.hidden || !config.hidden_except.is_empty();
+    if hidden && !config.hidden_only.is_empty() {
+        warn!("Ignoring --transfer-hidden-only, all hidden files are transferred");
+    }
+    // the local target directory isn't uploaded, and the sync doesn't delete the remote one
+    let local_target_relative = paths
+        .local_target
+        .strip_prefix(project_dir)
+        .ok()
+        .map(local_shell::slashes);
+    let remote_target_relative = config
+        .remote_target_dir
+        .as_deref()
+        .filter(|dir| !dir.starts_with('/') && !dir.starts_with("~/"))
+        .map(|dir| {
+            dir.trim_start_matches("./")
+                .trim_end_matches('/')
+                .to_owned()
+        });
+    let target_dirs = local_target_relative
+        .into_iter()
+        .chain(remote_target_relative)
+        .filter(|dir| !dir.is_empty() && dir != "target")
+        .collect::<Vec<_>>();
+
+    let info_exclude = Some(project_dir.join(".git").join("info").join("exclude"))
+        .filter(|info_exclude| config.respect_gitignore && info_exclude.is_file())
+        .map(|info_exclude| local_shell::path(&info_exclude));
+    let filters = sync_filters(
+        &config.include,
+        &target_dirs,
+        scope.map_or(&[][..], |scope| scope.filters.as_slice()),
+        use_direnv,
+        config.transfer_cargo_config,
+        &config.exclude,
+        hidden,
+        &config.hidden_except,
+        &config.hidden_only,
+        config.respect_gitignore,
+        info_exclude,
+    );
+
+    // the filters are written relative to the workspace root, but apply to the sync root
+    let mut filters = paths.sync_root.anchor(filters);
+    filters.extend(paths.sync_root.filters.iter().cloned());
+    filters
+}
+
+/// `cargo remote config`: prints the effective values of the options that decide where and how a
+/// build runs, and where each comes from.
+fn show_config(mut config: RemoteConfig) -> Result<i32, Error> {
+    let workspace = Workspace::load(&config)?;
+    let fan_out_member = fan_out::current();
+    let origins = complete_config(
+        &mut config,
+        &workspace.named_configs,
+        fan_out_member.as_ref(),
+        None,
+    );
+    if let Some(members) = resolve_remotes(&mut config, &workspace, false, fan_out_member.as_ref())?
+    {
+        return fan_out::run(&members);
+    }
+    expand_placeholders(&mut config, &workspace.dir)?;
+    let pinned = toolchain::pinned(&workspace.dir);
+    let paths = BuildPaths::new(
+        &config,
+        &workspace,
+        pinned.as_ref(),
+        fan_out_member.as_ref(),
+    )?;
+
+    let toolchain = remote_toolchain(
+        config.rustup_default.clone(),
+        pinned.as_ref(),
+        config.docker_image.is_some(),
+    );
+    let (toolchain, toolchain_origin) = match (toolchain, &pinned) {
+        (Some(toolchain), _) => (toolchain, origins.rustup_default),
+        (None, Some(pinned)) => (pinned.channel.clone(), Origin::Default(pinned.file)),
+        (None, None) => (String::new(), Origin::Default("the Docker image")),
+    };
+    let list =
+        |values: &[String]| Value::Array(values.iter().cloned().map(Value::String).collect());
+    config_origin::print(&[
+        ("remote", Value::String(paths.build_server), &origins.remote),
+        ("build_env", list(&config.build_env), &origins.build_env),
+        (
+            "rustup_default",
+            Value::String(toolchain),
+            &toolchain_origin,
+        ),
+        ("env", list(&config.env), &origins.env),
+        (
+            "build_path",
+            Value::String(paths.build_path),
+            &origins.build_path,
+        ),
+    ]);
+    Ok(0)
+}
+
+/// `cargo remote which`: prints where the local path of [`query`] ends up on the build server.
+fn show_which(mut config: RemoteConfig, query: which::Query) -> Result<i32, Error> {
+    let workspace = Workspace::load(&config)?;
+    let fan_out_member = fan_out::current();
+    complete_config(
+        &mut config,
+        &workspace.named_configs,
+        fan_out_member.as_ref(),
+        None,
+    );
+    if let Some(members) = resolve_remotes(&mut config, &workspace, false, fan_out_member.as_ref())?
+    {
+        return fan_out::run(&members);
+    }
+    expand_placeholders(&mut config, &workspace.dir)?;
+    let pinned = toolchain::pinned(&workspace.dir);
+    let paths = BuildPaths::new(
+        &config,
+        &workspace,
+        pinned.as_ref(),
+        fan_out_member.as_ref(),
+    )?;
+    let connection = Connection::new(&config, &workspace.dir, &paths);
+
+    let package_scope = resolve_package_scope(&config, &workspace)?;
+    // nothing runs, so there is nothing to ask about
+    let use_direnv = config.use_direnv && workspace.dir.join(".envrc").is_file();
+    let filters = upload_filters(
+        &config,
+        &workspace.dir,
+        &paths,
+        package_scope.as_ref(),
+        use_direnv,
+    );
+    let layout = which::Layout {
+        project_dir: &workspace.dir,
+        sync_dir: &paths.sync_root.dir,
+        local_target: &paths.local_target,
+        build_path: &paths.build_path,
+        remote_target: &paths.remote_target,
+        target: config.target.as_deref(),
+        filters: &filters,
+        rsync_bin: config.rsync_bin.as_deref().unwrap_or_default(),
+    };
+    let _control_master = (connection.control_master && query.exists).then(|| {
+        ControlMaster::adopt(
+            config.ssh_bin.as_deref().unwrap_or_default(),
+            &connection.ssh_options,
+            &paths.build_server,
+        )
+    });
+    which::print(
+        &query,
+        &workspace.current_path,
+        &layout,
+        &connection.transport,
+    )
+    .map(|()| 0)
+}
+
+/// `cargo remote audit-log`: prints the commands of the last audited run.
+fn show_audit_log(mut config: RemoteConfig) -> Result<(), Error> {
+    let workspace = Workspace::load(&config)?;
+    complete_config(
+        &mut config,
+        &workspace.named_configs,
+        fan_out::current().as_ref(),
+        None,
+    );
+    let audit_log = config
+        .audit_log
+        .map(PathBuf::from)
+        .or_else(audit::default_path)
+        .ok_or_else(|| {
+            Error::Project("can't determine the data directory, set audit_log".to_owned())
+        })?;
+    audit::show(&audit_log).map_err(Error::Project)
+}
+
+/// Syncs the project to the build server, runs the cargo commands of [`steps`] there one after the
+/// other and transfers the results back. Returns the exit code of the first failing command.
+fn run_remote(
+    mut config: RemoteConfig,
+    flags: RunFlags,
+    mut steps: Vec<Step>,
+    mut report: Option<&mut BugReport>,
+) -> Result<i32, Error> {
+    let RunFlags {
+        dry_run,
+        force,
+        no_sync,
+        clean,
+        clean_only,
+        fresh,
+        quiet,
+        progress,
+        all_remotes,
+    } = flags;
+    let workspace = Workspace::load(&config)?;
+    let fan_out_member = fan_out::current();
+    // a target given to cargo wins, see below
+    let cargo_target = steps
+        .iter()
+        .find_map(|step| option_values(&step.options, "--target").last())
+        .map(str::to_owned);
+    complete_config(
+        &mut config,
+        &workspace.named_configs,
+        fan_out_member.as_ref(),
+        cargo_target.as_deref(),
+    );
+    let configs = workspace.configs();
+
+    // the credentials are only transferred without asking if a config file opts in
+    let credentials_confirmed = configs
+        .iter()
+        .any(|c| c.get("transfer_credentials").and_then(Value::as_bool) == Some(true));
+    // the same goes for running the code of an .envrc
+    let direnv_confirmed = configs
+        .iter()
+        .any(|c| c.get("use_direnv").and_then(Value::as_bool) == Some(true));
+    if config.audit && !dry_run {
+        match config
+            .audit_log
+            .clone()
+            .map(PathBuf::from)
+            .or_else(audit::default_path)
+        {
+            Some(audit_log) => audit::start(audit_log, &workspace.dir),
+            None => warn!("Can't determine the data directory, nothing is audited (set audit_log)"),
+        }
+    }
+
+    // the [webhook_headers] of the config with the highest precedence are sent, their values may
+    // name secrets in the environment
+    let mut webhook_headers = Vec::new();
+    if let Some(table) = configs
+        .iter()
+        .find_map(|c| c.get("webhook_headers")?.as_table())
+    {
+        for (name, value) in table {
+            match value.as_str() {
+                Some(value) => {
+                    let value = placeholders::expand(value, |name| std::env::var(name).ok())
+                        .map_err(|reason| Error::Placeholder {
+                            key: "webhook_headers",
+                            reason,
+                        })?;
+                    webhook_headers.push((name.clone(), value));
+                }
+                None => warn!(
+                    "Ignoring the [webhook_headers] entry {}: it isn't a string",
+                    name
+                ),
+            }
+        }
+    }
+    if let Some(members) = resolve_remotes(
+        &mut config,
+        &workspace,
+        all_remotes,
+        fan_out_member.as_ref(),
+    )? {
+        return fan_out::run(&members);
+    }
+    // the output of the processes of a fan-out is prefixed line by line, which progress bars
+    // don't survive
+    let quiet = quiet || fan_out_member.is_some();
+
+    // when cargo options control the same thing as cargo-remote options, the cargo options win
+    let settings = conflicts::Settings {
+        target: config.target.as_deref(),
+        build_env: &config.build_env,
+        copies_back: !config.copy_back.is_empty() || config.copy_back_bins,
+        force_tty: config.force_tty,
+    };
+    let found = conflicts::report(
+        &settings,
+        steps.iter().map(|step| step.options.as_slice()),
+        config.strict_flags,
+    )?;
+    if let Some(target) = config.target.clone() {
+        let cargo_target = steps.iter().find_map(|step| {
+            option_values(&step.options, "--target")
+                .last()
+                .filter(|t| *t != target)
+        });
+        if let Some(cargo_target) = cargo_target {
+            config.target = Some(cargo_target.to_owned());
+        }
+    }
+    if found.iter().any(|c| c.ours == "--force-tty") {
+        config.force_tty = false;
+    }
+
+    // cargo-remote's --target is passed on to cargo, one given to cargo is understood as well
+    match &config.target {
+        Some(target) => {
+            // commands like fmt don't know the option
+            let building = steps.iter_mut().filter(|step| {
+                !NO_ARTIFACT_COMMANDS.contains(&step.command.as_str())
+                    && option_values(&step.options, "--target").next().is_none()
+            });
+            for step in building {
+                let end = step
+                    .options
+                    .iter()
+                    .position(|o| o == "--")
+                    .unwrap_or(step.options.len());
+                step.options
+                    .splice(end..end, ["--target".to_owned(), target.clone()]);
+            }
+        }
+        None => {
+            config.target = steps
+                .iter()
+                .find_map(|step| option_values(&step.options, "--target").last())
+                .map(str::to_owned)
+        }
+    }
+    expand_placeholders(&mut config, &workspace.dir)?;
+    if let Some(report) = report.as_deref_mut() {
+        // the config holds only the names of forwarded variables, never their values
+        report.set("config.txt", format!("{:#?}\n", config));
+        report.set(
+            "versions.txt",
+            bug_report::local_versions(
+                config.ssh_bin.as_deref().unwrap_or("ssh"),
+                config.rsync_bin.as_deref().unwrap_or("rsync"),
+            ),
+        );
+    }
+    let pinned = toolchain::pinned(&workspace.dir);
+    let paths = BuildPaths::new(
+        &config,
+        &workspace,
+        pinned.as_ref(),
+        fan_out_member.as_ref(),
+    )?;
+    let Connection {
+        transport,
+        ssh_options,
+        control_master,
+    } = Connection::new(&config, &workspace.dir, &paths);
+    let ssh_bin = config.ssh_bin.clone().unwrap_or_default();
+
+    audit::set_transport(&transport);
+
+    if clean_only {
+        let clean = clean_command(&transport, &paths.sync_path)?;
+        if dry_run {
+            println!("{}", command_line(&clean));
+        } else {
+            let _control_master = control_master
+                .then(|| ControlMaster::adopt(&ssh_bin, &ssh_options, &paths.build_server));
+            clean_remote_dir(clean, &transport, &paths.sync_path)?;
+        }
+        return Ok(0);
+    }
+
+    // only the package and the members it depends on are synced, and cargo is pointed at it
+    let package_scope = resolve_package_scope(&config, &workspace)?;
+    // direnv needs the .envrc on the build server
+    let use_direnv = config.use_direnv
+        && if !workspace.dir.join(".envrc").is_file() {
+            warn!("Not using direnv, the project has no .envrc");
+            false
+        } else if dry_run
+            || direnv_confirmed
+            || interactive::confirm("Allow the .envrc to run on the build server?")
+        {
+            true
+        } else {
+            info!("Not using direnv (set use_direnv = true in a config to skip the question)");
+            false
+        };
+    let filters = upload_filters(
+        &config,
+        &workspace.dir,
+        &paths,
+        package_scope.as_ref(),
+        use_direnv,
+    );
+
+    let Workspace {
+        current_path,
+        manifest_path,
+        local_cargo,
+        metadata: project_metadata,
+        dir: project_dir,
+        named_configs: _,
+    } = workspace;
+    let BuildPaths {
+        sync_root,
+        project_subdir,
+        build_server,
+        local_dir: _,
+        build_base: _,
+        sync_path,
+        build_path,
+        legacy_build_paths,
+        remote_target,
+        local_target,
+    } = paths;
+    let RemoteConfig {
+        remote: _,
+        build_env,
+        forward_env,
+        pre_hooks,
+        post_hooks,
+        rustup_default,
+        install_toolchain,
+        docker_image,
+        target,
+        no_rustup_target_add,
+        package,
+        sync_parent: _,
+        env,
+        build_path: _,
+        build_path_no_hash: _,
+        remote_target_dir,
+        isolate: _,
+        isolate_toolchain: _,
+        migrate_remote_dir,
+        copy_back,
+        no_copy_lock,
+        sync_back_files,
+        copy_back_on_failure,
+        copy_back_bins,
+        copy_back_isolate,
+        doc_crate,
+        collect_on_failure,
+        no_build_script_logs,
+        fetch,
+        no_hygiene_check,
+        hidden,
+        hidden_except,
+        hidden_only: _,
+        transfer_cargo_config: _,
+        transfer_credentials,
+        sync_advisory_db,
+        use_direnv: _,
+        include: _,
+        exclude: _,
+        respect_gitignore: _,
+        no_delete,
+        skip_unchanged,
+        remote_clean_shell,
+        remote_shell,
+        ssh_opts: _,
+        jump_host: _,
+        ssh_bin: _,
+        rsync_bin,
+        local_shell: _,
+        connect_timeout: _,
+        skip_probe,
+        no_control_master: _,
+        ssh_control_path: _,
+        retries,
+        bwlimit,
+        no_compress,
+        no_partial,
+        force_tty,
+        non_interactive,
+        no_local_cargo: _,
+        no_pty_bridge,
+        no_stale_lock_recovery,
+        stale_lock_grace,
+        coalesce,
+        no_space_monitor,
+        space_floor,
+        space_monitor_interval,
+        max_remote_target_size,
+        regression_threshold,
+        stats,
+        strict_flags: _,
+        no_config_warnings: _,
+        audit: _,
+        audit_log: _,
+        webhook: webhook_url,
+        webhook_on,
+        sync_timeout,
+        build_timeout,
+        copy_back_timeout,
+    } = config;
+    let toolchain = remote_toolchain(rustup_default, pinned.as_ref(), docker_image.is_some());
+    let remote_shell = remote_shell.unwrap_or_default();
+    let rsync_bin = rsync_bin.unwrap_or_default();
+    // a browser on the build server is of no use, the documentation is opened locally instead
+    let open_docs = docs::take_open(&mut steps);
+    let copy_back = match &target {
+        Some(target) => copy_back
+            .into_iter()
+            .map(|path| target_artifact_path(target, path))
+            .collect(),
+        None => copy_back,
+    };
+
+    if let Some(name) = &package {
+        let selecting = steps.iter_mut().filter(|step| {
+            PACKAGE_COMMANDS.contains(&step.command.as_str())
+                && option_values(&step.options, "--package").next().is_none()
+                && option_values(&step.options, "-p").next().is_none()
+        });
+        for step in selecting {
+            let end = step
+                .options
+                .iter()
+                .position(|o| o == "--")
+                .unwrap_or(step.options.len());
+            step.options
+                .splice(end..end, ["-p".to_owned(), name.clone()]);
+        }
+    }
+
+    let project_hash = project_hash(&project_dir);
+    let retries = retries.unwrap_or(0);
+    let clean = if clean {
+        Some(clean_command(&transport, &sync_path)?)
+    } else {
+        None
+    };
+    // the command of the first step, clap requires it without --clean-only
+    let options = &steps[0].options;
+    let output_sensitive = steps.iter().any(|step| is_output_sensitive(&step.options));
+    let produces_artifacts = steps
+        .iter()
+        .any(|step| !NO_ARTIFACT_COMMANDS.contains(&step.command.as_str()));
+    let modifies_lock = steps
+        .iter()
+        .any(|step| LOCK_MODIFYING_COMMANDS.contains(&step.command.as_str()));
+    let offline = conflicts::offline(steps.iter().map(|step| &step.options[..]), &build_env);
+    if let (Some(offline), true) = (offline, modifies_lock && !no_copy_lock) {
+        info!(
+            "cargo runs with {}, so Cargo.lock isn't transferred back",
+            offline
+        );
+    }
+    let modifies_lock = modifies_lock && offline.is_none();
+    // the processes of a fan-out would race for the one Cargo.lock and the other files
+    let sync_back_files = if fan_out_member.is_some() {
+        Vec::new()
+    } else {
+        sync_back::files(&sync_back_files, no_copy_lock, modifies_lock)
+    };
+    let command = steps
+        .iter()
+        .map(|step| step.command.as_str())
+        .collect::<Vec<_>>()
+        .join("+");
+
+    let current_relative_path = match &package_scope {
+        Some(scope) => project_relative_dir(&project_dir, &scope.dir)?,
+        None => project_relative_dir(&project_dir, &current_path)?,
+    };
+
+    // keep rsync's progress out of machine readable output
+    let progress_to_stderr = output_sensitive;
+
+    // progress redraws a line with \r, which only makes sense on a terminal
+    let mut transfer_options = Vec::new();
+    let terminal = if progress_to_stderr {
+        interactive::stderr()
+    } else {
+        interactive::stdout()
+    };
+    // --progress can't help a fan-out, whose output is prefixed line by line
+    if (progress && fan_out_member.is_none()) || (!quiet && terminal) {
+        transfer_options.push(PROGRESS_FLAG.to_owned());
+    }
+    if !no_compress {
+        transfer_options.push("--compress".to_owned());
+    }
+    if let Some(limit) = bwlimit {
+        transfer_options.push(format!("--bwlimit={}", limit));
+    }
+    if !no_partial {
+        transfer_options.push("--partial".to_owned());
+        transfer_options.push(format!("--partial-dir={}", PARTIAL_DIR));
+    }
+
+    // transfer project to build server
+    let origin = local_origin(&project_dir);
+    if let Some(url) = webhook_url.filter(|_| !dry_run) {
+        webhook::arm(
+            url,
+            webhook_headers,
+            webhook_on.unwrap_or(WebhookOn::Always),
+            &project_dir,
+            &build_server,
+            &command,
+        );
+    }
+
+    let sync_setup = format!(
+        "mkdir -p {path} && printf '%s' {marker} > {path}{file}",
+        path = shell_quote_path(&build_path),
+        marker = shell_quote(&origin_marker(&origin)),
+        file = ORIGIN_MARKER
+    );
+    let mut rsync_to = rsync(&rsync_bin, &transport, &transfer_options);
+    if !no_delete {
+        rsync_to.arg("--delete");
+    }
+    rsync_to
+        .args(&filters)
+        .args(transport.rsync_setup_args(&sync_setup))
+        .arg(local_shell::dir(&sync_root.dir))
+        .arg(transport.location(&sync_path))
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit());
+
+    // the values may be secrets, so only their names are ever shown
+    let forwarded = forwarded_env(&forward_env)?;
+    audit::redact(
+        forwarded
+            .iter()
+            .filter_map(|assignment| assignment.split_once('=').map(|(_, value)| value)),
+    );
+    let forwarded_names = forwarded
+        .iter()
+        .map(|assignment| assignment.split('=').next().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let redacted = forwarded_names
+        .iter()
+        .map(|name| format!("{}=<redacted>", name))
+        .collect::<Vec<_>>();
+
+    // cargo doesn't see a terminal through the ssh pipe, so its colors are forced unless a program
+    // reads the output or the colors were chosen explicitly
+    let mut build_env = build_env;
+    let color_chosen = build_env
+        .iter()
+        .chain(&forwarded)
+        .any(|assignment| assignment.starts_with("CARGO_TERM_COLOR="))
+        || steps
+            .iter()
+            .any(|step| option_values(&step.options, "--color").next().is_some());
+    if !color_chosen && !output_sensitive && interactive::stderr() {
+        build_env.insert(0, "CARGO_TERM_COLOR=always".to_owned());
+    }
+
+    info!("Build ENV: {:?}", build_env);
+    info!("Forwarded ENV: {:?}", forwarded_names);
+    info!("Environment profile: {:?}", env);
+    info!("Build path: {:?}", build_path);
+    info!("Sub directory: {:?}", current_relative_path);
+    if remote_clean_shell || output_sensitive {
+        info!("Using a clean remote shell.");
+    }
+    let hooks = Hooks {
+        pre: pre_hooks,
+        post: post_hooks,
+    };
+    let pid_file = format!("{}{}", shell_quote_path(&build_path), PID_FILE);
+    // a TTY merges the remote stdout and stderr, so only allocate one if nobody can tell
+    let tty: &[&str] = if force_tty && !non_interactive {
+        &["-tt"]
+    } else if interactive::all() {
+        &["-t"]
+    } else {
+        &[]
+    };
+    // in a container the directory of the sync root is mounted at DOCKER_WORKDIR, and a target
+    // directory outside of it at DOCKER_TARGET_DIR
+    let docker_relative_path = match (project_subdir.as_str(), current_relative_path.as_str()) {
+        ("", relative) => relative.to_owned(),
+        (_, ".") => sync_root.project.clone(),
+        (subdir, relative) => format!("{}{}", subdir, relative),
+    };
+    let (command_env, command_build_path, command_target) = match &docker_image {
+        Some(_) => (
+            &[][..],
+            format!("{}{}", DOCKER_WORKDIR, project_subdir),
+            remote_target_dir.as_ref().map(|_| {
+                match remote_target.strip_prefix(sync_path.as_str()) {
+                    Some(relative) => format!("{}{}", DOCKER_WORKDIR, relative),
+                    None => DOCKER_TARGET_DIR.to_owned(),
+                }
+            }),
+        ),
+        None => (
+            &env[..],
+            build_path.clone(),
+            remote_target_dir.as_ref().map(|_| remote_target.clone()),
+        ),
+    };
+    // built with the real values to run it, and with redacted ones to show it
+    let remote_command = |assignments: &[String]| {
+        let build_command = build_remote_command(
+            command_env,
+            toolchain.as_deref(),
+            target.as_deref().filter(|_| !no_rustup_target_add),
+            &command_build_path,
+            &current_relative_path,
+            command_target.as_deref(),
+            &[&build_env[..], assignments].concat(),
+            &hooks,
+            use_direnv,
+            &steps,
+        );
+        let build_command = match &docker_image {
+            Some(image) => docker_command(
+                image,
+                &sync_path,
+                &docker_relative_path,
+                Some(remote_target.as_str())
+                    .filter(|_| command_target.as_deref() == Some(DOCKER_TARGET_DIR)),
+                !tty.is_empty(),
+                &build_command,
+            ),
+            None => build_command,
+        };
+        // removed in the same session, so --fresh costs no extra connection
+        let build_command = if fresh {
+            format!(
+                "rm -rf {}; {}",
+                shell_quote_path(&remote_target),
+                build_command
+            )
+        } else {
+            build_command
+        };
+        let build_command = if build_timeout.is_some() || !no_stale_lock_recovery {
+            // record the pid so the remote command can be killed if it times out or waits for a
+            // stale lock, and keep the previous one to tell whether a lock is stale
+            format!(
+                "mv -f {pid} {pid}{last} 2>/dev/null; echo $$ > {pid}; {cmd}",
+                pid = pid_file,
+                last = LAST_PID_SUFFIX,
+                cmd = build_command
+            )
+        } else {
+            build_command
+        };
+        if remote_clean_shell || output_sensitive {
+            clean_shell_command(&build_command)
+        } else {
+            remote_shell_command(&remote_shell, &build_command)
+        }
+    };
+
+    let mut ssh = transport.shell_with(
+        tty,
+        &remote_command(if dry_run { &redacted } else { &forwarded }),
+    );
+    ssh.stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit());
+
+    let copies_artifacts = !copy_back.is_empty() || copy_back_bins;
+    // the transfers back are assembled up front, so a dry run shows them as well
+    let mut copy_back_transfers = Vec::new();
+    for file_name in copy_back {
+        let destination = local_target.join(&file_name);
+        let (local_dir, fetched, destination) = if is_glob(&file_name) {
+            // the matches are placed next to each other in the directory of the pattern
+            let dir = destination.parent().unwrap_or(&project_dir);
+            (dir.to_owned(), dir.to_owned(), local_shell::dir(dir))
+        } else {
+            let parent = destination.parent().unwrap_or(&project_dir);
+            (
+                parent.to_owned(),
+                destination.clone(),
+                local_shell::path(&destination),
+            )
+        };
+        let mut copy_back_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        if !is_glob(&file_name) {
+            copy_back_rsync.arg("--delete");
+            for exclude in local_only_files() {
+                copy_back_rsync.arg("--exclude").arg(exclude);
+            }
+        }
+        copy_back_rsync
+            .arg(transport.location(&format!("{}/{}", remote_target, file_name)))
+            .arg(&destination)
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        copy_back_transfers.push(CopyBackTransfer {
+            description: copy_back_description(&file_name),
+            local_dir,
+            fetched: vec![fetched],
+            rsync: copy_back_rsync,
+        });
+    }
+
+    if copy_back_bins && !local_cargo {
+        warn!("Not transferring the binaries back, they can't be told without a local cargo");
+    }
+    if copy_back_bins && local_cargo {
+        let profile = match &target {
+            Some(target) => target_artifact_path(target, profile_dir(options)),
+            None => profile_dir(options),
+        };
+        let bins: Vec<&str> = project_metadata
+            .packages
+            .iter()
+            .flat_map(|p| p.targets.iter())
+            .filter(|t| t.kind.iter().any(|k| k == "bin"))
+            .map(|t| t.name.as_str())
+            .collect();
+        let destination = local_target.join(&profile);
+        let mut bins_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        bins_rsync
+            .arg("--ignore-missing-args")
+            .args(
+                bins.iter().map(|bin| {
+                    transport.location(&format!("{}/{}/{}", remote_target, profile, bin))
+                }),
+            )
+            .arg(local_shell::dir(&destination))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        copy_back_transfers.push(CopyBackTransfer {
+            description: format!("{} binaries ({})", profile, bins.join(", ")),
+            fetched: bins.iter().map(|bin| destination.join(bin)).collect(),
+            local_dir: destination,
+            rsync: bins_rsync,
+        });
+    }
+
+    let local_docs = if open_docs {
+        let doc_dir = match &target {
+            Some(target) => target_artifact_path(target, "doc".to_owned()),
+            None => "doc".to_owned(),
+        };
+        let local_docs = local_target.join(&doc_dir);
+        let remote_docs = format!("{}/{}/", remote_target, doc_dir);
+        let mut docs_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        match &doc_crate {
+            Some(name) => {
+                // the `/./` marks where the path reproduced by --relative starts
+                docs_rsync
+                    .arg("--relative")
+                    .arg("--ignore-missing-args")
+                    .args(
+                        docs::crate_files(&name.replace('-', "_"))
+                            .iter()
+                            .map(|file| transport.location(&format!("{}./{}", remote_docs, file))),
+                    );
+            }
+            None => {
+                docs_rsync
+                    .arg("--delete")
+                    .arg(transport.location(&remote_docs));
+            }
+        }
+        docs_rsync
+            .arg(local_shell::dir(&local_docs))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        copy_back_transfers.push(CopyBackTransfer {
+            description: "the documentation".to_owned(),
+            local_dir: local_docs.clone(),
+            fetched: vec![local_docs.clone()],
+            rsync: docs_rsync,
+        });
+        Some(local_docs)
+    } else {
+        None
+    };
+
+    // all --fetch paths in one transfer, the `/./` marks where the path reproduced by --relative
+    // starts, and the remote rsync expands the globs
+    let fetch_rsync = if fetch.is_empty() {
+        None
+    } else {
+        let mut fetch_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        fetch_rsync
+            .arg("--relative")
+            .args(fetch.iter().map(|path| {
+                transport.location(&format!("{}./{}", build_path, path.trim_start_matches('/')))
+            }))
+            .arg(local_shell::dir(&project_dir))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        Some(fetch_rsync)
+    };
+
+    let mut sync_back_files: Vec<SyncBackFile> = sync_back_files
+        .iter()
+        .map(|path| {
+            let mut rsync = rsync(&rsync_bin, &transport, &transfer_options);
+            rsync.stderr(Stdio::inherit()).stdin(Stdio::inherit());
+            SyncBackFile::new(path, &project_dir, &build_path, rsync, |path| {
+                transport.location(path)
+            })
+        })
+        .collect();
+
+    let mut advisory_dbs = if sync_advisory_db {
+        advisory_db::Transfers::new(&rsync_bin, &transport)
+    } else {
+        None
+    };
+
+    let mut target_gc = max_remote_target_size
+        .map(|limit| target_gc::command(&transport, &shell_quote_path(&remote_target), limit));
+
+    let mut commands = Vec::new();
+    if let Some(clean) = &clean {
+        commands.push(command_line(clean));
+    }
+    let sync_command = commands.len();
+    commands.push(command_line(&rsync_to));
+    if let Some(upload) = advisory_dbs.as_ref().and_then(|t| t.upload.as_ref()) {
+        commands.push(command_line(upload));
+    }
+    commands.push(command_line(
+        &transport.shell_with(tty, &remote_command(&redacted)),
+    ));
+    if produces_artifacts {
+        commands.extend(copy_back_transfers.iter().map(|t| command_line(&t.rsync)));
+    }
+    if let Some(fetch_rsync) = &fetch_rsync {
+        commands.push(command_line(fetch_rsync));
+    }
+    if let Some(advisory_dbs) = &advisory_dbs {
+        commands.push(command_line(&advisory_dbs.download));
+    }
+    for file in &sync_back_files {
+        commands.push(command_line(&file.rsync));
+    }
+    if let Some(target_gc) = &target_gc {
+        commands.push(command_line(target_gc));
+    }
+    if let Some(report) = report.as_deref_mut() {
+        report.set("commands.txt", commands.join("\n") + "\n");
+    }
+    if dry_run {
+        if !produces_artifacts && !copy_back_transfers.is_empty() {
+            skipping_copy_back(&command);
+        }
+        for command in commands {
+            println!("{}", command);
+        }
+        return Ok(0);
+    }
+
+    // held until the run ends
+    let _turn = match coalesce {
+        Some(window) => {
+            let steps = steps
+                .iter()
+                .map(|step| shell_words::join(std::iter::once(&step.command).chain(&step.options)))
+                .collect::<Vec<_>>()
+                .join(" && ");
+            let turn = coalesce::enter(
+                &local_target,
+                &steps,
+                Duration::from_millis(window),
+                &INTERRUPTED,
+            )
+            .map_err(|e| Error::Project(format!("can't take turns with other runs: {}", e)))?;
+            match turn {
+                coalesce::Turn::Run(lock) => Some(lock),
+                coalesce::Turn::Coalesced => {
+                    if !quiet {
+                        eprintln!(
+                            "A run of '{}' is already waiting and will include these changes",
+                            steps
+                        );
+                    }
+                    return Ok(0);
+                }
+                coalesce::Turn::Interrupted => return Err(Error::Interrupted { phase: "wait" }),
+            }
+        }
+        None => None,
+    };
+
+    let _control_master =
+        control_master.then(|| ControlMaster::open(&ssh_bin, &ssh_options, &build_server));
+
+    // a password prompt can't be answered without a terminal, so ssh shouldn't wait for one
+    let remote_host = if skip_probe {
+        None
+    } else {
+        probe_build_server(
+            &transport,
+            &env,
+            docker_image.is_some(),
+            !interactive::stdin(),
+        )?
+    };
+    // artifacts for another host in the same profile directory confuse cargo's fingerprints, with
+    // --target they go to a directory of their own
+    if copies_artifacts && target.is_none() && !copy_back_isolate && fan_out_member.is_none() {
+        let local_host = host_triple::of_builds_in(&local_target);
+        if let (Some(local_host), Some(remote_host)) = (local_host, &remote_host) {
+            if local_host != *remote_host {
+                warn!(
+                    "{} holds local builds for {}, the artifacts copied back are built for {} and \
+                     mix with them, which causes rebuilds and invalid metadata errors; pass \
+                     --copy-back-isolate to copy them into {} instead",
+                    local_target.to_string_lossy(),
+                    local_host,
+                    remote_host,
+                    local_target.join(ISOLATED_TARGET).to_string_lossy()
+                );
+            }
+        }
+    }
+
+    if let Some(report) = report.as_deref_mut() {
+        report.set(
+            "remote.txt",
+            bug_report::remote_host_info(&transport, &quiet_sources(&env)),
+        );
+    }
+
+    if use_direnv {
+        check_direnv(&transport, &env)?;
+    }
+
+    // stable is there on every build server set up with rustup's defaults, and the toolchains of
+    // the build server don't matter in a container
+    match (&toolchain, &pinned) {
+        _ if docker_image.is_some() => {}
+        (Some(toolchain), _) if toolchain != "stable" => {
+            check_toolchain(&transport, &env, toolchain, install_toolchain, &[], offline)?
+        }
+        (None, Some(pinned)) if pinned.channel != "stable" => check_toolchain(
+            &transport,
+            &env,
+            &pinned.channel,
+            install_toolchain,
+            &pinned.install_args(),
+            offline,
+        )?,
+        _ => {}
+    }
+
+    if let (true, Some(offline)) = (transfer_credentials, offline) {
+        info!(
+            "Not transferring the credentials, cargo runs with {} and doesn't reach a registry",
+            offline
+        );
+    } else if transfer_credentials {
+        warn!(
+            "This copies your cargo registry tokens to '{}'",
+            build_server
+        );
+        if transport.is_local() {
+            info!("Not transferring the credentials, the local build uses them already");
+        } else if credentials_confirmed || interactive::confirm("Transfer the credentials?") {
+            copy_credentials(&transport);
+        } else {
+            info!("Not transferring the credentials (set transfer_credentials = true in a config to skip the question)");
+        }
+    }
+
+    let cleaned = clean.is_some();
+    if let Some(clean) = clean {
+        clean_remote_dir(clean, &transport, &sync_path)?;
+    } else if !legacy_build_paths.is_empty() {
+        migrate_build_dir(
+            &transport,
+            &build_path,
+            &legacy_build_paths,
+            migrate_remote_dir,
+        );
+    }
+
+    let last_sync = if no_sync {
+        None
+    } else {
+        read_remote_origin(&transport, &build_path)
+    };
+    if let Some((last_origin, synced_at)) = &last_sync {
+        if *last_origin != origin {
+            warn!(
+                "The remote build directory was last synced from '{}' ({}), \
+                 syncing will delete files that don't exist locally",
+                last_origin,
+                format_elapsed(*synced_at)
+            );
+            if !force && !interactive::confirm("Sync anyway?") {
+                return Err(Error::SyncAborted);
+            }
+        }
+    }
+
+    let mut phase_stats = PhaseStats {
+        enabled: stats,
+        ..PhaseStats::default()
+    };
+    let mut sync_time = Duration::from_secs(0);
+    let mut synced_bytes = 0;
+    log_format::enter(LogPhase::Upload);
+    let state_remote = transport.location(&build_path);
+    // the remote directory may have been wiped or overwritten since the sources were recorded
+    let remote_unchanged = !cleaned
+        && last_sync
+            .as_ref()
+            .is_some_and(|(last_origin, _)| *last_origin == origin);
+    let digest = if skip_unchanged && !no_sync {
+        let skipped_dirs = [
+            local_target.clone(),
+            project_dir.join(skip_unchanged::STATE_DIR),
+        ];
+        // the history is only synced with all hidden files
+        let skipped_names: &[&str] = if hidden && hidden_except.is_empty() {
+            &["target", PARTIAL_DIR]
+        } else {
+            &["target", PARTIAL_DIR, ".git"]
+        };
+        let skipped = skip_unchanged::Skipped {
+            dirs: &skipped_dirs,
+            names: skipped_names,
+        };
+        skip_unchanged::digest(&sync_root.dirs, &skipped, &filters)
+    } else {
+        None
+    };
+    if cleaned && skip_unchanged {
+        skip_unchanged::record(&project_dir, &state_remote, None);
+    }
+    let sources_unchanged = digest.as_deref().is_some_and(|digest| {
+        remote_unchanged && skip_unchanged::unchanged(&project_dir, &state_remote, digest)
+    });
+    if no_sync {
+        info!("Skipping the transfer of the sources.");
+    } else if sources_unchanged {
+        info!(
+            "The sources didn't change since the last sync to this build server, not syncing them."
+        );
+    } else {
+        let state_path = local_target.join(STATE_FILE);
+        let files = list_files(&rsync_bin, &sync_root.dir, &filters);
+        let changes = files.as_ref().and_then(|files| {
+            SyncState::load(&state_path)
+                .filter(|state| remote_unchanged && state.remote == state_remote)
+                .map(|state| state.changes(files))
+        });
+
+        let sync = Phase::start("sync", sync_timeout);
+        log_format::milestone("sync_started", "Starting the sync.", json!({}));
+        if transport.is_local() {
+            let status = sync.run(&mut transport.shell(&sync_setup), Error::Sync)?;
+            if !status.success() {
+                return Err(remote_error(
+                    "preparing the build directory",
+                    status,
+                    Error::Sync,
+                ));
+            }
+        }
+        let synced = match changes {
+            Some(changes) if changes.is_empty() => {
+                info!("No files changed since the last sync, skipping the transfer.");
+                true
+            }
+            Some(changes) if changes.len() <= INCREMENTAL_SYNC_MAX_FILES => {
+                info!(
+                    "Transferring {} changed files to build server.",
+                    changes.len()
+                );
+                let list_path = local_target.join(".cargo-remote-files");
+                let list = changes
+                    .modified
+                    .iter()
+                    .chain(&changes.deleted)
+                    .map(|file| format!("{}\n", file))
+                    .collect::<String>();
+                std::fs::write(&list_path, list).map_err(|e| {
+                    Error::Sync(format!(
+                        "can't write '{}': {}",
+                        list_path.to_string_lossy(),
+                        e
+                    ))
+                })?;
+                let mut rsync_changes = rsync(&rsync_bin, &transport, &transfer_options);
+                rsync_changes
+                    .arg("--files-from")
+                    .arg(local_shell::path(&list_path))
+                    .arg(if no_delete {
+                        "--ignore-missing-args"
+                    } else {
+                        "--delete-missing-args"
+                    })
+                    .args(transport.rsync_setup_args(&sync_setup))
+                    .arg(local_shell::dir(&sync_root.dir))
+                    .arg(transport.location(&sync_path))
+                    .stderr(Stdio::inherit())
+                    .stdin(Stdio::inherit());
+                if let Some(report) = report.as_deref_mut() {
+                    commands[sync_command] = command_line(&rsync_changes);
+                    report.set("commands.txt", commands.join("\n") + "\n");
+                }
+                let transfer = sync.run_transfer_retrying(
+                    &mut rsync_changes,
+                    Error::Sync,
+                    progress_to_stderr,
+                    retries,
+                );
+                let _ = std::fs::remove_file(&list_path);
+                let (status, stats) = transfer?;
+                synced_bytes = stats.sent;
+                upload_succeeded(status)?
+            }
+            _ => {
+                info!("Transferring sources to build server.");
+                let (status, stats) = sync.run_transfer_retrying(
+                    &mut rsync_to,
+                    Error::Sync,
+                    progress_to_stderr,
+                    retries,
+                )?;
+                synced_bytes = stats.sent;
+                upload_succeeded(status)?
+            }
+        };
+        sync_time = sync.elapsed();
+        phase_stats.sync = Some((sync_time, synced_bytes));
+        webhook::phase_finished("sync", sync_time);
+        log_format::milestone(
+            "sync_finished",
+            &format!(
+                "Synced the sources in {} ({} sent).",
+                format_duration(sync_time),
+                format_size(synced_bytes)
+            ),
+            json!({
+                "duration_ms": sync_time.as_millis() as u64,
+                "bytes": synced_bytes,
+            }),
+        );
+        if let Some(report) = report.as_deref_mut() {
+            report.set("stats.txt", phase_stats.summary() + "\n");
+        }
+
+        if let (Some(digest), true) = (&digest, synced) {
+            skip_unchanged::record(&project_dir, &state_remote, Some(digest));
+        }
+        if let Some(files) = files.filter(|_| synced) {
+            let state = SyncState {
+                remote: state_remote,
+                files,
+            };
+            state.save(&state_path);
+        }
+    }
+
+    // remote paths in cargo's JSON messages are useless to IDEs, so point them to the local files
+    let json_remap = if output_sensitive {
+        let remote_dir = remote_absolute_path(&transport, &build_path);
+        if remote_dir.is_none() {
+            warn!("Failed to resolve the remote build directory, JSON paths are not rewritten");
+        }
+        remote_dir
+    } else {
+        None
+    };
+
+    if let Some(advisory_dbs) = &mut advisory_dbs {
+        advisory_dbs.upload();
+    }
+
+    log_format::enter(LogPhase::Build);
+    log_format::milestone("build_started", "Starting build process.", json!({}));
+    let output_tail = report.as_deref().map(BugReport::output_tail);
+    let build = Phase::start("build", build_timeout);
+    let local_dir = project_dir
+        .to_string_lossy()
+        .trim_end_matches('/')
+        .to_owned();
+    // the build is restarted at most once, a lock that turns stale again needs a closer look
+    let mut recover_stale_locks = !no_stale_lock_recovery;
+    // the fullest the remote filesystem got below the --space-floor
+    let mut space_usage: Option<space_monitor::Usage> = None;
+    // the build script directories named in the output of the last attempt
+    let mut build_script_hints;
+    let mut connection_attempt = 0;
+    let status = loop {
+        let lock_watch = if recover_stale_locks {
+            Some(Arc::new(LockWatch::default()))
+        } else {
+            None
+        };
+        let finished = Arc::new(AtomicBool::new(false));
+        let monitor = lock_watch.clone().map(|watch| {
+            monitor_stale_locks(
+                watch,
+                finished.clone(),
+                stale_lock_grace.unwrap_or_default(),
+                transport.clone(),
+                remote_target.clone(),
+                pid_file.clone(),
+            )
+        });
+        let space_monitor = if no_space_monitor {
+            None
+        } else {
+            Some(space_monitor::monitor(
+                transport.clone(),
+                shell_quote_path(&remote_target),
+                shell_quote_path(&build_path),
+                space_monitor_interval.unwrap_or_default(),
+                space_floor.unwrap_or_default(),
+                finished.clone(),
+            ))
+        };
+        build_script_hints = if no_build_script_logs {
+            None
+        } else {
+            Some(Arc::new(build_script_logs::Hints::default()))
+        };
+        let remote_output = if connection_attempt < retries {
+            Some(Arc::new(RemoteOutput::default()))
+        } else {
+            None
+        };
+        let observers = Observers {
+            lock_watch,
+            tail: output_tail.clone(),
+            build_scripts: build_script_hints.clone(),
+            remote_output: remote_output.clone(),
+        };
+        let status = if json_remap.is_none() && observers.is_empty() {
+            build.run(&mut ssh, Error::Build)
+        } else {
+            let json_remap = json_remap.clone();
+            let local_dir = local_dir.clone();
+            build.run_filtered(
+                &mut ssh,
+                Error::Build,
+                !no_pty_bridge,
+                observers,
+                move |line| remap_json_line(line, json_remap.as_ref()?, &local_dir),
+            )
+        };
+        finished.store(true, Ordering::SeqCst);
+        let restart = monitor.is_some_and(|monitor| monitor.join().unwrap_or(false));
+        if let Some(usage) = space_monitor.and_then(|monitor| monitor.join().ok().flatten()) {
+            if space_usage.is_none_or(|u| usage.available < u.available) {
+                space_usage = Some(usage);
+            }
+        }
+        let status = status.map_err(|e| {
+            if let Error::Timeout { .. } = e {
+                kill_remote_command(&transport, &build_path);
+            }
+            e
+        })?;
+        if let Some(remote_output) = remote_output.filter(|output| {
+            !restart && !status.success() && retry::is_connection_failure(status, output)
+        }) {
+            drop(remote_output);
+            connection_attempt += 1;
+            let delay = retry::backoff(connection_attempt);
+            warn!(
+                "The connection to the build server failed ({}), retrying the remote command in \
+                 {} (attempt {} of {})",
+                status,
+                format_duration(delay),
+                connection_attempt,
+                retries
+            );
+            std::thread::sleep(delay);
+            // the command may still be running if only the connection dropped
+            kill_remote_command(&transport, &build_path);
+            continue;
+        }
+        if !restart {
+            break status;
+        }
+        kill_remote_command(&transport, &build_path);
+        warn!("Restarting the build after removing the stale lock");
+        recover_stale_locks = false;
+    };
+
+    // the databases were likely updated even if the audit found something
+    if let Some(advisory_dbs) = &mut advisory_dbs {
+        advisory_dbs.download();
+    }
+
+    let build_succeeded = status.success();
+    if let (false, Some(usage)) = (build_succeeded, space_usage) {
+        error!(
+            "The remote filesystem hit {}% capacity during the build, which is the likely cause \
+             of the failure",
+            usage.capacity
+        );
+    }
+    let copy_back_allowed = build_succeeded || copy_back_on_failure;
+    if !copy_back_allowed
+        && (!copy_back_transfers.is_empty() || fetch_rsync.is_some() || !sync_back_files.is_empty())
+    {
+        warn!(
+            "Remote command failed, skipping transfers back to the local machine \
+             (use --copy-back-on-failure to transfer anyway)"
+        );
+    }
+
+    if !produces_artifacts && copy_back_allowed && !copy_back_transfers.is_empty() {
+        skipping_copy_back(&command);
+    }
+
+    let build_time = build.elapsed();
+    phase_stats.build = Some(build_time);
+    webhook::phase_finished("build", build_time);
+    log_format::milestone(
+        "build_finished",
+        &format!(
+            "The remote command finished in {} ({}).",
+            format_duration(build_time),
+            status
+        ),
+        json!({
+            "duration_ms": build_time.as_millis() as u64,
+            "exit_code": status.code(),
+        }),
+    );
+
+    log_format::enter(LogPhase::CopyBack);
+    let copy_back_phase = Phase::start("copy-back", copy_back_timeout);
+    let mut fetched_bytes = 0;
+    // where the artifacts landed locally, for the hygiene check
+    let mut fetched_paths: Vec<PathBuf> = Vec::new();
+    if !build_succeeded && !collect_on_failure.is_empty() {
+        let destination = local_target.join(format!(
+            "remote-failure-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        ));
+        create_local_dir(&destination)?;
+        let sources = collect_on_failure.iter().map(|pattern| {
+            if pattern.starts_with('/') {
+                transport.location(pattern)
+            } else {
+                // the `/./` marks where the path reproduced by --relative starts
+                transport.location(&format!("{}./{}", build_path, pattern))
+            }
+        });
+        let mut collect_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        collect_rsync
+            .arg("--relative")
+            .arg("--ignore-missing-args")
+            .args(sources)
+            .arg(local_shell::dir(&destination))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        let (collected, stats) = copy_back_phase.run_transfer(
+            &mut collect_rsync,
+            Error::CopyBack,
+            progress_to_stderr,
+        )?;
+        fetched_bytes += stats.received;
+        fetched_paths.push(destination.clone());
+        // a failed collection must not hide the failure of the remote command itself
+        if collected.success() {
+            warn!(
+                "Collected failure artifacts into {}",
+                destination.to_string_lossy()
+            );
+        } else {
+            warn!("Failed to collect some failure artifacts from the build server");
+        }
+    }
+
+    let build_script_dirs = build_script_hints
+        .filter(|_| !build_succeeded)
+        .map(|hints| hints.dirs())
+        .unwrap_or_default();
+    if !build_script_dirs.is_empty() {
+        let destination = local_target.join(build_script_logs::LOCAL_DIR);
+        create_local_dir(&destination)?;
+        let mut sources = Vec::new();
+        for dir in &build_script_dirs {
+            for file in build_script_logs::FILES {
+                // the `/./` marks where the path reproduced by --relative starts
+                let path = format!("{}/./{}/{}", dir.parent, dir.name, file);
+                sources.push(transport.location(&match &docker_image {
+                    Some(_) => docker_host_path(&path, &sync_path, &remote_target),
+                    None => path,
+                }));
+            }
+        }
+        let mut logs_rsync = rsync(&rsync_bin, &transport, &transfer_options);
+        logs_rsync
+            .arg("--relative")
+            .arg("--ignore-missing-args")
+            .arg(format!("--max-size={}", build_script_logs::MAX_FILE_SIZE))
+            .args(sources)
+            .arg(local_shell::dir(&destination))
+            .stderr(Stdio::inherit())
+            .stdin(Stdio::inherit());
+        let (fetched, stats) =
+            copy_back_phase.run_transfer(&mut logs_rsync, Error::CopyBack, progress_to_stderr)?;
+        fetched_bytes += stats.received;
+        if !fetched.success() {
+            warn!("Failed to fetch some build script output from the build server");
+        }
+        for dir in &build_script_dirs {
+            for file in build_script_logs::FILES {
+                let local = destination.join(&dir.name).join(file);
+                if local.is_file() {
+                    warn!(
+                        "See the build script output {}/{}/{} at {}",
+                        dir.parent,
+                        dir.name,
+                        file,
+                        local.to_string_lossy()
+                    );
+                }
+            }
+        }
+    }
+
+    if copy_back_allowed && produces_artifacts {
+        for mut transfer in copy_back_transfers {
+            info!("Transferring {} back to client.", transfer.description);
+            create_local_dir(&transfer.local_dir)?;
+            let (status, stats) = copy_back_phase.run_transfer(
+                &mut transfer.rsync,
+                Error::CopyBack,
+                progress_to_stderr,
+            )?;
+            fetched_bytes += stats.received;
+            if !status.success() {
+                return Err(rsync_error(status, Error::CopyBack));
+            }
+            fetched_paths.extend(transfer.fetched);
+        }
+    }
+
+    // the fetched paths were asked for by name, so they are transferred for any command
+    if let Some(mut fetch_rsync) = fetch_rsync.filter(|_| copy_back_allowed) {
+        info!("Transferring {} back to client.", fetch.join(", "));
+        let (status, stats) =
+            copy_back_phase.run_transfer(&mut fetch_rsync, Error::CopyBack, progress_to_stderr)?;
+        fetched_bytes += stats.received;
+        if !status.success() {
+            warn!(
+                "Failed to fetch some paths from the build server: {}",
+                rsync_error(status, Error::CopyBack)
+            );
+        }
+    }
+
+    if let (true, Some(local_docs)) = (build_succeeded, &local_docs) {
+        let crate_name = doc_crate
+            .map(|name| name.replace('-', "_"))
+            .or_else(|| docs::crate_name(&project_metadata, &manifest_path, &steps));
+        match crate_name {
+            Some(name) => docs::open(&local_docs.join(name).join("index.html")),
+            None => warn!("Can't tell which crate's documentation to open, pass --doc-crate"),
+        }
+    }
+
+    if !no_copy_lock && copy_back_allowed && !modifies_lock && offline.is_none() {
+        info!(
+            "'cargo {}' doesn't modify Cargo.lock, skipping its transfer.",
+            command
+        );
+    }
+
+    for file in sync_back_files.iter_mut().filter(|_| copy_back_allowed) {
+        info!("Transferring {} back to client.", file.path);
+        let on_error = file.error();
+        let transfer = copy_back_phase.run_transfer(&mut file.rsync, on_error, progress_to_stderr);
+        let installed = transfer.and_then(|(status, stats)| {
+            fetched_bytes += stats.received;
+            if !status.success() {
+                return Err(rsync_error(status, on_error));
+            }
+            file.install()
+        });
+        if installed.is_err() {
+            let _ = std::fs::remove_file(&file.fetched);
+        }
+        match installed? {
+            Installed::Missing => info!(
+                "There is no {} on the build server, nothing was transferred.",
+                file.path
+            ),
+            Installed::Unchanged => info!("{} didn't change.", file.path),
+            Installed::Replaced => {}
+        }
+    }
+
+    let copy_back_time = copy_back_phase.elapsed();
+    phase_stats.copy_back = Some((copy_back_time, fetched_bytes));
+    webhook::phase_finished("copy_back", copy_back_time);
+    webhook::artifacts(&fetched_paths);
+    log_format::milestone(
+        "copy_back_finished",
+        &format!(
+            "Finished the transfers back in {} ({} received).",
+            format_duration(copy_back_time),
+            format_size(fetched_bytes)
+        ),
+        json!({
+            "duration_ms": copy_back_time.as_millis() as u64,
+            "bytes": fetched_bytes,
+        }),
+    );
+    if let Some(report) = report {
+        report.set("stats.txt", phase_stats.summary() + "\n");
+    }
+
+    if !no_hygiene_check && !fetched_paths.is_empty() {
+        hygiene::check(&project_dir, &fetched_paths);
+    }
+
+    // after the copy-back, which doesn't need the incremental sessions
+    if let (true, Some(target_gc)) = (build_succeeded, &mut target_gc) {
+        target_gc::run(target_gc, max_remote_target_size.unwrap_or_default(), quiet);
+    }
+
+    // the builds of a fan-out ran on different machines, so they're no baseline for each other
+    if fan_out_member.is_none() {
+        let run = Run {
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            command: command.clone(),
+            success: build_succeeded,
+            sync: sync_time,
+            build: build_time,
+            copy_back: copy_back_time,
+            synced: synced_bytes,
+            fetched: fetched_bytes,
+        };
+        let runs = history::load(project_hash);
+        // compare with the same command, a check is no baseline for a release build
+        let previous = runs
+            .iter()
+            .rev()
+            .find(|r| r.success && r.command == run.command);
+        let (summary, regression) =
+            history::summary(&run, previous, regression_threshold.unwrap_or_default());
+        if !quiet {
+            eprintln!("{}", summary);
+        }
+        if regression && build_succeeded {
+            warn!(
+                "The build took more than {}% longer than the last run, the remote target directory may need a sweep",
+                regression_threshold.unwrap_or_default()
+            );
+        }
+        history::record(project_hash, &run);
+    }
+
+    if build_succeeded {
+        Ok(0)
+    } else {
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(command: &str, options: &[&str]) -> Step {
+        Step {
+            command: command.to_owned(),
+            options: options.iter().map(|o| (*o).to_owned()).collect(),
+        }
+    }
+
+    fn no_hooks() -> Hooks {
+        Hooks {
+            pre: Vec::new(),
+            post: Vec::new(),
+        }
+    }
+
+    /// The remote command of one cargo step with the default env file and build path.
+    fn remote_command(relative_path: &str, build_env: &[&str], step: Step) -> String {
+        let build_env: Vec<String> = build_env.iter().map(|e| (*e).to_owned()).collect();
+        build_remote_command(
+            &["~/.cargo/env".to_owned()],
+            None,
+            None,
+            "~/remote-builds/1/",
+            relative_path,
+            None,
+            &build_env,
+            &no_hooks(),
+            false,
+            &[step],
+        )
+    }
+
+    /// The words of [`command`] after the commands that enter the build directory and [`cd`], as
+    /// the remote shell sees them.
+    fn cargo_words(command: &str, cd: &str) -> Vec<String> {
+        let prefix = format!(". ~/.cargo/env; cd ~/remote-builds/1/; {}", cd);
+        shell_words::split(command.strip_prefix(&prefix).unwrap()).unwrap()
+    }
+
+    /// The build servers and the remote command of `cargo remote` with [`args`], as a shell
+    /// passes them.
+    fn passed_through(args: &[&str]) -> (Vec<String>, String) {
+        let argv = ["cargo", "remote"].iter().chain(args).map(OsString::from);
+        match parse_args(argv.collect()) {
+            Opts::Remote {
+                config,
+                command: Some(command),
+                options,
+                ..
+            } => (
+                config.remote,
+                remote_command(".", &[], Step { command, options }),
+            ),
+            _ => panic!("not a cargo command: {:?}", args),
+        }
+    }
+
+    #[test]
+    fn cargo_options_pass_through() {
+        let prefix = ". ~/.cargo/env; cd ~/remote-builds/1/; ";
+        let (remote, command) = passed_through(&["-r", "host", "clippy", "--", "-D", "warnings"]);
+        assert_eq!(remote, ["host"]);
+        assert_eq!(command, format!("{}cargo clippy -- -D warnings", prefix));
+
+        let (remote, command) = passed_through(&["build", "--features", "a b"]);
+        assert!(remote.is_empty());
+        assert_eq!(command, format!("{}cargo build --features 'a b'", prefix));
+
+        let (_, command) = passed_through(&["nextest", "run"]);
+        assert_eq!(command, format!("{}cargo nextest run", prefix));
+    }
+
+    #[test]
+    fn bare_copy_back_before_the_command() {
+        let parsed = |args: &[&str]| {
+            let argv = ["cargo", "remote"].iter().chain(args).map(OsString::from);
+            match parse_args(argv.collect()) {
+                Opts::Remote {
+                    config,
+                    command: Some(command),
+                    ..
+                } => (config.copy_back, command),
+                _ => panic!("not a cargo command: {:?}", args),
+            }
+        };
+        assert_eq!(
+            parsed(&["-c", "clean"]),
+            (strings(&[""]), "clean".to_owned())
+        );
+        assert_eq!(
+            parsed(&["--copy-back", "build", "--release"]),
+            (strings(&[""]), "build".to_owned())
+        );
+        assert_eq!(
+            parsed(&["-c", "debug", "build"]),
+            (strings(&["debug"]), "build".to_owned())
+        );
+        assert_eq!(
+            parsed(&["-c", "-r", "host", "build"]),
+            (strings(&[""]), "build".to_owned())
+        );
+    }
+
+    #[test]
+    fn remote_command_quotes_spaces() {
+        let command = remote_command(".", &[], step("build", &["--features", "foo bar"]));
+        assert_eq!(
+            command,
+            ". ~/.cargo/env; cd ~/remote-builds/1/; cargo build --features 'foo bar'"
+        );
+        assert_eq!(
+            cargo_words(&command, ""),
+            ["cargo", "build", "--features", "foo bar"]
+        );
+    }
+
+    #[test]
+    fn remote_command_quotes_quotes() {
+        let env = "CC=clang -O2 CFLAGS=\"-march=native\"";
+        let command = remote_command("it's", &[env], step("run", &["--", "don't", "\"x\""]));
+        assert_eq!(
+            command,
+            ". ~/.cargo/env; cd ~/remote-builds/1/; cd 'it'\\''s'; \
+             CC='clang -O2 CFLAGS=\"-march=native\"' cargo run -- 'don'\\''t' '\"x\"'"
+        );
+        assert_eq!(
+            cargo_words(&command, "cd 'it'\\''s'; "),
+            [env, "cargo", "run", "--", "don't", "\"x\""]
+        );
+    }
+
+    #[test]
+    fn remote_command_quotes_dollars_and_semicolons() {
+        let command = remote_command(
+            "a;b",
+            &["TOKEN=$SECRET", "PATH=$(pwd);x"],
+            step("test", &["$HOME", "x; rm -rf ~", "`id`"]),
+        );
+        assert_eq!(
+            command,
+            ". ~/.cargo/env; cd ~/remote-builds/1/; cd 'a;b'; \
+             TOKEN='$SECRET' PATH='$(pwd);x' cargo test '$HOME' 'x; rm -rf ~' '`id`'"
+        );
+        assert_eq!(
+            cargo_words(&command, "cd 'a;b'; "),
+            [
+                "TOKEN=$SECRET",
+                "PATH=$(pwd);x",
+                "cargo",
+                "test",
+                "$HOME",
+                "x; rm -rf ~",
+                "`id`"
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_command_keeps_home_unquoted() {
+        let command = build_remote_command(
+            &["~/my env".to_owned(), "/opt/env; x".to_owned()],
+            Some("nightly"),
+            None,
+            "~/remote builds/1/",
+            ".",
+            Some("~/target dir"),
+            &[],
+            &no_hooks(),
+            false,
+            &[step("check", &[])],
+        );
+        assert_eq!(
+            command,
+            ". ~/'my env'; . '/opt/env; x'; export CARGO_TARGET_DIR=~/'target dir'; \
+             cd ~/'remote builds/1/'; cargo +nightly check"
+        );
+    }
+
+    fn strings(dirs: &[&str]) -> Vec<String> {
+        dirs.iter().map(|d| (*d).to_owned()).collect()
+    }
+
+    #[test]
+    fn legacy_dirs_of_each_scheme() {
+        let project_dir = Path::new("/home/me/project");
+        let scheme_2 = format!("~/remote-builds/{}/", project_hash(project_dir));
+        let scheme_1 = legacy_project_dirs("~/remote-builds", project_dir, Isolation::None);
+        // without isolation the directory is still named by the project hash alone
+        assert_eq!(scheme_1.len(), 1);
+        assert_ne!(scheme_1[0], scheme_2);
+        assert!(scheme_1[0].starts_with("~/remote-builds/") && scheme_1[0].ends_with('/'));
+        for isolation in [Isolation::Host, Isolation::Branch] {
+            assert_eq!(
+                legacy_project_dirs("~/remote-builds", project_dir, isolation),
+                [scheme_2.clone(), scheme_1[0].clone()]
+            );
+        }
+    }
+
+    #[test]
+    fn migrates_the_newest_legacy_dir() {
+        let legacy = strings(&["~/b/scheme-2/", "~/b/scheme-1/"]);
+        let target = "~/b/scheme-3/";
+        assert_eq!(migration_source(&[], target, &legacy), None);
+        assert_eq!(
+            migration_source(&strings(&["~/b/scheme-1/"]), target, &legacy),
+            Some("~/b/scheme-1/")
+        );
+        assert_eq!(
+            migration_source(&strings(&["~/b/scheme-2/"]), target, &legacy),
+            Some("~/b/scheme-2/")
+        );
+        assert_eq!(
+            migration_source(
+                &strings(&["~/b/scheme-1/", "~/b/scheme-2/"]),
+                target,
+                &legacy
+            ),
+            Some("~/b/scheme-2/")
+        );
+        assert_eq!(
+            migration_source(&strings(&["~/b/other/"]), target, &legacy),
+            None
+        );
+    }
+
+    #[test]
+    fn doesnt_migrate_over_an_existing_dir() {
+        let legacy = strings(&["~/b/scheme-2/", "~/b/scheme-1/"]);
+        let target = "~/b/scheme-3/";
+        assert_eq!(migration_source(&strings(&[target]), target, &legacy), None);
+        assert_eq!(
+            migration_source(&strings(&["~/b/scheme-2/", target]), target, &legacy),
+            None
+        );
+        assert_eq!(
+            migration_source(
+                &strings(&["~/b/scheme-1/", target, "~/b/scheme-2/"]),
+                target,
+                &legacy
+            ),
+            None
+        );
+    }
+
+    /// The filters of the sync after cargo-remote's own excludes.
+    #[allow(clippy::too_many_arguments)]
+    fn filters(
+        include: &[&str],
+        target_dirs: &[&str],
+        use_direnv: bool,
+        transfer_cargo_config: bool,
+        exclude: &[&str],
+        hidden: bool,
+        hidden_except: &[&str],
+        hidden_only: &[&str],
+        gitignore: Option<Option<&str>>,
+    ) -> Vec<String> {
+        let filters = sync_filters(
+            &strings(include),
+            &strings(target_dirs),
+            &["--include".into(), "/member/***".into()],
+            use_direnv,
+            transfer_cargo_config,
+            &strings(exclude),
+            hidden,
+            &strings(hidden_except),
+            &strings(hidden_only),
+            gitignore.is_some(),
+            gitignore.flatten().map(String::from),
+        );
+        let own = [
+            "--exclude",
+            "/.cargo-remote-origin",
+            "--exclude",
+            "/.cargo-remote-pid*",
+            "--exclude",
+            ".cargo-remote-partial/",
+            "--exclude",
+            "/.cargo-remote/",
+        ];
+        let filters: Vec<String> = filters
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(filters[..own.len()], own);
+        filters[own.len()..].to_vec()
+    }
+
+    #[test]
+    fn default_filters() {
+        assert_eq!(
+            filters(&[], &[], false, false, &[], false, &[], &[], None),
+            [
+                "--exclude",
+                "target",
+                "--include",
+                "/member/***",
+                "--exclude",
+                ".*"
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_in_order_of_precedence() {
+        assert_eq!(
+            filters(
+                &["target/keep"],
+                &["build/out"],
+                true,
+                true,
+                &["*.log"],
+                false,
+                &[],
+                &[".env"],
+                Some(Some("/p/.git/info/exclude")),
+            ),
+            [
+                "--include",
+                "target/keep",
+                "--exclude",
+                "target",
+                "--exclude",
+                "/build/out/",
+                "--include",
+                "/member/***",
+                "--include=/.envrc",
+                "--include=/.cargo/",
+                "--include=/.cargo/config.toml",
+                "--include=/.cargo/config",
+                "--exclude=/.cargo/*",
+                "--exclude",
+                "*.log",
+                "--include",
+                ".env",
+                "--exclude",
+                ".*",
+                "--filter=:- .gitignore",
+                "--exclude-from",
+                "/p/.git/info/exclude",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_with_hidden_files() {
+        // everything hidden is transferred, so the .envrc and cargo config need no rules
+        assert_eq!(
+            filters(&[], &[], true, true, &[], true, &[], &[], Some(None)),
+            [
+                "--exclude",
+                "target",
+                "--include",
+                "/member/***",
+                "--filter=:- .gitignore"
+            ]
+        );
+        assert_eq!(
+            filters(&[], &[], true, true, &[], true, &[".idea"], &[], None),
+            [
+                "--exclude",
+                "target",
+                "--include",
+                "/member/***",
+                "--exclude",
+                ".idea",
+                "--exclude",
+                ".git"
+            ]
+        );
+    }
+
+    #[test]
+    fn quiet_sources_run_in_a_posix_shell() {
+        let dir = std::env::temp_dir().join(format!("cargo-remote-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let profile = dir.join("my env");
+        std::fs::write(&profile, "FOO=sourced\n").unwrap();
+        let env = [
+            dir.join("missing").to_string_lossy().into_owned(),
+            profile.to_string_lossy().into_owned(),
+        ];
+        let sources = quiet_sources(&env);
+        assert!(!sources.contains("source "), "{}", sources);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{}echo \"$FOO\"", sources))
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "sourced\n");
+        assert!(
+            output.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[test]
+    fn directories_relative_to_the_workspace() {
+        let dir = std::env::temp_dir().join(format!("cargo-remote-dirs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (workspace, outside) = (dir.join("ws"), dir.join("other"));
+        std::fs::create_dir_all(workspace.join("crates/a b")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        let relative = |dir: &Path| project_relative_dir(&workspace, dir).unwrap();
+        let cases = [
+            relative(&workspace),
+            relative(&workspace.join("crates/a b")),
+            relative(&workspace.join("crates/./a b/..")),
+            // not below the workspace root, the remote command runs in it
+            relative(&outside),
+            relative(&dir),
+            relative_path(&workspace, &outside).unwrap(),
+        ];
+        let missing = project_relative_dir(&workspace, &dir.join("missing"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(cases, [".", "crates/a b", "crates", ".", ".", "../other"]);
+        assert!(matches!(missing, Err(Error::RelativePath(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_relative_to_a_workspace_behind_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("cargo-remote-link-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let workspace = dir.join("real/ws");
+        std::fs::create_dir_all(workspace.join("crates/a")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let linked = dir.join("link/ws");
+        let cases = [
+            // the link in the cwd but not in the root cargo metadata reports, or the other way
+            project_relative_dir(&workspace, &linked.join("crates/a")).unwrap(),
+            project_relative_dir(&linked, &workspace.join("crates/a")).unwrap(),
+            project_relative_dir(&linked, &linked.join("crates")).unwrap(),
+            project_relative_dir(&linked, &workspace).unwrap(),
+        ];
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(cases, ["crates/a", "crates/a", "crates", "."]);
+    }
+
+    /// The build server comes from `--remote`, else `CARGO_REMOTE_HOST`, else the `[targets]`
+    /// table for the target, else the project config, else the global config.
+    #[test]
+    fn remote_precedence() {
+        let named = |project: &str, global: &str| {
+            vec![
+                ("project".to_owned(), project.parse::<Value>().unwrap()),
+                ("global".to_owned(), global.parse::<Value>().unwrap()),
+            ]
+        };
+        let remote = |args: &[&str],
+                      named_configs: &[(String, Value)],
+                      member: Option<&fan_out::Member>,
+                      cargo_target: Option<&str>| {
+            let argv = std::iter::once("cargo-remote").chain(args.iter().copied());
+            let mut config = RemoteConfig::from_iter(argv);
+            let origins = complete_config(&mut config, named_configs, member, cargo_target);
+            (config.remote, origins.remote.to_string())
+        };
+        let targets = "[targets]\naarch64-unknown-linux-musl = \"arm\"";
+        let all = named(
+            &format!("remote = \"project\"\n{}", targets),
+            "remote = \"global\"",
+        );
+        let cross = ["--target", "aarch64-unknown-linux-musl"];
+        let member = fan_out::Member {
+            alias: "b".to_owned(),
+            server: "member".to_owned(),
+        };
+
+        std::env::set_var("CARGO_REMOTE_HOST", "env");
+        let from_flag = remote(&["-r", "flag", cross[0], cross[1]], &all, None, None);
+        let from_env = remote(&cross, &all, None, None);
+        let from_fan_out = remote(&cross, &all, Some(&member), None);
+        std::env::remove_var("CARGO_REMOTE_HOST");
+        assert_eq!(from_flag, (strings(&["flag"]), "command line".to_owned()));
+        assert_eq!(
+            from_env,
+            (
+                strings(&["env"]),
+                "environment variable CARGO_REMOTE_HOST".to_owned()
+            )
+        );
+        assert_eq!(from_fan_out.0, ["member"]);
+
+        let from_targets = (strings(&["arm"]), "[targets] of project".to_owned());
+        assert_eq!(remote(&cross, &all, None, None), from_targets);
+        // a target given to cargo selects the entry as well
+        assert_eq!(remote(&[], &all, None, Some(cross[1])), from_targets);
+        assert_eq!(
+            remote(&cross, &all, None, Some("x86_64-unknown-linux-gnu")),
+            (strings(&["project"]), "project".to_owned())
+        );
+        assert_eq!(remote(&[], &all, None, None).0, ["project"]);
+        assert_eq!(
+            remote(&[], &named("", "remote = \"global\""), None, None),
+            (strings(&["global"]), "global".to_owned())
+        );
+        assert_eq!(remote(&cross, &named("", targets), None, None).0, ["arm"]);
+        let target_in_config = named(&format!("target = \"{}\"", cross[1]), targets);
+        assert_eq!(
+            remote(&[], &target_in_config, None, None),
+            (strings(&["arm"]), "[targets] of global".to_owned())
+        );
+    }
+
+    #[test]
+    fn quiet_and_debug() {
+        // --quiet, --debug, the cargo options and the level cargo-remote logs at
+        let cases: &[(bool, bool, &[&str], LevelFilter)] = &[
+            (false, false, &[], LevelFilter::Warn),
+            (false, false, &["-q"], LevelFilter::Error),
+            (false, false, &["--quiet"], LevelFilter::Error),
+            (false, false, &["--verbose"], LevelFilter::Warn),
+            (false, false, &["-v"], LevelFilter::Warn),
+            (false, false, &["--", "-q"], LevelFilter::Warn),
+            (true, false, &[], LevelFilter::Error),
+            (true, false, &["-q"], LevelFilter::Error),
+            (true, false, &["--verbose"], LevelFilter::Error),
+            (false, true, &[], LevelFilter::Trace),
+            (false, true, &["-q"], LevelFilter::Trace),
+            (true, true, &[], LevelFilter::Trace),
+            (true, true, &["-q"], LevelFilter::Trace),
+        ];
+        for (quiet, debug, options, level) in cases {
+            let steps = [step("build", options)];
+            let run_quiet = run_is_quiet(*quiet, *debug, &steps);
+            assert_eq!(
+                log_level(*debug, run_quiet, LogFormat::Text),
+                *level,
+                "--quiet {} --debug {} {:?}",
+                quiet,
+                debug,
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn quiet_step_quiets_the_run() {
+        let steps = [step("build", &[]), step("test", &["-q"])];
+        assert!(run_is_quiet(false, false, &steps));
+        assert!(!run_is_quiet(false, true, &steps));
+        // the JSON milestones stay unless cargo-remote is quiet
+        assert_eq!(log_level(false, false, LogFormat::Json), LevelFilter::Info);
+        assert_eq!(log_level(false, true, LogFormat::Json), LevelFilter::Error);
+    }
+
+    #[test]
+    fn json_output_is_detected() {
+        let sensitive = |options: &[&str]| is_output_sensitive(&strings(options));
+        assert!(sensitive(&["--message-format=json"]));
+        assert!(sensitive(&["--message-format", "json"]));
+        assert!(sensitive(&[
+            "--message-format=json-diagnostic-rendered-ansi"
+        ]));
+        assert!(sensitive(&[
+            "--release",
+            "--message-format",
+            "json-render-diagnostics"
+        ]));
+        assert!(!sensitive(&[]));
+        assert!(!sensitive(&["--message-format=short"]));
+        // the options of the program that cargo runs
+        assert!(!sensitive(&["--", "--message-format=json"]));
+    }
+
+    const REMOTE_DIR: &str = "/home/b/remote-builds/42";
+    const LOCAL_DIR: &str = "/home/me/project";
+
+    fn remap(message: serde_json::Value) -> serde_json::Value {
+        let line = remap_json_line(&format!("{}\n", message), REMOTE_DIR, LOCAL_DIR).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn json_paths_point_to_the_local_project() {
+        let artifact = remap(json!({
+            "reason": "compiler-artifact",
+            "manifest_path": "/home/b/remote-builds/42/member/Cargo.toml",
+            "target": {"src_path": "/home/b/remote-builds/42/member/src/main.rs"},
+            "filenames": ["/home/b/remote-builds/42/target/debug/app"],
+            "executable": "/home/b/remote-builds/42/target/debug/app",
+        }));
+        assert_eq!(
+            artifact,
+            json!({
+                "reason": "compiler-artifact",
+                "manifest_path": "/home/me/project/member/Cargo.toml",
+                "target": {"src_path": "/home/me/project/member/src/main.rs"},
+                "filenames": ["/home/me/project/target/debug/app"],
+                "executable": "/home/me/project/target/debug/app",
+            })
+        );
+
+        let diagnostic = remap(json!({
+            "reason": "compiler-message",
+            "manifest_path": "/home/b/remote-builds/42/Cargo.toml",
+            "message": {
+                "spans": [{"file_name": "/home/b/remote-builds/42/build.rs", "line_start": 3}],
+                "rendered": "warning: unused\n --> /home/b/remote-builds/42/build.rs:3:9\n",
+            },
+        }));
+        assert_eq!(
+            diagnostic,
+            json!({
+                "reason": "compiler-message",
+                "manifest_path": "/home/me/project/Cargo.toml",
+                "message": {
+                    "spans": [{"file_name": "/home/me/project/build.rs", "line_start": 3}],
+                    "rendered": "warning: unused\n --> /home/me/project/build.rs:3:9\n",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn json_paths_elsewhere_are_kept() {
+        let message = json!({
+            "file_name": "src/main.rs",
+            "sibling": "/home/b/remote-builds/421/src/main.rs",
+            "dependency": "/home/b/.cargo/registry/src/serde-1.0.0/src/lib.rs",
+            "line": 42,
+        });
+        assert_eq!(remap(message.clone()), message);
+        assert_eq!(
+            remap(json!({"workspace_root": "/home/b/remote-builds/42"})),
+            json!({"workspace_root": "/home/me/project"})
+        );
+    }
+
+    #[test]
+    fn other_output_is_forwarded_as_is() {
+        for line in [
+            "   Compiling app v0.1.0 (/home/b/remote-builds/42)\n",
+            "\n",
+            "{oops\n",
+        ] {
+            assert_eq!(remap_json_line(line, REMOTE_DIR, LOCAL_DIR), None);
+        }
     }
 }
@@ -0,0 +1,107 @@
+//! `--package`: syncing a workspace package with the members it depends on, instead of the whole
+//! workspace. Cargo loads the manifest of every member, so the other members are reduced to their
+//! manifests and target root files rather than left out.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::{Metadata, Package};
+use log::warn;
+
+use crate::error::Error;
+use crate::local_shell;
+
+/// What is synced for a package.
+pub struct Scope {
+    /// The directory of the package's manifest.
+    pub dir: PathBuf,
+    /// rsync filters that leave out the sources of the members the package doesn't depend on.
+    pub filters: Vec<OsString>,
+}
+
+/// Finds the workspace package [`name`] and the members it depends on through path dependencies
+/// in [`metadata`], which lists only the workspace members. If a path dependency isn't a member,
+/// the whole workspace is synced.
+pub fn resolve(metadata: &Metadata, name: &str) -> Result<Scope, Error> {
+    let member = |name: &str| metadata.packages.iter().find(|p| p.name == name);
+    let package = member(name)
+        .ok_or_else(|| Error::Project(format!("the workspace has no package named '{}'", name)))?;
+    let dir = manifest_dir(package).to_owned();
+
+    let mut needed = vec![package];
+    let mut queue = vec![package];
+    while let Some(current) = queue.pop() {
+        // dependencies from a registry or git have a source, path dependencies don't
+        for dependency in current.dependencies.iter().filter(|d| d.source.is_none()) {
+            match member(&dependency.name) {
+                Some(dependency) if needed.iter().all(|n| n.id != dependency.id) => {
+                    needed.push(dependency);
+                    queue.push(dependency);
+                }
+                Some(_) => {}
+                None => {
+                    warn!(
+                        "The path dependency '{}' of '{}' isn't a workspace member, syncing the whole workspace",
+                        dependency.name, current.name
+                    );
+                    return Ok(Scope {
+                        dir,
+                        filters: Vec::new(),
+                    });
+                }
+            }
+        }
+    }
+
+    let root = &metadata.workspace_root;
+    let needed_dirs: Vec<&Path> = needed.iter().map(|p| manifest_dir(p)).collect();
+    let mut filters = Vec::new();
+    for other in metadata
+        .packages
+        .iter()
+        .filter(|p| needed.iter().all(|n| n.id != p.id))
+    {
+        let other_dir = manifest_dir(other);
+        // a member containing a needed one is synced in full, like the workspace root package
+        if other_dir == root || needed_dirs.iter().any(|d| d.starts_with(other_dir)) {
+            continue;
+        }
+        let relative = match other_dir.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let files = std::iter::once(other.manifest_path.as_path())
+            .chain(other.targets.iter().map(|t| t.src_path.as_path()))
+            .filter_map(|file| file.strip_prefix(other_dir).ok());
+        let mut includes = BTreeSet::new();
+        for file in files {
+            // rsync doesn't descend into excluded directories, so the ones in between are included
+            let mut path = relative.to_owned();
+            let mut components = file.components().peekable();
+            while let Some(component) = components.next() {
+                path.push(component);
+                let pattern = format!("/{}", local_shell::slashes(&path));
+                includes.insert(if components.peek().is_some() {
+                    pattern + "/"
+                } else {
+                    pattern
+                });
+            }
+        }
+        for include in includes {
+            filters.push("--include".into());
+            filters.push(include.into());
+        }
+        filters.push("--exclude".into());
+        filters.push(format!("/{}/**", local_shell::slashes(relative)).into());
+    }
+    Ok(Scope { dir, filters })
+}
+
+fn manifest_dir(package: &Package) -> &Path {
+    package
+        .manifest_path
+        .parent()
+        .unwrap_or(&package.manifest_path)
+}
@@ -0,0 +1,40 @@
+//! `${NAME}` placeholders in config values like `remote = "ci@builder-pr-${CI_MR_IID}.internal"`,
+//! expanded on the local machine before anything is run.
+
+/// Replaces every `${NAME}` in [`text`] with the value [`lookup`] returns for `NAME`. `$${` stands
+/// for a literal `${`, so hooks can still use variables of the remote shell. Returns why the
+/// expansion failed, e.g. the name of a variable that isn't set.
+pub fn expand(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("the '${{' at '{}' isn't closed", &rest[start..]))?;
+        let name = &rest[start + 2..start + end];
+        let value =
+            lookup(name).ok_or_else(|| format!("the variable '{}' isn't set locally", name))?;
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Turns a git branch name into something usable in host names and paths, e.g. `feature/Foo_1`
+/// into `feature-foo-1`.
+pub fn slug(branch: &str) -> String {
+    branch
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
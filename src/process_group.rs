@@ -0,0 +1,142 @@
+//! The commands of a phase with a timeout run in a process group of their own, so the timeout
+//! kills everything they started, like the ssh that rsync runs, instead of leaving it behind. While
+//! such a group runs, it owns the terminal, so ssh can still ask for a password and Ctrl-C reaches
+//! all of its processes. Windows has no process groups, there the whole process tree is ended with
+//! `taskkill` and the console delivers Ctrl-C to every process attached to it anyway.
+
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus};
+
+/// Makes [`cmd`] start a process group of its own, led by the process it spawns.
+#[cfg(unix)]
+pub fn isolate(cmd: &mut Command) {
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+pub fn isolate(_cmd: &mut Command) {}
+
+/// Sends [`signal`] to the process [`pid`], and to all processes of its group if it leads one.
+#[cfg(unix)]
+fn signal(pid: u32, signal: libc::c_int) {
+    let pid = pid as libc::pid_t;
+    // SAFETY: getpgid and kill have no memory safety requirements, at worst the pid is gone
+    unsafe {
+        let target = if libc::getpgid(pid) == pid { -pid } else { pid };
+        libc::kill(target, signal);
+    }
+}
+
+/// Ends the process [`pid`] and everything it started.
+#[cfg(not(unix))]
+fn taskkill(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+}
+
+/// Interrupts the process [`pid`] like Ctrl-C does, and all processes of its group if it leads
+/// one.
+pub fn interrupt(pid: u32) {
+    #[cfg(unix)]
+    signal(pid, libc::SIGINT);
+    // the console already sent Ctrl-C to the process
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+/// Asks the process [`pid`] to exit, and all processes of its group if it leads one.
+pub fn terminate(pid: u32) {
+    #[cfg(unix)]
+    signal(pid, libc::SIGTERM);
+    #[cfg(not(unix))]
+    taskkill(pid);
+}
+
+/// Kills [`child`] and all processes of its group if it leads one.
+pub fn kill(child: &mut Child) {
+    // the child may have exited in the meantime, which makes kill fail
+    #[cfg(unix)]
+    signal(child.id(), libc::SIGKILL);
+    #[cfg(not(unix))]
+    {
+        taskkill(child.id());
+        let _ = child.kill();
+    }
+}
+
+/// The signal that ended the process of [`status`], never one on Windows.
+#[cfg(unix)]
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Whether the process of [`status`] was ended by Ctrl-C.
+#[cfg(unix)]
+pub fn interrupted(status: &ExitStatus) -> bool {
+    status.signal() == Some(libc::SIGINT)
+}
+
+#[cfg(not(unix))]
+pub fn interrupted(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Hands the terminal to the process group of a child until dropped. Nothing happens unless
+/// cargo-remote runs in the foreground of a terminal, which is never the case on Windows.
+pub struct Foreground {
+    active: bool,
+}
+
+impl Foreground {
+    /// Hands the terminal to the group led by [`child`].
+    #[cfg(unix)]
+    pub fn give(child: &Child) -> Foreground {
+        let group = child.id() as libc::pid_t;
+        // SAFETY: the calls take no pointers, at worst the group is gone and they fail
+        let active = unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+                && libc::tcsetpgrp(libc::STDIN_FILENO, group) == 0
+        };
+        if active {
+            // a process that used the terminal before it got it was stopped by SIGTTIN or SIGTTOU
+            signal(child.id(), libc::SIGCONT);
+        }
+        Foreground { active }
+    }
+
+    #[cfg(not(unix))]
+    pub fn give(_child: &Child) -> Foreground {
+        Foreground { active: false }
+    }
+
+    /// Whether the child's group got the terminal, so Ctrl-C only reached the child.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        // SAFETY: the previous handler is restored as it was. A process in the background that
+        // takes the terminal back is stopped by SIGTTOU unless it ignores it.
+        unsafe {
+            let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+            libc::signal(libc::SIGTTOU, previous);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! The remote build directories of all checkouts of a project, as listed and removed by
+//! `cargo remote clean-remote`.
+
+use std::process::Stdio;
+
+use crate::audit;
+use crate::error::{remote_error, spawn_error, Error};
+use crate::shell_quote;
+use crate::transport::Transport;
+
+/// A build directory on the build server with its size as reported by `du -sh`.
+pub struct RemoteDir {
+    pub size: String,
+    pub path: String,
+}
+
+/// Lists the directories matching the shell [`patterns`] on the build server. Returns [`None`] if
+/// the build server couldn't be asked.
+pub fn list(transport: &Transport, patterns: &[String]) -> Option<Vec<RemoteDir>> {
+    let script = format!(
+        "for d in {}; do [ -d \"$d\" ] && du -sh \"$d\"; done; true",
+        patterns.join(" ")
+    );
+    let output = audit::output(transport.shell(&script).stderr(Stdio::inherit()))
+        .ok()
+        .filter(|o| o.status.success())?;
+    let dirs = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (size, path) = line.split_once('\t')?;
+            Some(RemoteDir {
+                size: size.trim().to_owned(),
+                path: path.trim_end_matches('/').to_owned(),
+            })
+        })
+        .collect();
+    Some(dirs)
+}
+
+/// Prints [`dirs`] as a table, marking the one named [`current`] that this checkout builds in.
+pub fn print_table(dirs: &[RemoteDir], current: &str) {
+    println!("{:>8}  directory", "size");
+    for dir in dirs {
+        let name = dir.path.rsplit('/').next().unwrap_or_default();
+        println!(
+            "{:>8}  {}{}",
+            dir.size,
+            dir.path,
+            if name == current { "  (current)" } else { "" }
+        );
+    }
+}
+
+/// Removes [`dirs`] from the build server.
+pub fn remove(transport: &Transport, dirs: &[RemoteDir]) -> Result<(), Error> {
+    let paths = dirs
+        .iter()
+        .map(|dir| shell_quote(&dir.path))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut rm = transport.shell(&format!("rm -rf -- {}", paths));
+    let status = audit::status(&mut rm).map_err(|e| spawn_error(&rm, e, Error::Clean))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(remote_error("rm", status, Error::Clean))
+    }
+}
@@ -0,0 +1,139 @@
+//! `--retries`: retrying the upload and the build when the connection to the build server dropped,
+//! e.g. on a flaky Wi-Fi. The hard part is telling a dropped connection from a failed build, both
+//! end with a non-zero exit code.
+
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// The rsync exit codes of a transfer that broke off rather than failed: 10 (error in socket I/O),
+/// 12 (error in the rsync protocol data stream), 30 (timeout in data send/receive) and 255, which
+/// ssh exits with when the connection fails.
+const TRANSIENT_RSYNC_CODES: [i32; 4] = [10, 12, 30, 255];
+
+/// The exit code of ssh if the connection failed, or of the remote command if it exited with 255.
+const SSH_FAILURE: i32 = 255;
+
+/// The longest pause between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The beginnings of the messages ssh itself prints when it can't connect or loses the
+/// connection. They arrive on the same stderr as the output of the remote command.
+const SSH_DIAGNOSTICS: [&str; 8] = [
+    "ssh:",
+    "ssh_exchange_identification:",
+    "kex_exchange_identification:",
+    "client_loop:",
+    "packet_write_wait:",
+    "Connection closed by",
+    "Connection reset by",
+    "Timeout, server",
+];
+
+/// Whether the rsync transfer that exited with [`status`] broke off and is worth retrying.
+pub fn is_transient_transfer_failure(status: ExitStatus) -> bool {
+    status
+        .code()
+        .is_some_and(|code| TRANSIENT_RSYNC_CODES.contains(&code))
+}
+
+/// Whether the remote command that exited with [`status`] likely never ran or lost its connection,
+/// rather than failed: ssh exited with 255 and the remote side didn't print anything but ssh's
+/// own complaints. A build that printed anything before the connection dropped isn't retried,
+/// as it may have been a build failure exiting with 255 just as well.
+pub fn is_connection_failure(status: ExitStatus, output: &RemoteOutput) -> bool {
+    status.code() == Some(SSH_FAILURE) && !output.seen()
+}
+
+/// How long to wait before the attempt after [`attempt`], starting at 1: 1s, 2s, 4s, ... up to
+/// [`MAX_BACKOFF`].
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.saturating_sub(1).min(6)).min(MAX_BACKOFF)
+}
+
+/// Watches the output of the remote command for anything but ssh's diagnostics.
+#[derive(Default)]
+pub struct RemoteOutput {
+    seen: AtomicBool,
+}
+
+impl RemoteOutput {
+    pub fn observe(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if !line.is_empty() && !SSH_DIAGNOSTICS.iter().any(|d| line.starts_with(d)) {
+            self.seen.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the remote command printed anything.
+    pub fn seen(&self) -> bool {
+        self.seen.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn exited(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exited(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    fn output(lines: &[&str]) -> RemoteOutput {
+        let output = RemoteOutput::default();
+        for line in lines {
+            output.observe(format!("{}\n", line).as_bytes());
+        }
+        output
+    }
+
+    #[test]
+    fn connection_failures() {
+        assert!(is_connection_failure(exited(255), &output(&[])));
+        assert!(is_connection_failure(
+            exited(255),
+            &output(&[
+                "ssh: connect to host build port 22: Connection refused",
+                "",
+                "client_loop: send disconnect: Broken pipe",
+                "  Connection closed by 10.0.0.2 port 22",
+            ])
+        ));
+        // the remote command ran and may have exited with 255 itself
+        assert!(!is_connection_failure(
+            exited(255),
+            &output(&["   Compiling app v0.1.0", "ssh: connect to host build"])
+        ));
+        // a failed build
+        assert!(!is_connection_failure(exited(101), &output(&[])));
+        assert!(!is_connection_failure(exited(0), &output(&[])));
+    }
+
+    #[test]
+    fn transient_transfer_failures() {
+        for code in [10, 12, 30, 255] {
+            assert!(is_transient_transfer_failure(exited(code)), "{}", code);
+        }
+        // a partial transfer and a syntax error happen again on a retry
+        for code in [0, 1, 23] {
+            assert!(!is_transient_transfer_failure(exited(code)), "{}", code);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let secs: Vec<u64> = (1..=8).map(|a| backoff(a).as_secs()).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
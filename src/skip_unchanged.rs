@@ -0,0 +1,225 @@
+//! `--skip-unchanged`: a digest of the sources of the last successful sync to each build server,
+//! recorded in `.cargo-remote/state` in the workspace root, so a run on an untouched tree doesn't
+//! start rsync at all. The digest covers the path, size and modification time of every file below
+//! the synced directories and the sync filters, so editing, adding or removing a file or changing
+//! what is synced invalidates it. Unlike the rsync scan, the directories the sync never transfers
+//! aren't walked.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+use serde_json::{Map, Value};
+
+use crate::fnv1a;
+
+/// The directory of the state in the workspace root, excluded from the sync.
+pub const STATE_DIR: &str = ".cargo-remote";
+
+/// The state file in [`STATE_DIR`], mapping the remote build directories to the digests.
+const STATE_FILE: &str = "state";
+
+/// What the walk leaves out because the sync never transfers it.
+pub struct Skipped<'a> {
+    /// Directories like the local target directory.
+    pub dirs: &'a [PathBuf],
+    /// Names of files and directories excluded wherever they are, like `target`.
+    pub names: &'a [&'a str],
+}
+
+/// The digest of the files below [`dirs`] synced with [`filters`], [`None`] if a directory can't
+/// be read.
+pub fn digest(dirs: &[PathBuf], skipped: &Skipped, filters: &[OsString]) -> Option<String> {
+    let mut entries = filters
+        .iter()
+        .map(|filter| format!("filter {}", filter.to_string_lossy()))
+        .collect::<Vec<_>>();
+    for dir in dirs {
+        entries.push(format!("dir {}", dir.to_string_lossy()));
+        if let Err(e) = walk(dir, skipped, &mut entries) {
+            warn!(
+                "Can't tell if the sources changed, syncing them (error reading '{}': {})",
+                dir.to_string_lossy(),
+                e
+            );
+            return None;
+        }
+    }
+    entries.sort();
+    Some(format!("{:016x}", fnv1a(entries.join("\n").as_bytes())))
+}
+
+/// Adds a line with the path, size and modification time of every file below [`dir`] and one for
+/// every directory to [`entries`]. Symbolic links aren't followed, rsync transfers them as links.
+fn walk(dir: &Path, skipped: &Skipped, entries: &mut Vec<String>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        if skipped.names.iter().any(|skipped| name == *skipped) || skipped.dirs.contains(&path) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            // rsync creates empty directories as well
+            entries.push(format!("dir {}", path.to_string_lossy()));
+            walk(&path, skipped, entries)?;
+        } else {
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            entries.push(format!(
+                "{} {} {}",
+                modified.as_nanos(),
+                metadata.len(),
+                path.to_string_lossy()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn state_path(workspace_root: &Path) -> PathBuf {
+    workspace_root.join(STATE_DIR).join(STATE_FILE)
+}
+
+fn load(workspace_root: &Path) -> Map<String, Value> {
+    std::fs::read_to_string(state_path(workspace_root))
+        .ok()
+        .and_then(|state| serde_json::from_str::<Value>(&state).ok())
+        .and_then(|state| state.as_object().cloned())
+        .unwrap_or_default()
+}
+
+/// Whether the last successful sync to [`remote`] transferred sources with the [`digest`].
+pub fn unchanged(workspace_root: &Path, remote: &str, digest: &str) -> bool {
+    load(workspace_root).get(remote).and_then(Value::as_str) == Some(digest)
+}
+
+/// Records that the sources with the [`digest`] were synced to [`remote`], or that the sources
+/// there are unknown if [`None`], e.g. after `--clean`. Failing to do so only costs a sync.
+pub fn record(workspace_root: &Path, remote: &str, digest: Option<&str>) {
+    let mut state = load(workspace_root);
+    let changed = match digest {
+        Some(digest) => {
+            state.insert(remote.to_owned(), digest.into()).as_ref() != Some(&digest.into())
+        }
+        None => state.remove(remote).is_some(),
+    };
+    if !changed {
+        return;
+    }
+    let dir = workspace_root.join(STATE_DIR);
+    // like cargo's target directory, the state directory keeps itself out of git
+    let written = std::fs::create_dir_all(&dir)
+        .and_then(|()| match dir.join(".gitignore") {
+            gitignore if gitignore.exists() => Ok(()),
+            gitignore => std::fs::write(gitignore, "*\n"),
+        })
+        .and_then(|()| {
+            std::fs::write(state_path(workspace_root), Value::Object(state).to_string())
+        });
+    if let Err(e) = written {
+        warn!(
+            "Failed to write '{}', the next run syncs the sources (error: {})",
+            state_path(workspace_root).to_string_lossy(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test, removed when it ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "cargo-remote-skip-unchanged-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("src")).unwrap();
+            std::fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+            std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const NAMES: &[&str] = &["target", ".git"];
+
+    fn digest_of(dir: &Path, filters: &[&str]) -> String {
+        let skipped = Skipped {
+            dirs: &[dir.join(STATE_DIR)],
+            names: NAMES,
+        };
+        let filters: Vec<OsString> = filters.iter().map(OsString::from).collect();
+        digest(&[dir.to_owned()], &skipped, &filters).unwrap()
+    }
+
+    #[test]
+    fn digest_changes_with_the_sources() {
+        let dir = TempDir::new("sources");
+        let before = digest_of(&dir.0, &[]);
+        assert_eq!(digest_of(&dir.0, &[]), before);
+
+        std::fs::write(dir.0.join("src/lib.rs"), "fn main() {}").unwrap();
+        let edited = digest_of(&dir.0, &[]);
+        assert_ne!(edited, before);
+
+        std::fs::create_dir(dir.0.join("src/empty")).unwrap();
+        let added = digest_of(&dir.0, &[]);
+        assert_ne!(added, edited);
+
+        std::fs::remove_dir(dir.0.join("src/empty")).unwrap();
+        assert_eq!(digest_of(&dir.0, &[]), edited);
+        assert_ne!(digest_of(&dir.0, &["--exclude", "data"]), edited);
+    }
+
+    #[test]
+    fn digest_ignores_what_isnt_synced() {
+        let dir = TempDir::new("skipped");
+        let before = digest_of(&dir.0, &[]);
+        for skipped in ["target", ".git", "src/target"] {
+            std::fs::create_dir_all(dir.0.join(skipped)).unwrap();
+            std::fs::write(dir.0.join(skipped).join("file"), "").unwrap();
+        }
+        record(&dir.0, "host:~/remote-builds/1/", Some("0"));
+        assert_eq!(digest_of(&dir.0, &[]), before);
+    }
+
+    #[test]
+    fn state_is_kept_per_remote() {
+        let dir = TempDir::new("state");
+        let (host, other) = ("host:~/remote-builds/1/", "other:~/remote-builds/1/");
+        assert!(!unchanged(&dir.0, host, "1"));
+
+        record(&dir.0, host, Some("1"));
+        record(&dir.0, other, Some("2"));
+        assert!(unchanged(&dir.0, host, "1"));
+        assert!(!unchanged(&dir.0, host, "2"));
+        assert!(unchanged(&dir.0, other, "2"));
+
+        // after --clean
+        record(&dir.0, host, None);
+        assert!(!unchanged(&dir.0, host, "1"));
+        assert!(unchanged(&dir.0, other, "2"));
+        assert_eq!(
+            std::fs::read_to_string(dir.0.join(STATE_DIR).join(".gitignore")).unwrap(),
+            "*\n"
+        );
+    }
+}
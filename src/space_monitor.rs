@@ -0,0 +1,93 @@
+//! Watching the free space of the remote filesystem while the build runs. A quota-limited home
+//! directory fills up in the middle of linking, and the "No space left on device" that follows is
+//! easily buried thousands of lines up in the output.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::audit;
+use crate::transport::Transport;
+use crate::units::format_size;
+
+/// How full the remote filesystem is.
+#[derive(Clone, Copy)]
+pub struct Usage {
+    /// The free space in bytes.
+    pub available: u64,
+    /// How many percent of the filesystem are used.
+    pub capacity: u64,
+}
+
+/// Checks the filesystem of the quoted remote directory [`dir`], or of [`fallback`] while [`dir`]
+/// doesn't exist yet, every [`interval`] until [`finished`] is set. Warns once the free space
+/// drops below [`floor`] bytes and returns the fullest reading below the floor, if there was one.
+pub fn monitor(
+    transport: Transport,
+    dir: String,
+    fallback: String,
+    interval: Duration,
+    floor: u64,
+    finished: Arc<AtomicBool>,
+) -> std::thread::JoinHandle<Option<Usage>> {
+    std::thread::spawn(move || {
+        let mut fullest: Option<Usage> = None;
+        let mut failed = false;
+        let mut next_check = Instant::now();
+        while !finished.load(Ordering::SeqCst) {
+            if Instant::now() < next_check {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            next_check = Instant::now() + interval;
+            let usage = match usage(&transport, &dir, &fallback) {
+                Some(usage) => usage,
+                None => {
+                    if !failed {
+                        info!("Failed to check the free space on the build server");
+                        failed = true;
+                    }
+                    continue;
+                }
+            };
+            if usage.available >= floor {
+                continue;
+            }
+            if fullest.is_none() {
+                warn!(
+                    "The remote filesystem is {}% full, only {} are left. \
+                     The build may fail with 'No space left on device'",
+                    usage.capacity,
+                    format_size(usage.available)
+                );
+            }
+            if fullest.is_none_or(|f| usage.available < f.available) {
+                fullest = Some(usage);
+            }
+        }
+        fullest
+    })
+}
+
+/// Asks `df` how full the filesystem of the quoted remote directory [`dir`] or [`fallback`] is.
+fn usage(transport: &Transport, dir: &str, fallback: &str) -> Option<Usage> {
+    let output = audit::output(&mut transport.shell(&format!(
+        "{{ df -Pk {} 2>/dev/null || df -Pk {}; }} | tail -n 1",
+        dir, fallback
+    )))
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let line = String::from_utf8_lossy(&output.stdout);
+    let mut fields = line.split_whitespace().skip(3);
+    let available = fields.next()?.parse::<u64>().ok()?;
+    let capacity = fields.next()?.trim_end_matches('%').parse().ok()?;
+    Some(Usage {
+        available: available * 1024,
+        capacity,
+    })
+}
@@ -0,0 +1,125 @@
+//! Recovery from cargo waiting forever on a file lock that a crashed run left behind, e.g. on NFS
+//! where the lock outlives the process holding it.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::transport::Transport;
+
+/// Appended to the pid file of the remote command to name the one of the previous command.
+pub const LAST_PID_SUFFIX: &str = ".last";
+
+/// The locks cargo reports waiting for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lock {
+    BuildDirectory,
+    PackageCache,
+}
+
+impl Lock {
+    /// Recognizes cargo's message about waiting for a lock.
+    fn from_message(line: &str) -> Option<Lock> {
+        let waiting = line.split("Blocking waiting for file lock on ").nth(1)?;
+        if waiting.starts_with("build directory") {
+            Some(Lock::BuildDirectory)
+        } else if waiting.starts_with("package cache") {
+            Some(Lock::PackageCache)
+        } else {
+            None
+        }
+    }
+
+    /// Shell words matching the lock files, relative to the quoted target directory
+    /// [`target_dir`].
+    fn files(self, target_dir: &str) -> String {
+        match self {
+            Lock::BuildDirectory => format!(
+                "{dir}/.cargo-lock {dir}/*/.cargo-lock {dir}/*/*/.cargo-lock",
+                dir = target_dir
+            ),
+            Lock::PackageCache => "\"${CARGO_HOME:-$HOME/.cargo}/.package-cache\"".to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for Lock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lock::BuildDirectory => write!(f, "build directory"),
+            Lock::PackageCache => write!(f, "package cache"),
+        }
+    }
+}
+
+/// Scans the output of the remote command for cargo waiting on a lock.
+#[derive(Default)]
+pub struct LockWatch {
+    blocked: Mutex<Option<(Instant, Lock)>>,
+}
+
+impl LockWatch {
+    /// Notes when cargo starts waiting for a lock. Any other output means it got the lock.
+    pub fn observe(&self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let mut blocked = self.blocked.lock().unwrap_or_else(|e| e.into_inner());
+        *blocked = Lock::from_message(line).map(|lock| (Instant::now(), lock));
+    }
+
+    /// The lock cargo has been waiting for since more than [`grace`]. Returns it only once, so
+    /// every wait is checked once.
+    pub fn blocked_longer_than(&self, grace: Duration) -> Option<Lock> {
+        let mut blocked = self.blocked.lock().unwrap_or_else(|e| e.into_inner());
+        match *blocked {
+            Some((since, lock)) if since.elapsed() >= grace => {
+                *blocked = None;
+                Some(lock)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What [`remove_stale_lock`] found on the build server.
+pub enum Recovery {
+    /// The lock was stale, these files were removed.
+    Removed(Vec<String>),
+    /// The previous remote command is still running, so the lock is legitimately held.
+    Held,
+    /// No previous remote command was recorded, so nothing is removed.
+    Unknown,
+}
+
+/// Removes the files of [`lock`] if the previous remote command, whose pid was moved to
+/// [`pid_file`] + [`LAST_PID_SUFFIX`] when the current one started, is gone. Both paths are quoted
+/// for the remote shell. Returns [`None`] if the build server couldn't be asked.
+pub fn remove_stale_lock(
+    transport: &Transport,
+    target_dir: &str,
+    pid_file: &str,
+    lock: Lock,
+) -> Option<Recovery> {
+    // the whole process group counts, cargo may outlive the shell that started it
+    let script = format!(
+        "last=$(cat {pid}{suffix} 2>/dev/null); [ -n \"$last\" ] || exit 3; \
+         ps -A -o pgid= | grep -q \"^ *$last\\$\" && exit 4; \
+         for f in {files}; do if [ -e \"$f\" ]; then rm -f \"$f\" && echo \"$f\"; fi; done",
+        pid = pid_file,
+        suffix = LAST_PID_SUFFIX,
+        files = lock.files(target_dir)
+    );
+    let output = audit::output(&mut transport.shell(&script)).ok()?;
+    match output.status.code() {
+        Some(0) => Some(Recovery::Removed(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::to_owned)
+                .collect(),
+        )),
+        Some(3) => Some(Recovery::Unknown),
+        Some(4) => Some(Recovery::Held),
+        _ => None,
+    }
+}
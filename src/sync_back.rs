@@ -0,0 +1,120 @@
+//! Files the remote command may rewrite in the project that are transferred back, by default just
+//! `Cargo.lock`. With `sync_back_files`, e.g. the `workspace-hack/Cargo.toml` regenerated by
+//! `cargo hakari generate` comes back together with the lockfile, so the two stay consistent.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use toml::Value;
+
+use crate::error::Error;
+use crate::local_shell;
+
+/// The lockfile, which is transferred back unless `--no-copy-lock` is given.
+pub const LOCKFILE: &str = "Cargo.lock";
+
+/// The suffix of the local file a remote file is transferred into, it replaces the original once
+/// it's complete.
+const FETCHED_SUFFIX: &str = ".remote-tmp";
+
+/// A file transferred back.
+pub struct SyncBackFile {
+    /// The path relative to the workspace root, separated by slashes.
+    pub path: String,
+    /// The local file that is replaced.
+    pub local: PathBuf,
+    /// The local file the transfer writes into, so an interrupted or bogus transfer doesn't leave
+    /// a broken file behind.
+    pub fetched: PathBuf,
+    /// The transfer, from `<build_path>/<path>` into [`fetched`].
+    pub rsync: Command,
+}
+
+impl SyncBackFile {
+    /// Prepares the transfer of [`path`] from the remote [`build_path`] into [`project_dir`].
+    /// [`rsync`] is the rsync command with the transfer options, [`location`] names the remote
+    /// path for it.
+    pub fn new(
+        path: &str,
+        project_dir: &Path,
+        build_path: &str,
+        mut rsync: Command,
+        location: impl Fn(&str) -> String,
+    ) -> SyncBackFile {
+        let path = path.trim_start_matches('/').to_owned();
+        let local = project_dir.join(&path);
+        let mut fetched = local.clone().into_os_string();
+        fetched.push(FETCHED_SUFFIX);
+        let fetched = PathBuf::from(fetched);
+        rsync
+            .arg("--ignore-missing-args")
+            .arg(location(&format!(
+                "{}/{}",
+                build_path.trim_end_matches('/'),
+                path
+            )))
+            .arg(local_shell::path(&fetched));
+        SyncBackFile {
+            path,
+            local,
+            fetched,
+            rsync,
+        }
+    }
+
+    /// The error of a failed transfer of this file.
+    pub fn error(&self) -> fn(String) -> Error {
+        if self.path == LOCKFILE {
+            Error::CopyLock
+        } else {
+            Error::CopyBack
+        }
+    }
+
+    /// Replaces the local file with the fetched one if it was transferred and differs, after
+    /// making sure a TOML file is still TOML and not e.g. an error page. Leaving an unchanged file
+    /// alone keeps its modification time, which cargo and other tools compare.
+    pub fn install(&self) -> Result<Installed, Error> {
+        let content = match std::fs::read(&self.fetched) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Installed::Missing),
+            Err(e) => return Err(self.error()(format!("can't read {}: {}", self.path, e))),
+        };
+        if self.path.ends_with(".lock") || self.path.ends_with(".toml") {
+            String::from_utf8_lossy(&content)
+                .parse::<Value>()
+                .map_err(|e| self.error()(format!("{} isn't valid TOML: {}", self.path, e)))?;
+        }
+        if std::fs::read(&self.local).is_ok_and(|local| local == content) {
+            let _ = std::fs::remove_file(&self.fetched);
+            return Ok(Installed::Unchanged);
+        }
+        std::fs::rename(&self.fetched, &self.local)
+            .map(|()| Installed::Replaced)
+            .map_err(|e| self.error()(format!("can't replace the local {}: {}", self.path, e)))
+    }
+}
+
+/// What [`SyncBackFile::install`] did.
+pub enum Installed {
+    /// The file doesn't exist on the build server.
+    Missing,
+    /// The file is the same on both sides.
+    Unchanged,
+    /// The local file was replaced.
+    Replaced,
+}
+
+/// The files to transfer back: the configured [`files`], else the lockfile, which is left out with
+/// [`no_copy_lock`] or if [`modifies_lock`] is false because no command may have touched it.
+pub fn files(files: &[String], no_copy_lock: bool, modifies_lock: bool) -> Vec<String> {
+    let files = if files.is_empty() {
+        vec![LOCKFILE.to_owned()]
+    } else {
+        files.to_vec()
+    };
+    files
+        .into_iter()
+        .filter(|file| file != LOCKFILE || (!no_copy_lock && modifies_lock))
+        .collect()
+}
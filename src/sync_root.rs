@@ -0,0 +1,234 @@
+//! Path dependencies outside the workspace root, e.g. `path = "../shared-protos"`. Syncing just the
+//! workspace leaves cargo on the build server with a "failed to load manifest" error, so they are
+//! detected up front. With `--sync-parent`, the workspace and these dependencies are synced from a
+//! common ancestor instead, so their layout is the same on the build server.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::Metadata;
+use toml::Value;
+
+use crate::error::Error;
+use crate::local_shell;
+
+/// Where the sync starts and what it transfers.
+pub struct SyncRoot {
+    /// The local directory the sync transfers.
+    pub dir: PathBuf,
+    /// The workspace root relative to [`dir`], separated by slashes. Empty without a sync parent.
+    pub project: String,
+    /// The directories below [`dir`] that are transferred: the workspace root and the path
+    /// dependencies outside of it that aren't in there either.
+    pub dirs: Vec<PathBuf>,
+    /// The rsync filters that leave out everything below [`dir`] but the workspace and the path
+    /// dependencies outside of it.
+    pub filters: Vec<OsString>,
+}
+
+impl SyncRoot {
+    /// Anchors the rsync [`filters`], written relative to the workspace root, at [`SyncRoot::dir`].
+    pub fn anchor(&self, filters: Vec<OsString>) -> Vec<OsString> {
+        if self.project.is_empty() {
+            return filters;
+        }
+        let mut anchored = Vec::with_capacity(filters.len());
+        let mut is_pattern = false;
+        for filter in filters {
+            let text = filter.to_string_lossy();
+            if is_pattern && text.starts_with('/') {
+                anchored.push(format!("/{}{}", self.project, text).into());
+            } else {
+                anchored.push(filter.clone());
+            }
+            is_pattern = text == "--include" || text == "--exclude";
+        }
+        anchored
+    }
+}
+
+/// The manifest tables that list dependencies, also below `[target.<cfg>]`.
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// The directories of the path dependencies outside the workspace root, including the ones they
+/// depend on in turn. `cargo metadata --no-deps` doesn't tell where path dependencies are, so the
+/// manifests are read instead.
+fn outside_dependencies(metadata: &Metadata) -> Vec<PathBuf> {
+    let root = &metadata.workspace_root;
+    let canonical_root = root.canonicalize().unwrap_or_else(|_| root.clone());
+    let mut pending: Vec<PathBuf> = metadata
+        .packages
+        .iter()
+        .map(|p| p.manifest_path.clone())
+        .chain(std::iter::once(root.join("Cargo.toml")))
+        .collect();
+    let mut seen: BTreeSet<PathBuf> = pending.iter().cloned().collect();
+    let mut outside = BTreeSet::new();
+    while let Some(manifest_path) = pending.pop() {
+        for dir in path_dependencies(&manifest_path) {
+            let dir = dir.canonicalize().unwrap_or(dir);
+            if !dir.starts_with(&canonical_root) && !dir.starts_with(root) {
+                outside.insert(dir.clone());
+            }
+            let manifest_path = dir.join("Cargo.toml");
+            if seen.insert(manifest_path.clone()) {
+                pending.push(manifest_path);
+            }
+        }
+    }
+    outside.into_iter().collect()
+}
+
+/// The directories of the dependencies with a `path` in the manifest at [`manifest_path`],
+/// including `[workspace.dependencies]` and `[patch]`.
+fn path_dependencies(manifest_path: &Path) -> Vec<PathBuf> {
+    let manifest = match std::fs::read_to_string(manifest_path)
+        .ok()
+        .and_then(|content| content.parse::<Value>().ok())
+    {
+        Some(manifest) => manifest,
+        None => return Vec::new(),
+    };
+    let mut tables: Vec<&Value> = DEPENDENCY_TABLES
+        .iter()
+        .filter_map(|table| manifest.get(table))
+        .collect();
+    if let Some(targets) = manifest.get("target").and_then(Value::as_table) {
+        for target in targets.values() {
+            tables.extend(
+                DEPENDENCY_TABLES
+                    .iter()
+                    .filter_map(|table| target.get(table)),
+            );
+        }
+    }
+    tables.extend(
+        manifest
+            .get("workspace")
+            .and_then(|w| w.get("dependencies")),
+    );
+    if let Some(patches) = manifest.get("patch").and_then(Value::as_table) {
+        tables.extend(patches.values());
+    }
+    let dir = manifest_path.parent().unwrap_or(manifest_path);
+    tables
+        .iter()
+        .filter_map(|table| table.as_table())
+        .flat_map(|table| table.values())
+        .filter_map(|dependency| dependency.get("path")?.as_str())
+        .map(|path| dir.join(path))
+        .collect()
+}
+
+/// Decides where the sync of the workspace of [`metadata`] starts: at the workspace root, or at
+/// [`sync_parent`] if given. A relative [`sync_parent`] is resolved against the workspace root.
+/// Fails if a path dependency isn't below the directory the sync starts at.
+pub fn resolve(metadata: &Metadata, sync_parent: Option<&Path>) -> Result<SyncRoot, Error> {
+    let root = metadata.workspace_root.as_path();
+    let outside = outside_dependencies(metadata);
+
+    let parent = match sync_parent {
+        Some(parent) => root.join(parent),
+        None if outside.is_empty() => {
+            return Ok(SyncRoot {
+                dir: root.to_owned(),
+                project: String::new(),
+                dirs: vec![root.to_owned()],
+                filters: Vec::new(),
+            });
+        }
+        None => {
+            return Err(Error::Project(format!(
+                "these path dependencies are outside the workspace root {} and wouldn't be \
+                 synced: {}. Pass --sync-parent with a directory containing all of them",
+                root.to_string_lossy(),
+                list(&outside)
+            )));
+        }
+    };
+    let canonicalize = |dir: &Path| {
+        dir.canonicalize()
+            .map_err(|e| Error::Project(format!("{}: {}", dir.to_string_lossy(), e)))
+    };
+    let parent = canonicalize(&parent)?;
+    let mut dirs = BTreeSet::new();
+    for dir in std::iter::once(root).chain(outside.iter().map(PathBuf::as_path)) {
+        dirs.insert(canonicalize(dir)?);
+    }
+    let not_below: Vec<PathBuf> = dirs
+        .iter()
+        .filter(|dir| !dir.starts_with(&parent))
+        .cloned()
+        .collect();
+    if !not_below.is_empty() {
+        return Err(Error::Project(format!(
+            "the sync parent {} doesn't contain {}",
+            parent.to_string_lossy(),
+            list(&not_below)
+        )));
+    }
+
+    let project = local_shell::slashes(canonicalize(root)?.strip_prefix(&parent).unwrap_or(root));
+    // a directory below another one is synced with it
+    let outermost: Vec<PathBuf> = dirs
+        .iter()
+        .filter(|dir| {
+            !dirs
+                .iter()
+                .any(|other| other != *dir && dir.starts_with(other))
+        })
+        .cloned()
+        .collect();
+    let synced: Vec<String> = outermost
+        .iter()
+        .filter_map(|dir| dir.strip_prefix(&parent).ok())
+        .map(local_shell::slashes)
+        .filter(|dir| !dir.is_empty())
+        .collect();
+    Ok(SyncRoot {
+        dir: parent,
+        project,
+        dirs: outermost,
+        filters: filters(&synced),
+    })
+}
+
+/// The filters that transfer only the [`synced`] directories, relative to the sync root. rsync
+/// doesn't descend into excluded directories, so the ones in between are included, but nothing
+/// else in them.
+fn filters(synced: &[String]) -> Vec<OsString> {
+    if synced.is_empty() {
+        return Vec::new();
+    }
+    let mut between = BTreeSet::new();
+    for dir in synced {
+        // every ancestor below the sync root, e.g. `/a` and `/a/b` for `a/b/c`
+        let mut end = 0;
+        while let Some(slash) = dir[end..].find('/') {
+            end += slash;
+            between.insert(format!("/{}", &dir[..end]));
+            end += 1;
+        }
+    }
+    let mut filters: Vec<OsString> = Vec::new();
+    for dir in between
+        .iter()
+        .cloned()
+        .chain(synced.iter().map(|dir| format!("/{}", dir)))
+    {
+        filters.extend(["--include".into(), format!("{}/", dir).into()]);
+    }
+    for dir in &between {
+        filters.extend(["--exclude".into(), format!("{}/*", dir).into()]);
+    }
+    filters.extend(["--exclude".into(), "/*".into()]);
+    filters
+}
+
+fn list(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
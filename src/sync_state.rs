@@ -0,0 +1,127 @@
+//! Bookkeeping of the files transferred by the last sync, so small changes can be uploaded without
+//! rsync walking the whole tree on both ends.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Stdio;
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::audit;
+use crate::local_shell;
+
+/// Name of the state file inside the local `target` directory.
+pub const STATE_FILE: &str = ".cargo-remote-state.json";
+
+/// Maps the path of every transferred file, relative to the project dir, to its mtime and size.
+pub type FileList = BTreeMap<String, String>;
+
+/// The files of the last successful sync to [`SyncState::remote`].
+pub struct SyncState {
+    pub remote: String,
+    pub files: FileList,
+}
+
+/// Files that changed since the last sync.
+pub struct Changes {
+    /// New or modified files.
+    pub modified: Vec<String>,
+    /// Files that don't exist locally anymore.
+    pub deleted: Vec<String>,
+}
+
+impl Changes {
+    pub fn len(&self) -> usize {
+        self.modified.len() + self.deleted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SyncState {
+    /// Reads the state from [`path`]. Returns [`None`] if there is none or it can't be parsed.
+    pub fn load(path: &Path) -> Option<SyncState> {
+        let state: Value = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+        let remote = state.get("remote")?.as_str()?.to_owned();
+        let files = state
+            .get("files")?
+            .as_object()?
+            .iter()
+            .map(|(file, stat)| Some((file.clone(), stat.as_str()?.to_owned())))
+            .collect::<Option<_>>()?;
+        Some(SyncState { remote, files })
+    }
+
+    /// Writes the state to [`path`]. Failing to do so only costs a full sync next time.
+    pub fn save(&self, path: &Path) {
+        let state = json!({ "remote": self.remote, "files": self.files });
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, state.to_string()));
+        if let Err(e) = written {
+            warn!(
+                "Failed to write '{}', the next sync will be a full one (error: {})",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+
+    /// Compares the recorded files with [`current`].
+    pub fn changes(&self, current: &FileList) -> Changes {
+        Changes {
+            modified: current
+                .iter()
+                .filter(|(file, stat)| self.files.get(*file) != Some(stat))
+                .map(|(file, _)| file.clone())
+                .collect(),
+            deleted: self
+                .files
+                .keys()
+                .filter(|file| !current.contains_key(*file))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Lists the files below [`project_dir`] that a sync with the rsync [`filters`] transfers. This
+/// lets [`rsync`] apply the filters, but only walks the local tree.
+pub fn list_files(rsync: &str, project_dir: &Path, filters: &[OsString]) -> Option<FileList> {
+    let empty_dir = std::env::temp_dir().join(format!("cargo-remote-empty-{}", std::process::id()));
+    std::fs::create_dir_all(&empty_dir).ok()?;
+    let output = audit::output(
+        local_shell::command(rsync)
+            .arg("-a")
+            .arg("--dry-run")
+            .arg("--out-format=%M %l %n")
+            .args(filters)
+            .arg(local_shell::dir(project_dir))
+            .arg(local_shell::path(&empty_dir))
+            .stderr(Stdio::inherit()),
+    );
+    let _ = std::fs::remove_dir(&empty_dir);
+    let output = output.ok().filter(|o| o.status.success())?;
+
+    String::from_utf8(output.stdout).ok().map(|listing| {
+        listing
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, ' ');
+                let stat = format!("{} {}", fields.next()?, fields.next()?);
+                let file = fields.next()?;
+                // directories are created on the remote as a side effect of their files
+                if file.ends_with('/') {
+                    None
+                } else {
+                    Some((file.to_owned(), stat))
+                }
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,88 @@
+//! `--max-remote-target-size`: keeping the persistent remote target directory within a budget.
+//! The incremental compilation sessions grow with every build and are the part that is cheapest
+//! to lose, so the oldest of them are removed first.
+
+use std::process::{Command, Stdio};
+
+use log::{info, warn};
+
+use crate::audit;
+use crate::transport::Transport;
+use crate::units::format_size;
+
+/// Assembles the remote command that prints the size of the quoted target directory [`target`]
+/// and, if it's above [`limit`] bytes, removes the oldest incremental sessions until it isn't
+/// anymore and prints the size again.
+pub fn command(transport: &Transport, target: &str, limit: u64) -> Command {
+    let script = format!(
+        "size=$(du -sb {t} 2>/dev/null | cut -f1); [ -n \"$size\" ] || exit 0; echo \"$size\"; \
+         [ \"$size\" -gt {limit} ] || exit 0; \
+         find {t} -path '*/incremental/*/*' -prune -printf '%T@ %p\\n' 2>/dev/null | sort -n | \
+         cut -d' ' -f2- | while IFS= read -r session; do \
+         [ \"$size\" -gt {limit} ] || break; \
+         freed=$(du -sb \"$session\" | cut -f1); rm -rf \"$session\" && size=$((size - ${{freed:-0}})); \
+         done; du -sb {t} | cut -f1",
+        t = target,
+        limit = limit
+    );
+    let mut gc = transport.shell(&script);
+    gc.stdin(Stdio::null()).stderr(Stdio::inherit());
+    gc
+}
+
+/// Runs the command assembled by [`command`] and prints what it reclaimed unless [`quiet`] is set.
+/// A failure only warns, the build succeeded regardless.
+pub fn run(gc: &mut Command, limit: u64, quiet: bool) {
+    let output = match audit::output(gc) {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(
+                "Failed to check the size of the remote target directory ({})",
+                output.status
+            );
+            return;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to check the size of the remote target directory (error: {})",
+                e
+            );
+            return;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut sizes = stdout
+        .lines()
+        .filter_map(|line| line.trim().parse::<u64>().ok());
+    let before = match sizes.next() {
+        Some(before) => before,
+        None => return,
+    };
+    let after = match sizes.next() {
+        Some(after) => after,
+        None => {
+            info!(
+                "The remote target directory takes {} of {}",
+                format_size(before),
+                format_size(limit)
+            );
+            return;
+        }
+    };
+    if !quiet {
+        eprintln!(
+            "Reclaimed {} of incremental compilation sessions in the remote target directory, \
+             it takes {} now",
+            format_size(before.saturating_sub(after)),
+            format_size(after)
+        );
+    }
+    if after > limit {
+        warn!(
+            "The remote target directory takes {}, more than the {} of --max-remote-target-size \
+             even without incremental sessions. Pass --fresh to start over",
+            format_size(after),
+            format_size(limit)
+        );
+    }
+}
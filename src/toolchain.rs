@@ -0,0 +1,101 @@
+//! The toolchain a project pins with a `rust-toolchain.toml` or `rust-toolchain` file. rustup
+//! picks it up as a directory override on the build server as well, so nothing has to change the
+//! remote default toolchain, which other users of the build server may rely on.
+
+use std::path::Path;
+
+use log::warn;
+use toml::Value;
+
+/// The files rustup reads the pinned toolchain from, in the order it prefers them.
+const FILES: [&str; 2] = ["rust-toolchain.toml", "rust-toolchain"];
+
+/// A toolchain pinned by a toolchain file.
+pub struct Pinned {
+    /// The name of the file, relative to the project root.
+    pub file: &'static str,
+    /// The channel, e.g. `1.74.0` or `nightly-2024-01-01`.
+    pub channel: String,
+    pub components: Vec<String>,
+    pub targets: Vec<String>,
+}
+
+impl Pinned {
+    /// The options of `rustup toolchain install` that install the toolchain like the file asks.
+    pub fn install_args(&self) -> Vec<String> {
+        let mut args = vec!["--profile".to_owned(), "minimal".to_owned()];
+        for component in &self.components {
+            args.push("--component".to_owned());
+            args.push(component.clone());
+        }
+        for target in &self.targets {
+            args.push("--target".to_owned());
+            args.push(target.clone());
+        }
+        args
+    }
+}
+
+/// Reads the toolchain file in [`project_dir`]. Returns [`None`] if there is none or it names no
+/// channel, e.g. because it points to a custom toolchain by path.
+pub fn pinned(project_dir: &Path) -> Option<Pinned> {
+    let (file, content) = FILES.iter().find_map(|file| {
+        std::fs::read_to_string(project_dir.join(file))
+            .ok()
+            .map(|content| (*file, content))
+    })?;
+    // the legacy rust-toolchain file may hold just the channel
+    let toolchain = match content.parse::<Value>() {
+        Ok(value) => value.get("toolchain").cloned(),
+        Err(_) if !content.trim().is_empty() && !content.trim().contains(char::is_whitespace) => {
+            return Some(Pinned {
+                file,
+                channel: content.trim().to_owned(),
+                components: Vec::new(),
+                targets: Vec::new(),
+            });
+        }
+        Err(e) => {
+            warn!("Ignoring {}, it can't be parsed: {}", file, e);
+            return None;
+        }
+    };
+    let list = |key: &str| -> Vec<String> {
+        toolchain
+            .as_ref()
+            .and_then(|t| t.get(key))
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let channel = toolchain
+        .as_ref()
+        .and_then(|t| t.get("channel"))
+        .and_then(Value::as_str)?;
+    Some(Pinned {
+        file,
+        channel: channel.to_owned(),
+        components: list("components"),
+        targets: list("targets"),
+    })
+}
+
+/// What a build directory isolated by toolchain is named after: the [`explicit`] toolchain, else
+/// the Docker [`image`], else the [`pinned`] one, else stable, which cargo uses then.
+pub fn isolation_key(
+    explicit: Option<&str>,
+    image: Option<&str>,
+    pinned: Option<&Pinned>,
+) -> String {
+    explicit
+        .or(image)
+        .or(pinned.map(|p| p.channel.as_str()))
+        .unwrap_or("stable")
+        .to_owned()
+}
@@ -0,0 +1,126 @@
+//! How the build server is reached: over ssh, or directly for `local:` remotes that build in a
+//! directory of this machine.
+
+use std::fmt;
+use std::process::Command;
+
+use crate::{local_shell, shell_quote};
+
+/// Prefix of `--remote` values that name a build directory on the local machine.
+pub const LOCAL_PREFIX: &str = "local:";
+
+/// The way commands and transfers reach the build server.
+#[derive(Clone)]
+pub enum Transport {
+    /// A build server reached over ssh with these options. [`program`] is the ssh binary.
+    Ssh {
+        program: String,
+        server: String,
+        options: Vec<String>,
+    },
+    /// This machine, e.g. to build in a different mount namespace or for testing cargo-remote.
+    Local,
+}
+
+impl Transport {
+    /// Runs [`command`] in a shell on the build server.
+    pub fn shell(&self, command: &str) -> Command {
+        self.shell_with(&[], command)
+    }
+
+    /// Like [`Transport::shell`], but passes [`ssh_flags`] like `-t` to ssh. They are ignored for
+    /// the local machine.
+    pub fn shell_with(&self, ssh_flags: &[&str], command: &str) -> Command {
+        match self {
+            Transport::Ssh {
+                program,
+                server,
+                options,
+            } => {
+                let mut ssh = local_shell::command(program);
+                ssh.args(options).args(ssh_flags).arg(server).arg(command);
+                ssh
+            }
+            Transport::Local => {
+                // like sshd, run the command with the login shell of the user
+                let shell = std::env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+                let mut sh = Command::new(shell);
+                sh.arg("-c").arg(command);
+                sh
+            }
+        }
+    }
+
+    /// The command [`cmd`] runs on the build server if it was made by [`Transport::shell_with`].
+    pub fn remote_command(&self, cmd: &Command) -> Option<String> {
+        let args = cmd.get_args().collect::<Vec<_>>();
+        match self {
+            Transport::Ssh {
+                program, server, ..
+            } if cmd.get_program() == program.as_str() && args.len() >= 2 => {
+                let (command, rest) = args.split_last()?;
+                (rest.last()? == &server.as_str()).then(|| command.to_string_lossy().into_owned())
+            }
+            Transport::Local if args.len() == 2 && args[0] == "-c" => {
+                Some(args[1].to_string_lossy().into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Names [`path`] on the build server as source or destination of rsync.
+    pub fn location(&self, path: &str) -> String {
+        match self {
+            Transport::Ssh { server, .. } => format!("{}:{}", server, path),
+            Transport::Local => path.to_owned(),
+        }
+    }
+
+    /// The arguments that make rsync connect to the build server.
+    pub fn rsync_args(&self) -> Vec<String> {
+        match self {
+            Transport::Ssh {
+                program, options, ..
+            } if !options.is_empty() || program != "ssh" => {
+                let ssh = std::iter::once(shell_quote(program))
+                    .chain(options.iter().map(|o| shell_quote(o)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                vec!["-e".to_owned(), ssh]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Makes rsync run [`setup`] on the build server before it receives files. A local rsync
+    /// doesn't start its receiver through a shell, so there [`setup`] has to be run separately.
+    pub fn rsync_setup_args(&self, setup: &str) -> Vec<String> {
+        match self {
+            Transport::Ssh { .. } => vec!["--rsync-path".to_owned(), format!("{} && rsync", setup)],
+            Transport::Local => Vec::new(),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, Transport::Local)
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Ssh { server, .. } => write!(f, "{}", server),
+            Transport::Local => write!(f, "local"),
+        }
+    }
+}
+
+/// Splits the build directory off a `local:<dir>` remote. A leading `~/` is expanded, as rsync
+/// doesn't run the local paths through a shell.
+pub fn local_build_dir(remote: &str) -> Option<String> {
+    let dir = remote.strip_prefix(LOCAL_PREFIX)?;
+    match (dir.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => Some(format!("{}/{}", home.trim_end_matches('/'), rest)),
+        _ => Some(dir.to_owned()),
+    }
+}
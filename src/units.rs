@@ -0,0 +1,285 @@
+//! Parsing and formatting of the sizes and durations taken by command line flags and config keys.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses durations like `90s`, `15m`, `2h30m` or `1d`. A plain number is interpreted as seconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "invalid duration '{}', expected a number of seconds or a combination of \
+             <n>d, <n>h, <n>m and <n>s like '90s', '15m' or '2h30m'",
+            input
+        )
+    };
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = input.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let factor = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        secs = value
+            .checked_mul(factor)
+            .and_then(|v| secs.checked_add(v))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Formats [`duration`] like `2h30m5s`, omitting zero components.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let mut formatted = String::new();
+    if h > 0 {
+        formatted += &format!("{}h", h);
+    }
+    if m > 0 {
+        formatted += &format!("{}m", m);
+    }
+    if s > 0 || formatted.is_empty() {
+        formatted += &format!("{}s", s);
+    }
+    formatted
+}
+
+/// Formats the time passed since the unix time [`since`] in a human readable way.
+pub fn format_elapsed(since: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(since);
+    let elapsed = now.saturating_sub(since);
+    match elapsed {
+        0..=59 => format!("{}s ago", elapsed),
+        60..=3599 => format!("{}m ago", elapsed / 60),
+        3600..=86399 => format!("{}h ago", elapsed / 3600),
+        _ => format!("{}d ago", elapsed / 86400),
+    }
+}
+
+/// Formats [`bytes`] with a binary unit like `14.8 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Parses an rsync bandwidth limit into KiB per second. Like for rsync a plain number is taken as
+/// KiB/s, a size per second like `2MB` or `512K/s` (see [`parse_size`]) is accepted as well.
+pub fn parse_bandwidth(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    if let Ok(kib) = input.parse::<u64>() {
+        return Ok(kib);
+    }
+    let bytes = parse_size(input.strip_suffix("/s").unwrap_or(input)).map_err(|_| {
+        format!(
+            "invalid bandwidth '{}', expected a number of KiB/s or a size per second like \
+             '2MB' or '512K/s'",
+            input
+        )
+    })?;
+    // rsync treats 0 as no limit, so a tiny limit is rounded up instead
+    Ok(bytes.div_ceil(1024).max(1))
+}
+
+/// Parses sizes like `200MB`, `1.5GiB` or `512k` into bytes. Decimal units (`kB`, `MB`, `GB`,
+/// `TB`) are powers of 1000, binary units (`KiB`, `MiB`, `GiB`, `TiB`) and their single letter
+/// forms (`K`, `M`, `G`, `T`) are powers of 1024. Units are case-insensitive and a plain number
+/// is interpreted as bytes.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid size '{}', expected a number of bytes optionally followed by a unit \
+             like '200MB', '1.5GiB' or '512K' (B, kB, MB, GB, TB, KiB, MiB, GiB, TiB, K, M, G, T)",
+            input
+        )
+    };
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    if number.is_empty() || number.starts_with('.') || number.ends_with('.') {
+        return Err(invalid());
+    }
+
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+
+    if let Ok(value) = number.parse::<u64>() {
+        return value.checked_mul(factor).ok_or_else(invalid);
+    }
+    let value: f64 = number.parse().map_err(|_| invalid())?;
+    let bytes = (value * factor as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(input: &str) -> Result<u64, String> {
+        parse_duration(input).map(|d| d.as_secs())
+    }
+
+    #[test]
+    fn durations_with_suffixes() {
+        assert_eq!(secs("90s"), Ok(90));
+        assert_eq!(secs("15m"), Ok(900));
+        assert_eq!(secs("2h"), Ok(7200));
+        assert_eq!(secs("1d"), Ok(86400));
+        assert_eq!(secs("2h30m"), Ok(9000));
+        assert_eq!(secs("1d2h3m4s"), Ok(93784));
+        assert_eq!(secs("1m1m"), Ok(120));
+        assert_eq!(secs(" 30m "), Ok(1800));
+    }
+
+    #[test]
+    fn durations_without_suffix_are_seconds() {
+        assert_eq!(secs("0"), Ok(0));
+        assert_eq!(secs("0s"), Ok(0));
+        assert_eq!(secs("0h0m"), Ok(0));
+        assert_eq!(secs("45"), Ok(45));
+        assert_eq!(secs("18446744073709551615"), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn durations_that_overflow() {
+        assert_eq!(secs("213503982334601d"), Ok(213_503_982_334_601 * 86400));
+        assert!(secs("213503982334602d").is_err());
+        assert!(secs("18446744073709551615m").is_err());
+        assert!(secs("18446744073709551615s1s").is_err());
+        assert!(secs("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for input in [
+            "", " ", "s", "m30", "30x", "2h30", "-5s", "1.5h", "5 m", "ten",
+        ] {
+            let error = parse_duration(input).unwrap_err();
+            assert!(error.contains(&format!("'{}'", input)), "{}", error);
+            assert!(error.contains("'2h30m'"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn sizes_with_decimal_units() {
+        assert_eq!(parse_size("1B"), Ok(1));
+        assert_eq!(parse_size("1kB"), Ok(1000));
+        assert_eq!(parse_size("200MB"), Ok(200_000_000));
+        assert_eq!(parse_size("3GB"), Ok(3_000_000_000));
+        assert_eq!(parse_size("2TB"), Ok(2_000_000_000_000));
+        assert_eq!(parse_size("2mb"), Ok(2_000_000));
+    }
+
+    #[test]
+    fn sizes_with_binary_units() {
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("1KiB"), Ok(1024));
+        assert_eq!(parse_size("1 KiB"), Ok(1024));
+        assert_eq!(parse_size("20M"), Ok(20 << 20));
+        assert_eq!(parse_size("1mib"), Ok(1 << 20));
+        assert_eq!(parse_size("1.5GiB"), Ok(1_610_612_736));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("1TiB"), Ok(1 << 40));
+        assert_eq!(parse_size("4T"), Ok(4 << 40));
+    }
+
+    #[test]
+    fn sizes_without_unit_are_bytes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("0GiB"), Ok(0));
+        assert_eq!(parse_size("1234"), Ok(1234));
+        assert_eq!(parse_size("1.5"), Ok(2));
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn sizes_that_overflow() {
+        assert_eq!(parse_size("16777215T"), Ok(u64::MAX - (1 << 40) + 1));
+        assert!(parse_size("16777216T").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("20000000000TB").is_err());
+        assert!(parse_size("16777216.5TiB").is_err());
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for input in [
+            "", "MB", "-1", ".5G", "5.G", "1.2.3", "10XB", "1e3", "1 2", "big",
+        ] {
+            let error = parse_size(input).unwrap_err();
+            assert!(error.contains(&format!("'{}'", input)), "{}", error);
+            assert!(error.contains("'1.5GiB'"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn bandwidths() {
+        assert_eq!(parse_bandwidth("0"), Ok(0));
+        assert_eq!(parse_bandwidth("1000"), Ok(1000));
+        assert_eq!(parse_bandwidth("512K/s"), Ok(512));
+        assert_eq!(parse_bandwidth("2MiB"), Ok(2048));
+        assert_eq!(parse_bandwidth("2MB"), Ok(1954));
+        assert_eq!(parse_bandwidth("1B/s"), Ok(1));
+        assert!(parse_bandwidth("fast").is_err());
+        assert!(parse_bandwidth("2MB/h").is_err());
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1h");
+        assert_eq!(format_duration(Duration::from_secs(9005)), "2h30m5s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "59s");
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(15 << 20), "15.0 MiB");
+        assert_eq!(format_size(5000 << 40), "5000.0 TiB");
+    }
+}
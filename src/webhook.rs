@@ -0,0 +1,202 @@
+//! `--webhook`: a JSON summary POSTed to a URL when the run ends, so the result of an unattended
+//! build is recorded somewhere even if the laptop that started it went to sleep meanwhile. The
+//! request is made by the local curl. A webhook that can't be reached only warns, the outcome of
+//! the build stays what it was.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::audit;
+
+/// How long one delivery may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The pause before the one retry of a failed delivery.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Which runs are reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebhookOn {
+    Success,
+    Failure,
+    Always,
+}
+
+impl FromStr for WebhookOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(WebhookOn::Success),
+            "failure" => Ok(WebhookOn::Failure),
+            "always" => Ok(WebhookOn::Always),
+            _ => Err(format!(
+                "unknown webhook filter '{}', expected success, failure or always",
+                s
+            )),
+        }
+    }
+}
+
+/// The webhook of this run, [`None`] without `--webhook` or once it was sent.
+static WEBHOOK: Mutex<Option<Webhook>> = Mutex::new(None);
+
+struct Webhook {
+    url: String,
+    /// Extra headers like `Authorization`, the values are secrets.
+    headers: Vec<(String, String)>,
+    on: WebhookOn,
+    project_dir: PathBuf,
+    host: String,
+    command: String,
+    started: Instant,
+    /// The phases that finished, with their durations.
+    phases: Vec<(&'static str, Duration)>,
+    artifacts: Vec<String>,
+}
+
+/// Reports the end of this run of [`command`] on [`host`] to [`url`] unless [`on`] filters it
+/// out, with the extra [`headers`].
+pub fn arm(
+    url: String,
+    headers: Vec<(String, String)>,
+    on: WebhookOn,
+    project_dir: &Path,
+    host: &str,
+    command: &str,
+) {
+    info!(
+        "Webhook: {} ({:?}), headers: {:?}",
+        url,
+        on,
+        headers.iter().map(|(name, _)| name).collect::<Vec<_>>()
+    );
+    audit::redact(headers.iter().map(|(_, value)| value.as_str()));
+    *WEBHOOK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Webhook {
+        url,
+        headers,
+        on,
+        project_dir: project_dir.to_owned(),
+        host: host.to_owned(),
+        command: command.to_owned(),
+        started: Instant::now(),
+        phases: Vec::new(),
+        artifacts: Vec::new(),
+    });
+}
+
+/// Notes that the phase [`name`] finished after [`duration`].
+pub fn phase_finished(name: &'static str, duration: Duration) {
+    if let Some(webhook) = WEBHOOK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        webhook.phases.push((name, duration));
+    }
+}
+
+/// Notes the files transferred back, named relative to the project if they're inside it.
+pub fn artifacts(paths: &[PathBuf]) {
+    if let Some(webhook) = WEBHOOK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let project_dir = &webhook.project_dir;
+        webhook.artifacts = paths
+            .iter()
+            .map(|path| {
+                path.strip_prefix(project_dir)
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+    }
+}
+
+/// Sends the summary of the run that ends with [`exit_code`], retrying once. Failing to deliver
+/// it only warns.
+pub fn send(exit_code: i32) {
+    let webhook = match WEBHOOK.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        Some(webhook) => webhook,
+        None => return,
+    };
+    let wanted = match webhook.on {
+        WebhookOn::Success => exit_code == 0,
+        WebhookOn::Failure => exit_code != 0,
+        WebhookOn::Always => true,
+    };
+    if !wanted {
+        return;
+    }
+    let mut durations = Map::new();
+    durations.insert(
+        "total".to_owned(),
+        json!(webhook.started.elapsed().as_millis() as u64),
+    );
+    for (name, duration) in &webhook.phases {
+        durations.insert((*name).to_owned(), json!(duration.as_millis() as u64));
+    }
+    let payload = json!({
+        "project": webhook.project_dir.to_string_lossy(),
+        "host": webhook.host,
+        "command": webhook.command,
+        "exit_code": exit_code,
+        "success": exit_code == 0,
+        "durations_ms": durations,
+        "artifacts": webhook.artifacts,
+    });
+
+    let result = deliver(&webhook, &payload).or_else(|_| {
+        std::thread::sleep(RETRY_DELAY);
+        deliver(&webhook, &payload)
+    });
+    match result {
+        Ok(()) => info!("Reported the run to the webhook {}", webhook.url),
+        Err(e) => warn!(
+            "Failed to report the run to the webhook {}: {}",
+            webhook.url, e
+        ),
+    }
+}
+
+/// POSTs [`payload`] to the webhook. The headers are passed on stdin, so their secret values
+/// don't show in the process list.
+fn deliver(webhook: &Webhook, payload: &Value) -> Result<(), String> {
+    let mut curl = Command::new("curl");
+    curl.args(["--silent", "--show-error", "--fail", "--max-time"])
+        .arg(TIMEOUT.as_secs().to_string())
+        .args(["-X", "POST", "-H", "Content-Type: application/json"])
+        .arg("--data-binary")
+        .arg(payload.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    if webhook.headers.is_empty() {
+        curl.stdin(Stdio::null());
+    } else {
+        curl.args(["-H", "@-"]).stdin(Stdio::piped());
+    }
+    curl.arg(&webhook.url);
+
+    let mut child = curl.spawn().map_err(|e| format!("can't run curl: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let headers = webhook
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\n", name, value))
+            .collect::<String>();
+        // curl reads the headers before it sends anything
+        let _ = stdin.write_all(headers.as_bytes());
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("curl failed: {}", e))?;
+    audit::record(&curl, Some(&output.status));
+    let errors = String::from_utf8_lossy(&output.stderr);
+    match (output.status.success(), errors.trim()) {
+        (true, _) => Ok(()),
+        (false, "") => Err(format!("curl exited with {}", output.status)),
+        (false, errors) => Err(errors.to_owned()),
+    }
+}
@@ -0,0 +1,140 @@
+//! `cargo remote which`: where a local path ends up on the build server, worked out from the same
+//! settings as a sync, so mapping a path doesn't need a connection.
+
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+use crate::audit;
+use crate::error::{remote_error, spawn_error, Error};
+use crate::local_shell;
+use crate::sync_state::list_files;
+use crate::transport::Transport;
+use crate::{shell_quote_path, target_artifact_path};
+
+/// What `cargo remote which` was asked.
+pub struct Query {
+    /// The local path, relative to the current directory. The project root if not given.
+    pub path: Option<PathBuf>,
+    /// Whether to check that the remote path exists, which connects to the build server.
+    pub exists: bool,
+}
+
+/// How the project is laid out locally and on the build server.
+pub struct Layout<'a> {
+    pub project_dir: &'a Path,
+    /// The directory the sync starts at, the project dir or an ancestor given by --sync-parent.
+    pub sync_dir: &'a Path,
+    pub local_target: &'a Path,
+    /// The remote build directory, ending with a slash.
+    pub build_path: &'a str,
+    pub remote_target: &'a str,
+    /// The target triple the artifacts are built for.
+    pub target: Option<&'a str>,
+    /// The rsync filters of the sync.
+    pub filters: &'a [OsString],
+    pub rsync_bin: &'a str,
+}
+
+/// Prints the remote location of the local path of [`query`] and whether a sync transfers it.
+pub fn print(
+    query: &Query,
+    current_dir: &Path,
+    layout: &Layout,
+    transport: &Transport,
+) -> Result<(), Error> {
+    let local = match &query.path {
+        Some(path) => normalize(&current_dir.join(path)),
+        None => layout.project_dir.to_owned(),
+    };
+    let (remote, synced) = if let Ok(relative) = local.strip_prefix(layout.local_target) {
+        let relative = local_shell::slashes(relative);
+        let relative = match layout.target {
+            Some(target) if !relative.is_empty() => target_artifact_path(target, relative.clone()),
+            _ => relative,
+        };
+        let remote = if relative.is_empty() {
+            layout.remote_target.to_owned()
+        } else {
+            format!("{}/{}", layout.remote_target, relative)
+        };
+        (
+            remote,
+            "no, the target directory is built remotely and only transferred back with --copy-back"
+                .to_owned(),
+        )
+    } else if let Ok(relative) = local.strip_prefix(layout.project_dir) {
+        let relative = local_shell::slashes(relative);
+        let remote = format!("{}{}", layout.build_path, relative);
+        (remote, sync_status(&local, layout))
+    } else {
+        return Err(Error::Project(format!(
+            "'{}' isn't inside the project {}",
+            local.to_string_lossy(),
+            layout.project_dir.to_string_lossy()
+        )));
+    };
+
+    println!("local:  {}", local.to_string_lossy());
+    println!("remote: {}", transport.location(&remote));
+    println!("synced: {}", synced);
+    if query.exists {
+        let mut test = transport.shell(&format!(
+            "test -e {}",
+            shell_quote_path(remote.trim_end_matches('/'))
+        ));
+        let status = audit::status(&mut test).map_err(|e| spawn_error(&test, e, Error::Build))?;
+        let exists = match status.code() {
+            Some(0) => "yes",
+            Some(1) => "no",
+            _ => return Err(remote_error("test", status, Error::Build)),
+        };
+        println!("exists: {}", exists);
+    }
+    Ok(())
+}
+
+/// Whether a sync transfers [`local`] with the filters of [`layout`]. Directories count as synced
+/// if any file below them is.
+fn sync_status(local: &Path, layout: &Layout) -> String {
+    if !local.exists() {
+        return "no, it doesn't exist locally".to_owned();
+    }
+    if local == layout.project_dir {
+        return "yes".to_owned();
+    }
+    // the files are listed relative to the directory the sync starts at
+    let relative = match local.strip_prefix(layout.sync_dir) {
+        Ok(relative) => local_shell::slashes(relative),
+        Err(_) => return "unknown, it's outside of the synced directory".to_owned(),
+    };
+    let files = match list_files(layout.rsync_bin, layout.sync_dir, layout.filters) {
+        Some(files) => files,
+        None => return "unknown, listing the synced files with rsync failed".to_owned(),
+    };
+    let prefix = format!("{}/", relative);
+    let synced = if local.is_dir() {
+        files.keys().any(|file| file.starts_with(&prefix))
+    } else {
+        files.contains_key(&relative)
+    };
+    if synced {
+        "yes".to_owned()
+    } else {
+        "no, it's excluded by the sync rules".to_owned()
+    }
+}
+
+/// Resolves `.` and `..` in [`path`] without touching the filesystem, as it may not exist.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
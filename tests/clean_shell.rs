@@ -0,0 +1,65 @@
+//! Output meant for tools stays clean on a build server whose login shell talks.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+
+use common::Project;
+
+/// A login shell with a profile that prints to stdout, as `SHELL` and the remote shell. Started
+/// with `-c` only, like sshd does, it's quiet.
+const NOISY_SHELL: &str = r#"#!/bin/sh
+for arg; do
+    case $arg in
+        -l*|--login) echo "Welcome to the build server"; break ;;
+    esac
+done
+exec bash "$@"
+"#;
+
+#[test]
+fn json_output_skips_the_profile() {
+    if !common::installed("rsync") || !common::installed("bash") {
+        eprintln!("skipped, rsync or bash isn't installed");
+        return;
+    }
+    let project = Project::new("clean-shell");
+    let shell = project.dir.with_file_name("noisy-shell");
+    std::fs::write(&shell, NOISY_SHELL).unwrap();
+    std::fs::set_permissions(&shell, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let shell = shell.to_string_lossy().into_owned();
+    let remote = project.local_remote();
+    let run = |args: &[&str]| {
+        let mut all_args = vec!["-r", &remote, "--remote-shell", &shell];
+        all_args.extend(args);
+        let output = project
+            .cargo_remote(&all_args)
+            .env("SHELL", &shell)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", stderr);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // the profile talks to a plain build
+    let stdout = run(&["run"]);
+    assert!(stdout.contains("Welcome to the build server"), "{}", stdout);
+
+    let stdout = run(&["build", "--message-format=json"]);
+    assert!(
+        stdout.contains("\"reason\":\"build-finished\""),
+        "{}",
+        stdout
+    );
+    for line in stdout.lines() {
+        assert!(
+            serde_json::from_str::<serde_json::Value>(line).is_ok(),
+            "not JSON: {}\n{}",
+            line,
+            stdout
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Runs the cargo-remote binary on a throwaway project.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// A package with a `fn main`, removed when the test ends.
+pub struct Project {
+    pub dir: PathBuf,
+    /// Stands in for the XDG directories, so the configs of the user don't apply.
+    xdg: PathBuf,
+}
+
+impl Project {
+    pub fn new(name: &str) -> Project {
+        let root =
+            std::env::temp_dir().join(format!("cargo-remote-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("app");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n[workspace]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        Project {
+            dir,
+            xdg: root.join("xdg"),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+
+    /// Replaces the file at [`path`] in the project.
+    pub fn write(&self, path: &str, contents: &str) {
+        std::fs::write(self.dir.join(path), contents).unwrap();
+    }
+
+    /// A `local:` remote that builds next to the project.
+    pub fn local_remote(&self) -> String {
+        format!(
+            "local:{}",
+            self.dir.with_file_name("builds").to_string_lossy()
+        )
+    }
+
+    /// `cargo remote <args>` in the project directory.
+    pub fn cargo_remote(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_cargo-remote"));
+        cmd.arg("remote")
+            .args(args)
+            .current_dir(&self.dir)
+            .env_remove("CARGO_REMOTE_HOST")
+            .env("XDG_CONFIG_HOME", self.xdg.join("config"))
+            .env("XDG_DATA_HOME", self.xdg.join("data"))
+            .env("XDG_STATE_HOME", self.xdg.join("state"))
+            .env("XDG_CACHE_HOME", self.xdg.join("cache"))
+            .stdin(Stdio::null());
+        cmd
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.dir.parent().unwrap());
+    }
+}
+
+/// Whether [`program`] is on the `PATH`. Tests that build through a `local:` remote need rsync
+/// and skip themselves without it.
+pub fn installed(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
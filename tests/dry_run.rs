@@ -0,0 +1,312 @@
+//! The commands `--dry-run` prints, checked word by word.
+
+mod common;
+
+use std::fs::File;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use common::Project;
+
+/// Runs `cargo remote --dry-run` with a fixed build directory and toolchain and splits what it
+/// prints into the words of each command. A quoted word may span several lines.
+fn dry_run(project: &Project, args: &[&str]) -> Vec<Vec<String>> {
+    let output = dry_run_command(project, args).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    split_commands(&String::from_utf8(output.stdout).unwrap())
+}
+
+fn dry_run_command(project: &Project, args: &[&str]) -> Command {
+    let mut all_args = vec![
+        "-r",
+        "host",
+        "--build-path",
+        "~/b",
+        "--build-path-no-hash",
+        "--rustup-default",
+        "stable",
+        "--no-control-master",
+        "--dry-run",
+    ];
+    all_args.extend(args);
+    project.cargo_remote(&all_args)
+}
+
+fn split_commands(stdout: &str) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    let mut pending = String::new();
+    for line in stdout.lines() {
+        pending.push_str(line);
+        match shell_words::split(&pending) {
+            Ok(words) => {
+                commands.push(words);
+                pending.clear();
+            }
+            Err(_) => pending.push('\n'),
+        }
+    }
+    assert_eq!(pending, "");
+    commands
+}
+
+const RSYNC: &[&str] = &[
+    "rsync",
+    "-a",
+    "--compress",
+    "--partial",
+    "--partial-dir=.cargo-remote-partial",
+    "--info=stats1",
+];
+
+/// The upload with the commands that prepare the build directory replaced by `<setup>`, which
+/// record when the sync happened.
+fn upload(command: &[String]) -> Vec<String> {
+    let mut command = command.to_vec();
+    let setup = command.iter().position(|w| w == "--rsync-path").unwrap() + 1;
+    assert!(
+        command[setup].starts_with("mkdir -p ~/b/ && printf '%s' 'origin = ")
+            && command[setup].ends_with("' > ~/b/.cargo-remote-origin && rsync"),
+        "{}",
+        command[setup]
+    );
+    command[setup] = "<setup>".to_owned();
+    command
+}
+
+fn words(words: &[&[&str]]) -> Vec<String> {
+    words.concat().iter().map(|w| (*w).to_owned()).collect()
+}
+
+/// The build with the commands that record the pid of the remote shell.
+fn ssh_build(build: &str) -> Vec<String> {
+    words(&[&[
+        "ssh",
+        "host",
+        &format!(
+            "exec bash -lc {}",
+            shell_words::quote(&format!(
+                "mv -f ~/b/.cargo-remote-pid ~/b/.cargo-remote-pid.last 2>/dev/null; \
+                 echo $$ > ~/b/.cargo-remote-pid; {}",
+                build
+            ))
+        ),
+    ]])
+}
+
+/// The transfer of Cargo.lock back into the project at [`path`].
+fn lock_back(path: &str) -> Vec<String> {
+    words(&[
+        RSYNC,
+        &["--ignore-missing-args", "host:~/b/Cargo.lock"],
+        &[&format!("{}/Cargo.lock.remote-tmp", path)],
+    ])
+}
+
+const OWN_EXCLUDES: &[&str] = &[
+    "--delete",
+    "--exclude",
+    "/.cargo-remote-origin",
+    "--exclude",
+    "/.cargo-remote-pid*",
+    "--exclude",
+    ".cargo-remote-partial/",
+    "--exclude",
+    "/.cargo-remote/",
+    "--exclude",
+    "target",
+];
+
+/// What `--delete` leaves alone when transferring back, cargo-remote keeps it in the local target
+/// directory.
+const LOCAL_ONLY: &[&str] = &[
+    "--delete",
+    "--exclude",
+    "/.cargo-remote-state.json",
+    "--exclude",
+    "/.cargo-remote-run.lock",
+    "--exclude",
+    "/.cargo-remote-coalesce.json",
+    "--exclude",
+    "/remote-build-logs/",
+    "--exclude",
+    "/remote-failure-*/",
+    "--exclude",
+    "/remote/",
+    "--exclude",
+    "/remote-*/",
+];
+
+#[test]
+fn copy_back() {
+    let project = Project::new("dry-run-copy-back");
+    let path = project.path();
+    let commands = dry_run(&project, &["--copy-back", "debug/app", "build"]);
+    assert_eq!(commands.len(), 4, "{:?}", commands);
+    assert_eq!(
+        upload(&commands[0]),
+        words(&[
+            RSYNC,
+            OWN_EXCLUDES,
+            &["--exclude", ".*", "--rsync-path", "<setup>"],
+            &[&format!("{}/", path), "host:~/b/"],
+        ])
+    );
+    assert_eq!(
+        commands[1],
+        ssh_build(". ~/.cargo/env; cd ~/b/; RUST_BACKTRACE=1 cargo +stable build")
+    );
+    assert_eq!(
+        commands[2],
+        words(&[
+            RSYNC,
+            LOCAL_ONLY,
+            &[
+                "host:~/b/target/debug/app",
+                &format!("{}/target/debug/app", path)
+            ],
+        ])
+    );
+    assert_eq!(commands[3], lock_back(&path));
+}
+
+#[test]
+fn copy_back_target() {
+    let project = Project::new("dry-run-copy-back-target");
+    let path = project.path();
+    let commands = dry_run(&project, &["--copy-back=", "build"]);
+    assert_eq!(commands.len(), 4, "{:?}", commands);
+    // the whole folder, without deleting what only exists locally
+    assert_eq!(
+        commands[2],
+        words(&[
+            RSYNC,
+            LOCAL_ONLY,
+            &["host:~/b/target/", &format!("{}/target/", path)],
+        ])
+    );
+    assert_eq!(commands[3], lock_back(&path));
+}
+
+#[test]
+fn copy_back_after_clean() {
+    let project = Project::new("dry-run-clean");
+    let path = project.path();
+    let output = dry_run_command(&project, &["--debug", "-c", "clean"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(
+        stderr.contains("'cargo clean' doesn't produce artifacts, skipping the copy-back."),
+        "{}",
+        stderr
+    );
+    // nothing to transfer back, not even Cargo.lock
+    let commands = split_commands(&String::from_utf8(output.stdout).unwrap());
+    assert_eq!(commands.len(), 2, "{:?}", commands);
+    assert_eq!(
+        upload(&commands[0]),
+        words(&[
+            RSYNC,
+            OWN_EXCLUDES,
+            &["--exclude", ".*", "--rsync-path", "<setup>"],
+            &[&format!("{}/", path), "host:~/b/"],
+        ])
+    );
+    assert_eq!(
+        commands[1],
+        ssh_build(". ~/.cargo/env; cd ~/b/; RUST_BACKTRACE=1 cargo +stable clean")
+    );
+}
+
+#[test]
+fn hidden_files() {
+    let project = Project::new("dry-run-hidden");
+    let path = project.path();
+    let commands = dry_run(&project, &["--transfer-hidden", "check"]);
+    assert_eq!(commands.len(), 3, "{:?}", commands);
+    // no exclude of the hidden files
+    assert_eq!(
+        upload(&commands[0]),
+        words(&[
+            RSYNC,
+            OWN_EXCLUDES,
+            &["--rsync-path", "<setup>"],
+            &[&format!("{}/", path), "host:~/b/"],
+        ])
+    );
+    assert_eq!(
+        commands[1],
+        ssh_build(". ~/.cargo/env; cd ~/b/; RUST_BACKTRACE=1 cargo +stable check")
+    );
+    assert_eq!(commands[2], lock_back(&path));
+}
+
+#[test]
+fn custom_env() {
+    let project = Project::new("dry-run-env");
+    let path = project.path();
+    let commands = dry_run(
+        &project,
+        &[
+            "-e",
+            "/opt/rust env.sh",
+            "-e",
+            "~/.profile",
+            "-b",
+            "FOO=1",
+            "-b",
+            "BAR=a b; c",
+            "check",
+            "--features",
+            "x y",
+        ],
+    );
+    assert_eq!(commands.len(), 3, "{:?}", commands);
+    assert_eq!(
+        commands[1],
+        ssh_build(
+            ". '/opt/rust env.sh'; . ~/.profile; cd ~/b/; FOO=1 BAR='a b; c' cargo +stable check \
+             --features 'x y'"
+        )
+    );
+    assert_eq!(commands[2], lock_back(&path));
+}
+
+#[test]
+fn redirected_streams() {
+    let project = Project::new("dry-run-redirected");
+    let path = project.path();
+    let streams = project.dir.with_file_name("streams");
+    std::fs::create_dir_all(&streams).unwrap();
+    std::fs::write(streams.join("stdin"), "y\n").unwrap();
+    let mut child = dry_run_command(&project, &["build"])
+        .stdin(File::open(streams.join("stdin")).unwrap())
+        .stdout(File::create(streams.join("stdout")).unwrap())
+        .stderr(File::create(streams.join("stderr")).unwrap())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("the dry run with its streams redirected to files didn't end");
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let stderr = std::fs::read_to_string(streams.join("stderr")).unwrap();
+    assert!(status.success(), "{}", stderr);
+
+    let commands = split_commands(&std::fs::read_to_string(streams.join("stdout")).unwrap());
+    assert_eq!(commands.len(), 3, "{:?}", commands);
+    // no TTY without a terminal, ssh -t would complain about it
+    assert_eq!(
+        commands[1],
+        ssh_build(". ~/.cargo/env; cd ~/b/; RUST_BACKTRACE=1 cargo +stable build")
+    );
+    assert_eq!(commands[2], lock_back(&path));
+}
@@ -0,0 +1,120 @@
+//! cargo-remote runs on a machine without cargo, e.g. a bastion host with just ssh and rsync.
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+
+use common::Project;
+
+/// The directories of the `PATH` that hold no cargo.
+fn path_without_cargo() -> OsString {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::join_paths(std::env::split_paths(&path).filter(|dir| !dir.join("cargo").is_file()))
+        .unwrap()
+}
+
+/// The directory cargo is in, for the build that still needs it.
+fn cargo_dir() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find(|dir| dir.join("cargo").is_file())
+}
+
+/// `cargo remote <args>` with cargo hidden from cargo-remote.
+fn without_cargo(project: &Project, args: &[&str]) -> Command {
+    let mut cmd = project.cargo_remote(args);
+    cmd.env("PATH", path_without_cargo()).env_remove("CARGO");
+    cmd
+}
+
+#[test]
+fn finds_the_workspace_without_cargo() {
+    let project = Project::new("no-cargo-dry-run");
+    std::fs::create_dir_all(project.dir.join("member/src")).unwrap();
+    project.write(
+        "member/Cargo.toml",
+        "[package]\nname = \"member\"\nversion = \"0.1.0\"\n",
+    );
+    let output = without_cargo(
+        &project,
+        &[
+            "-r",
+            "host",
+            "--build-path",
+            "~/b",
+            "--build-path-no-hash",
+            "--rustup-default",
+            "stable",
+            "--no-control-master",
+            "--dry-run",
+            "build",
+        ],
+    )
+    .current_dir(project.dir.join("member/src"))
+    .output()
+    .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("No cargo found on this machine"),
+        "{}",
+        stderr
+    );
+    // the whole workspace is synced, the build runs in the current directory
+    assert!(
+        stdout.contains(&format!(" {}/ 'host:~/b/'", project.path())),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("cd ~/b/; cd member/src; RUST_BACKTRACE=1 cargo +stable build"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn builds_through_a_local_remote_without_cargo() {
+    let cargo_dir = match cargo_dir() {
+        Some(dir) if common::installed("rsync") => dir,
+        _ => {
+            eprintln!("skipped, rsync or cargo isn't installed");
+            return;
+        }
+    };
+    let project = Project::new("no-cargo-build");
+    // the build itself finds cargo through the profile, like on a build server
+    let profile = project.dir.with_file_name("profile.sh");
+    std::fs::write(
+        &profile,
+        format!(
+            "PATH='{}':\"$PATH\"; export PATH\n",
+            cargo_dir.to_string_lossy()
+        ),
+    )
+    .unwrap();
+    let output = without_cargo(
+        &project,
+        &[
+            "-r",
+            &project.local_remote(),
+            "-e",
+            &profile.to_string_lossy(),
+            "-c",
+            "debug/app",
+            "build",
+        ],
+    )
+    .output()
+    .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("No cargo found on this machine"),
+        "{}",
+        stderr
+    );
+    assert!(project.dir.join("target/debug/app").is_file(), "{}", stderr);
+}
@@ -0,0 +1,90 @@
+//! With `--offline`, `--frozen` or `CARGO_NET_OFFLINE=true`, cargo-remote stays off the network
+//! as well: no Cargo.lock back, no credentials over and no toolchain installed.
+
+mod common;
+
+use common::Project;
+
+/// What `cargo remote --dry-run <args>` would run through a `local:` remote.
+fn dry_run(project: &Project, args: &[&str]) -> String {
+    let remote = project.local_remote();
+    let mut all_args = vec!["-r", &remote, "--rustup-default", "stable", "--dry-run"];
+    all_args.extend(args);
+    let output = project.cargo_remote(&all_args).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn no_lock_back() {
+    let project = Project::new("offline-lock");
+    let commands = dry_run(&project, &["build"]);
+    assert!(commands.contains("/Cargo.lock.remote-tmp"), "{}", commands);
+    for args in [
+        &["build", "--offline"][..],
+        &["build", "--frozen"],
+        &["-b", "CARGO_NET_OFFLINE=true", "build"],
+    ] {
+        let commands = dry_run(&project, args);
+        assert_eq!(commands.lines().count(), 2, "{:?}: {}", args, commands);
+        assert!(!commands.contains("Cargo.lock"), "{:?}: {}", args, commands);
+    }
+}
+
+#[test]
+fn no_credentials_and_no_toolchain_install() {
+    if !common::installed("rsync") || !common::installed("rustup") {
+        eprintln!("skipped, rsync or rustup isn't installed");
+        return;
+    }
+    let project = Project::new("offline-run");
+    let remote = project.local_remote();
+
+    let output = project
+        .cargo_remote(&[
+            "-r",
+            &remote,
+            "--debug",
+            "--transfer-credentials",
+            "build",
+            "--offline",
+        ])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("Not transferring the credentials, cargo runs with --offline"),
+        "{}",
+        stderr
+    );
+    assert!(!project.dir.join("Cargo.lock").exists());
+
+    let output = project
+        .cargo_remote(&[
+            "-r",
+            &remote,
+            "--rustup-default",
+            "cargo-remote-missing-toolchain",
+            "--install-toolchain",
+            "build",
+            "--frozen",
+        ])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // the toolchain error
+    assert_eq!(output.status.code(), Some(18), "{}", stderr);
+    assert!(
+        stderr.contains(
+            "'cargo-remote-missing-toolchain' isn't installed and cargo runs with --frozen"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("Installing the toolchain"), "{}", stderr);
+}
@@ -0,0 +1,32 @@
+//! Without a TTY, the output of the remote command keeps stdout and stderr apart.
+
+mod common;
+
+use common::Project;
+
+#[test]
+fn stdout_and_stderr_stay_apart() {
+    if !common::installed("rsync") {
+        eprintln!("skipped, rsync isn't installed");
+        return;
+    }
+    let project = Project::new("streams");
+    project.write(
+        "src/main.rs",
+        "fn main() {\n    println!(\"to stdout\");\n    eprintln!(\"to stderr\");\n}\n",
+    );
+    let output = project
+        .cargo_remote(&["-r", &project.local_remote(), "run"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stdout.contains("to stdout"), "{}", stdout);
+    assert!(!stdout.contains("to stderr"), "{}", stdout);
+    assert!(stderr.contains("to stderr"), "{}", stderr);
+    assert!(!stderr.contains("to stdout"), "{}", stderr);
+    // cargo's own messages are diagnostics as well
+    assert!(stderr.contains("Finished"), "{}", stderr);
+    assert!(!stdout.contains("Finished"), "{}", stdout);
+}